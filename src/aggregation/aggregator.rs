@@ -0,0 +1,308 @@
+use std::collections::HashSet;
+use anyhow::Result;
+use duckdb::Connection;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedReceiver;
+use crate::config::config::{AggregateConfig, AggregateFunction, MeasureConfig};
+use crate::db::catalog::table_columns;
+use crate::ingestion::writer::Writer;
+use crate::ingestion::{INSERT_TIMESTAMP_COLUMN, SOURCE_COLUMN};
+use crate::query::sql::quote_identifier;
+use crate::scheduler::schedule::Schedule;
+
+/// Column on every aggregate holding the newest `insert_timestamp` of its group.
+pub const LAST_UPDATE_COLUMN: &str = "last_update";
+
+/// Keeps every aggregate materialized.
+///
+/// All aggregates are refreshed once on startup. After that, `on_change`
+/// aggregates are refreshed whenever their base table is named on `ingested`;
+/// aggregates with a clock schedule are left to the scheduler. Notifications
+/// that pile up while a refresh runs are coalesced, so a burst of Kafka
+/// messages costs one refresh.
+///
+/// Refreshes replace whole tables, so they go through the [`Writer`] as
+/// exclusive writes rather than racing ingestion on a pooled connection.
+///
+/// Every ingested table, and every aggregate once refreshed, is announced on
+/// `changed` for the publishers.
+pub async fn aggregate_refresher(
+    writer: Writer,
+    aggregates: Vec<AggregateConfig>,
+    mut ingested: UnboundedReceiver<String>,
+    changed: broadcast::Sender<String>,
+) -> Result<()> {
+    let all_tables = aggregates.iter().map(|a| a.table_name.clone()).collect();
+    refresh_for_tables(&writer, &aggregates, &all_tables, &changed).await;
+
+    let aggregates: Vec<AggregateConfig> = aggregates
+        .into_iter()
+        .filter(|a| matches!(a.schedule, None | Some(Schedule::OnChange)))
+        .collect();
+
+    while let Some(table) = ingested.recv().await {
+        let mut tables = HashSet::from([table]);
+        while let Ok(table) = ingested.try_recv() {
+            tables.insert(table);
+        }
+        for table in &tables {
+            let _ = changed.send(table.clone());
+        }
+        refresh_for_tables(&writer, &aggregates, &tables, &changed).await;
+    }
+
+    Ok(())
+}
+
+async fn refresh_for_tables(
+    writer: &Writer,
+    aggregates: &[AggregateConfig],
+    tables: &HashSet<String>,
+    changed: &broadcast::Sender<String>,
+) {
+    for aggregate in aggregates.iter().filter(|a| tables.contains(&a.table_name)) {
+        match refresh_through_writer(writer, aggregate).await {
+            Ok(()) => {
+                println!("Refreshed aggregate {}", aggregate.name);
+                let _ = changed.send(aggregate.name.clone());
+            }
+            Err(e) => eprintln!("Error refreshing aggregate {}: {:?}", aggregate.name, e),
+        }
+    }
+}
+
+/// Queues [`refresh_aggregate`] on the writer and waits for it.
+///
+/// The refresh reports no changed rows, so the writer doesn't announce the
+/// aggregate as ingested; callers announce it on `changed` themselves.
+pub async fn refresh_through_writer(writer: &Writer, aggregate: &AggregateConfig) -> Result<()> {
+    let aggregate_clone = aggregate.clone();
+    writer
+        .write_exclusive(&aggregate.name, move |conn| {
+            refresh_aggregate(conn, &aggregate_clone)?;
+            Ok(0)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Rebuilds one aggregate table from its base table.
+///
+/// The aggregate is grouped by its dimensions and carries a `last_update`
+/// column of `MAX(insert_timestamp)` per group, which publishers use for
+/// change detection. Aggregates over a base table that doesn't exist yet are
+/// skipped until it does.
+pub fn refresh_aggregate(conn: &mut Connection, aggregate: &AggregateConfig) -> Result<()> {
+    let base_columns = table_columns(conn, &aggregate.table_name)?;
+    if base_columns.is_empty() {
+        return Ok(());
+    }
+
+    let dimensions = match &aggregate.dimensions {
+        Some(dimensions) => dimensions.clone(),
+        None => infer_dimensions(&base_columns, &aggregate.measures),
+    };
+
+    let mut select_list: Vec<String> = dimensions.iter().map(|d| quote_identifier(d)).collect();
+    select_list.extend(aggregate.measures.iter().map(measure_sql));
+    if base_columns.iter().any(|c| c == INSERT_TIMESTAMP_COLUMN) {
+        select_list.push(format!(
+            "MAX({}) AS {}",
+            quote_identifier(INSERT_TIMESTAMP_COLUMN),
+            quote_identifier(LAST_UPDATE_COLUMN)
+        ));
+    } else {
+        select_list.push(format!("current_timestamp AS {}", quote_identifier(LAST_UPDATE_COLUMN)));
+    }
+
+    let group_by = if dimensions.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " GROUP BY {}",
+            dimensions
+                .iter()
+                .map(|d| quote_identifier(d))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    };
+
+    let sql = format!(
+        "CREATE OR REPLACE TABLE {} AS SELECT {} FROM {}{}",
+        quote_identifier(&aggregate.name),
+        select_list.join(", "),
+        quote_identifier(&aggregate.table_name),
+        group_by
+    );
+
+    let tx = conn.transaction()?;
+    tx.execute(&sql, [])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Output column name for a measure.
+pub fn measure_alias(measure: &MeasureConfig) -> String {
+    if let Some(alias) = &measure.alias {
+        return alias.clone();
+    }
+    let function = function_name(measure.function).to_lowercase();
+    if measure.column == "*" {
+        function
+    } else {
+        format!("{}_{}", function, measure.column)
+    }
+}
+
+/// `FUNCTION("column") AS "alias"` for a measure.
+pub fn measure_sql(measure: &MeasureConfig) -> String {
+    let argument = if measure.column == "*" {
+        "*".to_string()
+    } else {
+        quote_identifier(&measure.column)
+    };
+    format!(
+        "{}({}) AS {}",
+        function_name(measure.function),
+        argument,
+        quote_identifier(&measure_alias(measure))
+    )
+}
+
+fn function_name(function: AggregateFunction) -> &'static str {
+    match function {
+        AggregateFunction::Sum => "SUM",
+        AggregateFunction::Count => "COUNT",
+        AggregateFunction::Avg => "AVG",
+        AggregateFunction::Min => "MIN",
+        AggregateFunction::Max => "MAX",
+    }
+}
+
+/// Every base column that isn't measured or part of the ingestion lineage.
+fn infer_dimensions(base_columns: &[String], measures: &[MeasureConfig]) -> Vec<String> {
+    base_columns
+        .iter()
+        .filter(|c| c.as_str() != INSERT_TIMESTAMP_COLUMN && c.as_str() != SOURCE_COLUMN)
+        .filter(|c| !measures.iter().any(|m| &m.column == *c))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(yaml: &str) -> AggregateConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn trades(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE trades (sym VARCHAR, side VARCHAR, qty INTEGER, source VARCHAR, insert_timestamp TIMESTAMPTZ);
+             INSERT INTO trades VALUES
+                 ('A', 'buy', 1, 'a.csv', TIMESTAMPTZ '2024-01-01 10:00:00+00'),
+                 ('A', 'buy', 2, 'b.csv', TIMESTAMPTZ '2024-01-01 11:00:00+00'),
+                 ('B', 'sell', 5, 'a.csv', TIMESTAMPTZ '2024-01-01 09:00:00+00');",
+        )
+        .unwrap();
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        table_columns(conn, table).unwrap()
+    }
+
+    #[test]
+    fn aliases_default_to_function_and_column() {
+        let measures: Vec<MeasureConfig> = serde_yaml::from_str(
+            "[{column: qty, function: sum}, {column: '*', function: count}, {column: qty, function: max, alias: biggest}]",
+        )
+        .unwrap();
+        let aliases: Vec<String> = measures.iter().map(measure_alias).collect();
+        assert_eq!(aliases, ["sum_qty", "count", "biggest"]);
+        assert_eq!(measure_sql(&measures[0]), r#"SUM("qty") AS "sum_qty""#);
+        assert_eq!(measure_sql(&measures[1]), r#"COUNT(*) AS "count""#);
+    }
+
+    #[test]
+    fn dimensions_skip_measures_and_lineage_columns() {
+        let base: Vec<String> = ["sym", "side", "qty", SOURCE_COLUMN, INSERT_TIMESTAMP_COLUMN]
+            .map(String::from)
+            .to_vec();
+        let measures: Vec<MeasureConfig> =
+            serde_yaml::from_str("[{column: qty, function: sum}, {column: '*', function: count}]").unwrap();
+        assert_eq!(infer_dimensions(&base, &measures), ["sym", "side"]);
+    }
+
+    #[test]
+    fn groups_by_inferred_dimensions_with_the_newest_insert_time() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        trades(&conn);
+        let config = aggregate(
+            "{name: by_sym, table_name: trades, measures: [{column: qty, function: sum}, {column: '*', function: count, alias: trades}]}",
+        );
+
+        refresh_aggregate(&mut conn, &config).unwrap();
+        assert_eq!(columns(&conn, "by_sym"), ["sym", "side", "sum_qty", "trades", LAST_UPDATE_COLUMN]);
+        let mut stmt = conn
+            .prepare("SELECT sym, side, sum_qty, trades, CAST(last_update AS VARCHAR) FROM by_sym ORDER BY sym")
+            .unwrap();
+        let rows: Vec<(String, String, i64, i64, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("A".into(), "buy".into(), 3, 2, "2024-01-01 11:00:00+00".into()),
+                ("B".into(), "sell".into(), 5, 1, "2024-01-01 09:00:00+00".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn explicit_dimensions_and_a_refresh_replace_the_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        trades(&conn);
+        let config = aggregate("{name: by_side, table_name: trades, dimensions: [side], measures: [{column: qty, function: sum}]}");
+
+        refresh_aggregate(&mut conn, &config).unwrap();
+        conn.execute("INSERT INTO trades VALUES ('C', 'sell', 7, 'c.csv', now())", []).unwrap();
+        refresh_aggregate(&mut conn, &config).unwrap();
+
+        assert_eq!(columns(&conn, "by_side"), ["side", "sum_qty", LAST_UPDATE_COLUMN]);
+        let sells: i64 = conn.query_row("SELECT sum_qty FROM by_side WHERE side = 'sell'", [], |row| row.get(0)).unwrap();
+        let groups: i64 = conn.query_row("SELECT count(*) FROM by_side", [], |row| row.get(0)).unwrap();
+        assert_eq!((sells, groups), (12, 2));
+    }
+
+    #[test]
+    fn tables_without_lineage_still_get_a_last_update() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE legacy (sym VARCHAR, qty INTEGER); INSERT INTO legacy VALUES ('A', 1);")
+            .unwrap();
+        let config = aggregate("{name: legacy_agg, table_name: legacy, measures: [{column: qty, function: avg}]}");
+
+        refresh_aggregate(&mut conn, &config).unwrap();
+        let stamped: bool = conn
+            .query_row("SELECT last_update IS NOT NULL FROM legacy_agg", [], |row| row.get(0))
+            .unwrap();
+        assert!(stamped);
+    }
+
+    #[test]
+    fn skips_aggregates_whose_base_table_is_missing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let config = aggregate("{name: by_sym, table_name: trades, measures: [{column: qty, function: sum}]}");
+
+        refresh_aggregate(&mut conn, &config).unwrap();
+        assert!(columns(&conn, "by_sym").is_empty());
+
+        // Once the base table shows up, the next refresh builds it.
+        trades(&conn);
+        refresh_aggregate(&mut conn, &config).unwrap();
+        assert!(!columns(&conn, "by_sym").is_empty());
+    }
+}
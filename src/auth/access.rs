@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+use anyhow::{bail, Result};
+use crate::auth::identity::Identity;
+use crate::auth::policy::TablePolicy;
+use crate::config::config::AppConfig;
+use crate::db::catalog::filtered_catalog;
+use crate::query::read_only::{prepend_ctes, referenced_tables};
+use crate::query::sql::quote_identifier;
+
+/// Access control from `security.roles` and the datasets' and aggregates'
+/// `policy` sections.
+///
+/// A role grants read access to the tables behind some datasets and
+/// aggregates, and a caller may read whatever any of their roles grants.
+/// Names that reach a table indirectly resolve to it: a publisher to its
+/// aggregate's table, a Kafka dataset to its `table_name`. A table with a
+/// policy is read through it, so the caller sees only the rows and column
+/// values it allows.
+pub struct AccessControl {
+    /// Lower-cased tables each role grants.
+    roles: HashMap<String, HashSet<String>>,
+    /// Every dataset, aggregate and publisher name, with the (lower-cased)
+    /// table behind it.
+    tables: HashMap<String, String>,
+    /// Row and column policies by lower-cased table.
+    policies: HashMap<String, TablePolicy>,
+}
+
+/// The role whose grants apply to callers without an identity once
+/// `security.roles` is configured.
+pub const ANONYMOUS_ROLE: &str = "anonymous";
+
+/// What one caller may read, and how; see [`AccessControl::permissions`].
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    /// Lower-cased tables the caller may read, or `None` for every table.
+    readable: Option<HashSet<String>>,
+    /// For each lower-cased table with a policy that applies to the caller,
+    /// the query to read it through.
+    sources: HashMap<String, String>,
+}
+
+impl AccessControl {
+    /// Fails if a policy's row filter has an unknown placeholder.
+    pub fn new(config: &AppConfig) -> Result<Self> {
+        let mut tables = HashMap::new();
+        for dataset in &config.datasets {
+            tables.insert(dataset.name.clone(), dataset.table_name().to_lowercase());
+        }
+        for aggregate in &config.aggregates {
+            tables.insert(aggregate.name.clone(), aggregate.name.to_lowercase());
+        }
+        for publisher in &config.publishers {
+            tables.insert(publisher.name.clone(), publisher.aggregate.to_lowercase());
+        }
+
+        let mut roles = HashMap::new();
+        for role in &config.security.roles {
+            let grants: &mut HashSet<String> = roles.entry(role.name.clone()).or_default();
+            for name in &role.datasets {
+                match name.as_str() {
+                    "*" => grants.extend(config.datasets.iter().map(|d| d.table_name().to_lowercase())),
+                    name => match config.datasets.iter().find(|d| d.name == name) {
+                        Some(dataset) => {
+                            grants.insert(dataset.table_name().to_lowercase());
+                        }
+                        None => eprintln!("Role {} grants unknown dataset {}", role.name, name),
+                    },
+                }
+            }
+            for name in &role.aggregates {
+                match name.as_str() {
+                    "*" => grants.extend(config.aggregates.iter().map(|a| a.name.to_lowercase())),
+                    name if config.aggregates.iter().any(|a| a.name == name) => {
+                        grants.insert(name.to_lowercase());
+                    }
+                    name => eprintln!("Role {} grants unknown aggregate {}", role.name, name),
+                }
+            }
+        }
+
+        let mut policies = HashMap::new();
+        let policy_configs = config
+            .datasets
+            .iter()
+            .map(|d| (d.table_name(), &d.policy))
+            .chain(config.aggregates.iter().map(|a| (a.name.as_str(), &a.policy)));
+        for (table, policy) in policy_configs {
+            if let Some(policy) = policy {
+                policies.insert(table.to_lowercase(), TablePolicy::new(table, policy)?);
+            }
+        }
+
+        Ok(AccessControl { roles, tables, policies })
+    }
+
+    /// Whether any roles are configured, i.e. whether access is restricted at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.roles.is_empty()
+    }
+
+    /// What a caller may read. Every table when no roles are configured;
+    /// otherwise the union of the caller's roles, so a caller with no
+    /// configured role may read nothing. A caller without an identity gets
+    /// the [`ANONYMOUS_ROLE`], if there is one, and nothing otherwise.
+    ///
+    /// Policies apply either way, including to callers without an identity.
+    pub fn permissions(&self, identity: Option<&Identity>) -> Permissions {
+        let readable = match identity {
+            _ if !self.is_enabled() => None,
+            Some(identity) => Some(self.grants(identity.roles.iter().map(String::as_str))),
+            None => Some(self.grants([ANONYMOUS_ROLE])),
+        };
+        let sources = self
+            .policies
+            .iter()
+            .filter_map(|(table, policy)| Some((table.clone(), policy.source_query(identity)?)))
+            .collect();
+        Permissions { readable, sources }
+    }
+
+    /// The claims any row filter refers to, which are all of an identity's
+    /// claims that access control needs.
+    pub fn claims_used(&self) -> HashSet<String> {
+        self.policies
+            .values()
+            .flat_map(TablePolicy::claims)
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Every table any of `roles` grants.
+    fn grants<'a>(&self, roles: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+        roles
+            .into_iter()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the caller may read a dataset, aggregate or publisher.
+    /// Restricted callers are refused unknown names too, so a refusal doesn't
+    /// reveal whether a name exists.
+    pub fn can_read(&self, permissions: &Permissions, name: &str) -> bool {
+        match self.tables.get(name) {
+            Some(table) => permissions.can_read_table(table),
+            None => permissions.is_unrestricted(),
+        }
+    }
+
+    /// Checks a SQL query against what the caller may read, and rewrites it
+    /// to read tables with policies through them.
+    ///
+    /// Restricted callers may only name tables they were granted and the
+    /// query's own CTEs; anything else (system tables, a schema-qualified
+    /// name) is refused. A CTE that shadows a configured table counts as that
+    /// table, since the scan can't tell which one a FROM means.
+    ///
+    /// The exception is the `pg_catalog` and `information_schema` relations
+    /// clients use to discover tables: restricted callers read them filtered
+    /// to the tables they were granted (see [`filtered_catalog`]), through a
+    /// CTE named after the relation that the reference is rewritten to.
+    ///
+    /// Each table with a policy becomes a CTE of the same name in front of
+    /// the query. Naming such a table with a schema would get around that,
+    /// so it's refused.
+    pub fn secure_query(&self, permissions: &Permissions, sql: &str) -> Result<String> {
+        if permissions.is_unrestricted() && permissions.sources.is_empty() {
+            return Ok(sql.to_string());
+        }
+        let references = referenced_tables(sql)?;
+        let mut ctes = Vec::new();
+        let mut secured = HashSet::new();
+        let mut rewrites = Vec::new();
+        for (table, span) in references.tables.iter().zip(&references.table_spans) {
+            let table = table.to_lowercase();
+            let is_cte = references.ctes.contains(&table) && !self.tables.values().any(|t| *t == table);
+            if !is_cte && !permissions.can_read_table(&table) {
+                let catalog = permissions
+                    .readable
+                    .as_ref()
+                    .and_then(|readable| filtered_catalog(&table, readable));
+                let Some((name, query)) = catalog else {
+                    bail!("Access to {} is not permitted", table);
+                };
+                if references.ctes.contains(&name) || self.tables.values().any(|t| *t == name) {
+                    bail!("{} cannot be read alongside a table or CTE named {}", table, name);
+                }
+                rewrites.push((span.clone(), quote_identifier(&name)));
+                if secured.insert(name.clone()) {
+                    ctes.push(format!("{} AS ({})", quote_identifier(&name), query));
+                }
+                continue;
+            }
+
+            let unqualified = table.rsplit('.').next().unwrap_or(&table);
+            let Some(source) = permissions.sources.get(unqualified) else {
+                continue;
+            };
+            if unqualified != table {
+                bail!("Refer to {} without a schema; it has a row or column policy", unqualified);
+            }
+            if references.ctes.contains(&table) {
+                bail!("A CTE cannot be named {}; it has a row or column policy", table);
+            }
+            if secured.insert(table.clone()) {
+                ctes.push(format!("{} AS ({})", quote_identifier(&table), source));
+            }
+        }
+        let mut sql = sql.to_string();
+        // From the end, so earlier spans stay valid.
+        for (span, name) in rewrites.into_iter().rev() {
+            sql.replace_range(span, &name);
+        }
+        prepend_ctes(&sql, &ctes)
+    }
+}
+
+impl Permissions {
+    pub fn is_unrestricted(&self) -> bool {
+        self.readable.is_none()
+    }
+
+    /// Whether the caller may read a DuckDB table.
+    pub fn can_read_table(&self, table: &str) -> bool {
+        self.readable
+            .as_ref()
+            .is_none_or(|readable| readable.contains(&table.to_lowercase()))
+    }
+
+    /// What to put in a FROM clause to read `table` as the caller may see
+    /// it: the table itself, or its policy query.
+    pub fn source(&self, table: &str) -> String {
+        match self.sources.get(&table.to_lowercase()) {
+            Some(query) => format!("({}) AS {}", query, quote_identifier(table)),
+            None => quote_identifier(table),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+    use crate::db::db_pool::tests::memory_pool;
+
+    fn access(roles: &str) -> AccessControl {
+        let yaml = format!(
+            r#"
+datasets:
+  - name: trades
+    format: csv
+  - name: quotes
+    format: csv
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+  roles: {}
+"#,
+            roles
+        );
+        AccessControl::new(&serde_yaml::from_str(&yaml).unwrap()).unwrap()
+    }
+
+    fn identity(roles: &[&str]) -> Identity {
+        Identity {
+            subject: "alice".into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            claims: Map::new(),
+        }
+    }
+
+    #[test]
+    fn no_roles_means_unrestricted() {
+        let access = access("[]");
+        assert!(access.permissions(None).is_unrestricted());
+        assert!(access.can_read(&access.permissions(Some(&identity(&[]))), "quotes"));
+    }
+
+    #[test]
+    fn anonymous_callers_fail_closed() {
+        let access = access(r#"[{name: desk, datasets: [trades]}]"#);
+        let anonymous = access.permissions(None);
+        assert!(!anonymous.is_unrestricted());
+        assert!(!access.can_read(&anonymous, "trades"));
+        assert!(access.secure_query(&anonymous, "SELECT * FROM trades").is_err());
+        assert!(access.can_read(&access.permissions(Some(&identity(&["desk"]))), "trades"));
+    }
+
+    #[test]
+    fn anonymous_role_applies_without_identity() {
+        let access = access(r#"[{name: desk, datasets: ["*"]}, {name: anonymous, datasets: [quotes]}]"#);
+        let anonymous = access.permissions(None);
+        assert!(access.can_read(&anonymous, "quotes"));
+        assert!(!access.can_read(&anonymous, "trades"));
+    }
+
+    #[test]
+    fn restricted_callers_see_only_their_tables_in_the_catalog() {
+        let access = access(r#"[{name: desk, datasets: [trades]}]"#);
+        let permissions = access.permissions(Some(&identity(&["desk"])));
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch("CREATE TABLE trades (price DOUBLE); CREATE TABLE quotes (bid DOUBLE)")
+            .unwrap();
+        let names = |sql: &str| -> Vec<String> {
+            let sql = access.secure_query(&permissions, sql).unwrap();
+            let mut stmt = conn.prepare(&sql).unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+
+        assert_eq!(names("SELECT relname FROM pg_catalog.pg_class WHERE relkind = 'r'"), vec!["trades"]);
+        assert_eq!(names("SELECT relname FROM pg_class"), vec!["trades"]);
+        assert_eq!(
+            names("SELECT table_name FROM information_schema.tables t WHERE t.table_schema = 'main'"),
+            vec!["trades"]
+        );
+        assert_eq!(
+            names(
+                "SELECT a.attname FROM pg_catalog.pg_attribute a \
+                 JOIN pg_catalog.pg_class c ON a.attrelid = c.oid WHERE a.attnum > 0"
+            ),
+            vec!["price"]
+        );
+        assert!(!names("SELECT nspname FROM pg_catalog.pg_namespace").is_empty());
+    }
+
+    #[test]
+    fn other_system_tables_stay_refused() {
+        let access = access(r#"[{name: desk, datasets: [trades]}]"#);
+        let permissions = access.permissions(Some(&identity(&["desk"])));
+        for sql in [
+            "SELECT * FROM duckdb_tables",
+            "SELECT * FROM pg_catalog.pg_depend",
+            "SELECT * FROM main.quotes",
+            "SELECT * FROM system.pg_catalog.pg_class",
+        ] {
+            assert!(access.secure_query(&permissions, sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn secure_query_reads_tables_through_their_policy() {
+        let yaml = r#"
+datasets:
+  - name: trades
+    format: csv
+    policy:
+      row_filter: "desk = {{claims.desk}}"
+      masked_columns: [{column: trader, mask: redact}]
+      exempt_roles: [auditor]
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+"#;
+        let access = AccessControl::new(&serde_yaml::from_str(yaml).unwrap()).unwrap();
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE trades (desk VARCHAR, trader VARCHAR);
+             INSERT INTO trades VALUES ('fx', 'bob'), ('fx', 'carol'), ('rates', 'dave')",
+        )
+        .unwrap();
+        let rows = |permissions: &Permissions, sql: &str| -> Vec<(String, String)> {
+            let sql = access.secure_query(permissions, sql).unwrap();
+            let mut stmt = conn.prepare(&sql).unwrap();
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+
+        let mut fx = identity(&["desk"]);
+        fx.claims.insert("desk".into(), "fx".into());
+        let fx = access.permissions(Some(&fx));
+        let masked = vec![("fx".to_string(), "****".to_string()); 2];
+        assert_eq!(rows(&fx, "SELECT desk, trader FROM trades"), masked);
+        assert_eq!(
+            rows(&fx, "SELECT a.desk, b.trader FROM trades a JOIN trades b USING (desk) WHERE a.trader < 'z' LIMIT 2"),
+            masked
+        );
+        assert!(rows(&access.permissions(None), "SELECT desk, trader FROM trades").is_empty());
+        assert_eq!(
+            rows(&access.permissions(Some(&identity(&["auditor"]))), "SELECT desk, trader FROM trades ORDER BY trader").len(),
+            3
+        );
+
+        for sql in ["SELECT * FROM main.trades", "WITH trades AS (SELECT 1) SELECT * FROM trades"] {
+            assert!(access.secure_query(&fx, sql).is_err(), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use crate::auth::identity::Identity;
+use crate::auth::jwt::JwtValidator;
+use crate::auth::oauth::OAuthClient;
+
+/// Middleware that establishes who is calling, when OAuth or JWT is enabled.
+///
+/// A request carrying `Authorization: Bearer` is accepted only if the token
+/// validates; otherwise a valid OAuth session cookie is accepted. Either way
+/// the caller's [`Identity`] goes into the request extensions.
+///
+/// Unauthenticated API and WebSocket requests get `401 Unauthorized`. The UI
+/// is redirected to `/auth/login` when OAuth is enabled, and left open when
+/// only bearer tokens are (a browser can't attach one to a page load). The
+/// `/auth/*` routes are always open.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let path = req.path().to_string();
+    if path.starts_with("/auth/") {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let oauth = req.app_data::<web::Data<OAuthClient>>().cloned();
+    let jwt = req.app_data::<web::Data<JwtValidator>>().cloned();
+
+    let identity = match (&jwt, bearer_token(&req)) {
+        (Some(jwt), Some(token)) => match jwt.validate(&token).await {
+            Ok(identity) => Some(identity),
+            Err(e) => {
+                let response = HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#))
+                    .body(format!("Invalid bearer token: {}", e));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        },
+        _ => oauth.as_ref().and_then(|oauth| oauth.session(req.request())),
+    };
+    if let Some(identity) = identity {
+        req.extensions_mut().insert::<Identity>(identity);
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let response = if path.starts_with("/api/") || path == "/ws" {
+        let mut response = HttpResponse::Unauthorized();
+        if jwt.is_some() {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.body("Login required")
+    } else if oauth.is_some() {
+        let return_to = match req.query_string() {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+        let location = format!(
+            "/auth/login?return_to={}",
+            url::form_urlencoded::byte_serialize(return_to.as_bytes()).collect::<String>()
+        );
+        HttpResponse::Found()
+            .append_header((header::LOCATION, location))
+            .finish()
+    } else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// The token from an `Authorization: Bearer <token>` header, if there is one.
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use super::*;
+    use crate::auth::mock_idp::{claims, MockIdp, CLIENT_ID, ISSUER};
+    use crate::auth::session::SESSION_COOKIE;
+    use crate::config::config::{JwtConfig, OAuthConfig, SecurityConfig};
+
+    async fn oauth(idp: &MockIdp) -> web::Data<OAuthClient> {
+        let security = SecurityConfig {
+            oauth: OAuthConfig {
+                enabled: true,
+                client_id: CLIENT_ID.to_string(),
+                auth_url: format!("{}/authorize", idp.url),
+                token_url: format!("{}/token", idp.url),
+                redirect_url: "http://localhost/auth/callback".to_string(),
+                jwks_url: Some(format!("{}/jwks", idp.url)),
+                issuer: Some(ISSUER.to_string()),
+                ..OAuthConfig::default()
+            },
+            ..SecurityConfig::default()
+        };
+        web::Data::new(OAuthClient::new(&security, HashSet::new()).await.unwrap())
+    }
+
+    async fn jwt(idp: &MockIdp) -> web::Data<JwtValidator> {
+        let config = JwtConfig {
+            enabled: true,
+            issuer: ISSUER.to_string(),
+            audience: CLIENT_ID.to_string(),
+            jwks_url: Some(format!("{}/jwks", idp.url)),
+            ..JwtConfig::default()
+        };
+        web::Data::new(JwtValidator::new(&config).await.unwrap())
+    }
+
+    /// Answers every route with the caller's subject, or `anonymous`.
+    async fn whoami(identity: Option<Identity>) -> HttpResponse {
+        let subject = identity.map_or("anonymous".to_string(), |identity| identity.subject);
+        HttpResponse::Ok().body(subject)
+    }
+
+    macro_rules! app {
+        ($($data:expr),*) => {
+            test::init_service(
+                App::new()
+                    $(.app_data($data.clone()))*
+                    .wrap(from_fn(authenticate))
+                    .default_service(web::to(whoami)),
+            )
+            .await
+        };
+    }
+
+    async fn body(response: ServiceResponse<impl MessageBody>) -> String {
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    }
+
+    fn session(oauth: &OAuthClient, subject: &str) -> Cookie<'static> {
+        let identity = Identity {
+            subject: subject.to_string(),
+            roles: vec!["analyst".to_string()],
+            claims: Default::default(),
+        };
+        oauth.cookies.seal(SESSION_COOKIE, identity, 300).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn api_requests_without_a_session_are_unauthorized() {
+        let idp = MockIdp::start("one").await;
+        let app = app!(oauth(&idp).await);
+        for uri in ["/api/data/arrow?dataset=trades", "/ws"] {
+            let response = app.call(test::TestRequest::get().uri(uri).to_request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+            // Only bearer-token setups advertise the Bearer scheme.
+            assert!(response.headers().get(header::WWW_AUTHENTICATE).is_none());
+        }
+    }
+
+    #[actix_web::test]
+    async fn ui_requests_without_a_session_are_sent_to_login() {
+        let idp = MockIdp::start("one").await;
+        let app = app!(oauth(&idp).await);
+        let response = app
+            .call(test::TestRequest::get().uri("/dashboard/sales?tab=2").to_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            "/auth/login?return_to=%2Fdashboard%2Fsales%3Ftab%3D2"
+        );
+    }
+
+    #[actix_web::test]
+    async fn auth_routes_stay_open() {
+        let idp = MockIdp::start("one").await;
+        let app = app!(oauth(&idp).await, jwt(&idp).await);
+        for uri in ["/auth/login", "/auth/callback?code=x&state=y", "/auth/logout"] {
+            let response = app.call(test::TestRequest::get().uri(uri).to_request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(body(response).await, "anonymous");
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_valid_session_identifies_the_caller() {
+        let idp = MockIdp::start("one").await;
+        let oauth = oauth(&idp).await;
+        let app = app!(oauth);
+        let request = test::TestRequest::get().uri("/api/cubes").cookie(session(&oauth, "alice"));
+        let response = app.call(request.to_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "alice");
+    }
+
+    #[actix_web::test]
+    async fn tampered_session_cookies_are_rejected() {
+        let idp = MockIdp::start("one").await;
+        let oauth = oauth(&idp).await;
+        let app = app!(oauth);
+
+        // Re-signing with another key, as another server would.
+        let other = self::oauth(&idp).await;
+        let mut tampered = session(&oauth, "alice");
+        tampered.set_value(session(&other, "mallory").value().to_string());
+        let response = app
+            .call(test::TestRequest::get().uri("/api/cubes").cookie(tampered).to_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Editing the payload breaks the signature.
+        let mut edited = session(&oauth, "alice");
+        let mut value = edited.value().to_string();
+        let last = value.pop().unwrap();
+        value.push(if last == 'A' { 'B' } else { 'A' });
+        edited.set_value(value);
+        let response = app
+            .call(test::TestRequest::get().uri("/api/cubes").cookie(edited).to_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn bearer_tokens_are_validated() {
+        let idp = MockIdp::start("one").await;
+        let app = app!(jwt(&idp).await);
+
+        let token = idp.sign("one", &claims("bob", 300, json!({})));
+        let request = test::TestRequest::get()
+            .uri("/api/cubes")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+        let response = app.call(request.to_request()).await.unwrap();
+        assert_eq!(body(response).await, "bob");
+
+        let forged = idp.forge("one", &claims("bob", 300, json!({})));
+        let request = test::TestRequest::get()
+            .uri("/api/cubes")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", forged)));
+        let response = app.call(request.to_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(header::WWW_AUTHENTICATE).is_some());
+
+        // Without OAuth, the UI is left open and the API asks for a token.
+        let response = app.call(test::TestRequest::get().uri("/").to_request()).await.unwrap();
+        assert_eq!(body(response).await, "anonymous");
+        let response = app.call(test::TestRequest::get().uri("/api/cubes").to_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+    }
+}
@@ -0,0 +1,71 @@
+use std::future::{ready, Ready};
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+
+/// Registered claims that describe the token rather than the user.
+const TOKEN_CLAIMS: &[&str] = &["iss", "aud", "exp", "iat", "nbf", "jti", "nonce", "at_hash", "c_hash", "azp", "auth_time"];
+
+/// The signed-in caller, from an OAuth session or a bearer token.
+///
+/// The authentication middleware puts it in the request extensions, and
+/// handlers take it as an extractor: `Identity` where a caller is required
+/// (a request without one is rejected with `401`), or `Option<Identity>`
+/// where authentication may be turned off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub subject: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// The provider's remaining claims about the user (email, name, groups, ...).
+    #[serde(default)]
+    pub claims: Map<String, JsonValue>,
+}
+
+impl Identity {
+    /// Builds an identity from the provider's claims, taking the subject
+    /// from `subject_claim` and the roles from `roles_claim`, and dropping
+    /// claims about the token itself.
+    ///
+    /// `roles_claim` may be a dotted path into nested claims (Keycloak puts
+    /// roles in `realm_access.roles`), and the roles may be a list of
+    /// strings or a single space-separated string.
+    pub fn from_claims(mut claims: Map<String, JsonValue>, subject_claim: &str, roles_claim: &str) -> Result<Self> {
+        let subject = match claims.remove(subject_claim) {
+            Some(JsonValue::String(subject)) => subject,
+            Some(JsonValue::Number(subject)) => subject.to_string(),
+            _ => return Err(anyhow!("Claims have no {} to identify the user", subject_claim)),
+        };
+
+        let mut path = roles_claim.split('.');
+        let first = path.next().and_then(|name| claims.get(name));
+        let roles = match path.fold(first, |value, name| value.and_then(|v| v.get(name))) {
+            Some(JsonValue::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect(),
+            Some(JsonValue::String(roles)) => roles.split_whitespace().map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+
+        claims.retain(|name, _| !TOKEN_CLAIMS.contains(&name.as_str()));
+        Ok(Identity { subject, roles, claims })
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Identity>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Login required")),
+        )
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value as JsonValue};
+use crate::auth::identity::Identity;
+use crate::config::config::JwtConfig;
+
+/// Fewest seconds between two reloads triggered by an unknown key id, so a
+/// stream of bogus tokens can't hammer the JWKS endpoint.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Validates bearer tokens against the signing keys in a JWKS file or URL.
+///
+/// Keys are reloaded every `jwks_refresh_secs`, and early when a token names
+/// a key id that isn't known yet, so keys rotated in at the provider (or
+/// written to the local file) are picked up without a restart.
+pub struct JwtValidator {
+    config: JwtConfig,
+    keys: RwLock<JwksKeys>,
+    http: reqwest::Client,
+}
+
+struct JwksKeys {
+    keys: Vec<Jwk>,
+    loaded_at: Instant,
+}
+
+impl JwtValidator {
+    /// Loads the keys; fails if they can't be read, so a bad configuration
+    /// stops HydroCube at startup rather than rejecting every token.
+    pub async fn new(config: &JwtConfig) -> Result<Self> {
+        if config.jwks_path.is_none() && config.jwks_url.is_none() {
+            bail!("security.jwt needs a jwks_path or a jwks_url");
+        }
+        let validator = JwtValidator {
+            config: config.clone(),
+            keys: RwLock::new(JwksKeys {
+                keys: Vec::new(),
+                loaded_at: Instant::now(),
+            }),
+            http: reqwest::Client::new(),
+        };
+        validator.reload().await?;
+        Ok(validator)
+    }
+
+    /// Checks a token's signature, expiry, issuer and audience, and returns
+    /// the caller it identifies.
+    pub async fn validate(&self, token: &str) -> Result<Identity> {
+        let header = decode_header(token).context("Malformed token")?;
+        let jwk = match self.find_key(header.kid.as_deref()) {
+            Some(jwk) => jwk,
+            None => {
+                self.reload_if_stale().await;
+                self.find_key(header.kid.as_deref())
+                    .ok_or_else(|| anyhow!("Token is signed with an unknown key"))?
+            }
+        };
+
+        // Validation only accepts the header's algorithm if it belongs to the
+        // key's family, so an RSA public key can't be used as an HMAC secret.
+        // A key that names its algorithm is held to exactly that one.
+        if let Some(key_algorithm) = jwk.common.key_algorithm {
+            if key_algorithm.to_string().parse::<Algorithm>().ok() != Some(header.alg) {
+                bail!("Token is signed with {:?} but its key is for {}", header.alg, key_algorithm);
+            }
+        }
+        let key = DecodingKey::from_jwk(&jwk)?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.leeway = self.config.leeway_secs;
+        validation.validate_nbf = true;
+
+        let claims = decode::<Map<String, JsonValue>>(token, &key, &validation)?.claims;
+        Identity::from_claims(claims, &self.config.subject_claim, &self.config.roles_claim)
+    }
+
+    /// Reloads the keys every `jwks_refresh_secs`, keeping the old ones if a
+    /// reload fails.
+    pub async fn refresh_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.jwks_refresh_secs.max(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.reload().await {
+                eprintln!("Error reloading JWKS: {:?}", e);
+            }
+        }
+    }
+
+    /// The key a token's `kid` names; a token without one may use the only key.
+    fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        match kid {
+            Some(kid) => keys.keys.iter().find(|k| k.common.key_id.as_deref() == Some(kid)).cloned(),
+            None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn reload_if_stale(&self) {
+        {
+            let mut keys = self.keys.write().unwrap();
+            if keys.loaded_at.elapsed() < MIN_RELOAD_INTERVAL {
+                return;
+            }
+            // Claim this reload so concurrent requests don't start their own.
+            keys.loaded_at = Instant::now();
+        }
+        if let Err(e) = self.reload().await {
+            eprintln!("Error reloading JWKS: {:?}", e);
+        }
+    }
+
+    async fn reload(&self) -> Result<()> {
+        let jwks: JwkSet = match (&self.config.jwks_path, &self.config.jwks_url) {
+            (Some(path), _) => {
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Cannot read JWKS file {}", path))?;
+                serde_json::from_str(&contents).with_context(|| format!("Invalid JWKS in {}", path))?
+            }
+            (None, Some(url)) => self
+                .http
+                .get(url)
+                .send()
+                .await
+                .with_context(|| format!("Cannot fetch JWKS from {}", url))?
+                .error_for_status()?
+                .json()
+                .await
+                .with_context(|| format!("Invalid JWKS from {}", url))?,
+            (None, None) => unreachable!("checked in JwtValidator::new"),
+        };
+
+        let count = jwks.keys.len();
+        *self.keys.write().unwrap() = JwksKeys {
+            keys: jwks.keys,
+            loaded_at: Instant::now(),
+        };
+        println!("Loaded {} JWT signing key(s)", count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use tempfile::TempDir;
+    use super::*;
+    use crate::auth::mock_idp::{claims, MockIdp, CLIENT_ID, ISSUER};
+
+    async fn validator(idp: &MockIdp) -> JwtValidator {
+        JwtValidator::new(&JwtConfig {
+            enabled: true,
+            issuer: ISSUER.to_string(),
+            audience: CLIENT_ID.to_string(),
+            jwks_url: Some(format!("{}/jwks", idp.url)),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// A JWKS file with one HMAC key per `(kid, alg, secret)`.
+    fn write_jwks(path: &std::path::Path, keys: &[(&str, Option<&str>, &[u8])]) {
+        let keys: Vec<JsonValue> = keys
+            .iter()
+            .map(|(kid, alg, secret)| {
+                let mut key = json!({"kty": "oct", "kid": kid, "k": URL_SAFE_NO_PAD.encode(secret)});
+                if let Some(alg) = alg {
+                    key["alg"] = json!(alg);
+                }
+                key
+            })
+            .collect();
+        std::fs::write(path, json!({ "keys": keys }).to_string()).unwrap();
+    }
+
+    fn sign(kid: &str, algorithm: Algorithm, secret: &[u8]) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims("alice", 300, json!({})), &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    async fn file_validator(path: &std::path::Path) -> JwtValidator {
+        JwtValidator::new(&JwtConfig {
+            enabled: true,
+            issuer: ISSUER.to_string(),
+            audience: CLIENT_ID.to_string(),
+            jwks_path: Some(path.to_str().unwrap().to_string()),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Lets the next unknown key id reload the keys straight away.
+    fn age_keys(validator: &JwtValidator) {
+        validator.keys.write().unwrap().loaded_at = Instant::now().checked_sub(MIN_RELOAD_INTERVAL).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn accepts_tokens_from_the_provider() {
+        let idp = MockIdp::start("one").await;
+        let validator = validator(&idp).await;
+        let token = idp.sign("one", &claims("alice", 300, json!({"roles": ["analyst"], "region": "emea"})));
+        let identity = validator.validate(&token).await.unwrap();
+        assert_eq!(identity.subject, "alice");
+        assert_eq!(identity.roles, vec!["analyst"]);
+        assert_eq!(identity.claims.get("region"), Some(&json!("emea")));
+    }
+
+    #[actix_web::test]
+    async fn rejects_forged_expired_and_misaddressed_tokens() {
+        let idp = MockIdp::start("one").await;
+        let validator = validator(&idp).await;
+        let forged = idp.forge("one", &claims("alice", 300, json!({})));
+        assert!(validator.validate(&forged).await.is_err());
+        // Past the leeway.
+        let expired = idp.sign("one", &claims("alice", -120, json!({})));
+        assert!(validator.validate(&expired).await.is_err());
+        let other_audience = idp.sign("one", &claims("alice", 300, json!({"aud": "someone-else"})));
+        assert!(validator.validate(&other_audience).await.is_err());
+        let other_issuer = idp.sign("one", &claims("alice", 300, json!({"iss": "https://elsewhere.test"})));
+        assert!(validator.validate(&other_issuer).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn picks_up_rotated_keys() {
+        let idp = MockIdp::start("one").await;
+        let validator = validator(&idp).await;
+        let old = idp.sign("one", &claims("alice", 300, json!({})));
+        assert!(validator.validate(&old).await.is_ok());
+
+        idp.rotate("two");
+        let new = idp.sign("two", &claims("alice", 300, json!({})));
+        // Keys were just loaded, so an unknown key id doesn't reload them yet.
+        assert!(validator.validate(&new).await.is_err());
+        age_keys(&validator);
+        assert!(validator.validate(&new).await.is_ok());
+        // The retired key is gone with the reload.
+        assert!(validator.validate(&old).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn picks_up_keys_rewritten_to_the_jwks_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        write_jwks(&path, &[("one", Some("HS256"), b"first secret, at least 32 bytes!")]);
+        let validator = file_validator(&path).await;
+        assert!(validator.validate(&sign("one", Algorithm::HS256, b"first secret, at least 32 bytes!")).await.is_ok());
+
+        write_jwks(&path, &[("two", Some("HS256"), b"second secret, also 32 bytes....")]);
+        let new = sign("two", Algorithm::HS256, b"second secret, also 32 bytes....");
+        assert!(validator.validate(&new).await.is_err());
+        age_keys(&validator);
+        assert!(validator.validate(&new).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn holds_tokens_to_the_algorithm_their_key_names() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        let secret: &[u8] = b"a shared secret of 32 bytes or so";
+        write_jwks(&path, &[("pinned", Some("HS256"), secret), ("open", None, secret)]);
+        let validator = file_validator(&path).await;
+
+        assert!(validator.validate(&sign("pinned", Algorithm::HS256, secret)).await.is_ok());
+        let error = validator.validate(&sign("pinned", Algorithm::HS384, secret)).await.unwrap_err();
+        assert!(error.to_string().contains("its key is for HS256"), "{}", error);
+        // A key without an alg takes any algorithm of its family.
+        assert!(validator.validate(&sign("open", Algorithm::HS384, secret)).await.is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
+
+pub const ISSUER: &str = "https://idp.test";
+pub const CLIENT_ID: &str = "hydrocube";
+
+/// An identity provider for tests: serves its signing keys as a JWKS, and a
+/// token endpoint that redeems the codes it was told about for `id_token`s,
+/// but only given the matching PKCE verifier.
+///
+/// Keys are HMAC secrets, so tokens can be signed without generating RSA keys.
+#[derive(Clone)]
+pub struct MockIdp {
+    pub url: String,
+    state: Arc<Mutex<IdpState>>,
+}
+
+#[derive(Default)]
+struct IdpState {
+    /// Signing secrets by key id.
+    keys: HashMap<String, Vec<u8>>,
+    /// Each outstanding code's PKCE challenge and the `id_token` it redeems for.
+    codes: HashMap<String, (String, String)>,
+}
+
+impl MockIdp {
+    /// Starts the provider on a free local port, with one signing key, `kid`.
+    pub async fn start(kid: &str) -> MockIdp {
+        let state = Arc::new(Mutex::new(IdpState::default()));
+        let data = web::Data::from(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let idp = MockIdp { url, state };
+        idp.rotate(kid);
+        idp
+    }
+
+    /// Replaces every signing key with a new one, `kid`.
+    pub fn rotate(&self, kid: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.keys.clear();
+        state.keys.insert(kid.to_string(), rand::random::<[u8; 32]>().to_vec());
+    }
+
+    /// `claims`, signed with the current key `kid`.
+    pub fn sign(&self, kid: &str, claims: &JsonValue) -> String {
+        let secret = self.state.lock().unwrap().keys[kid].clone();
+        sign_with(kid, &secret, claims)
+    }
+
+    /// `claims`, signed with a secret the provider doesn't publish but
+    /// naming its key `kid`.
+    pub fn forge(&self, kid: &str, claims: &JsonValue) -> String {
+        sign_with(kid, b"not the provider's key", claims)
+    }
+
+    /// Makes `code` redeemable, once, for `id_token` by whoever holds the
+    /// verifier for `code_challenge`.
+    pub fn issue_code(&self, code: &str, code_challenge: &str, id_token: String) {
+        self.state
+            .lock()
+            .unwrap()
+            .codes
+            .insert(code.to_string(), (code_challenge.to_string(), id_token));
+    }
+}
+
+/// Claims for `subject` from this provider, expiring `expires_in` seconds
+/// from now (in the past, if negative).
+pub fn claims(subject: &str, expires_in: i64, extra: JsonValue) -> JsonValue {
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": ISSUER,
+        "aud": CLIENT_ID,
+        "sub": subject,
+        "iat": now,
+        "exp": now + expires_in,
+    });
+    if let (Some(claims), JsonValue::Object(extra)) = (claims.as_object_mut(), extra) {
+        claims.extend(extra);
+    }
+    claims
+}
+
+fn sign_with(kid: &str, secret: &[u8], claims: &JsonValue) -> String {
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(kid.to_string());
+    encode(&header, claims, &EncodingKey::from_secret(secret)).unwrap()
+}
+
+async fn jwks(state: web::Data<Mutex<IdpState>>) -> HttpResponse {
+    let keys: Vec<JsonValue> = state
+        .lock()
+        .unwrap()
+        .keys
+        .iter()
+        .map(|(kid, secret)| json!({"kty": "oct", "kid": kid, "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(secret)}))
+        .collect();
+    HttpResponse::Ok().json(json!({ "keys": keys }))
+}
+
+async fn token(state: web::Data<Mutex<IdpState>>, form: web::Form<HashMap<String, String>>) -> HttpResponse {
+    let code = form.get("code").cloned().unwrap_or_default();
+    let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+    let Some((challenge, id_token)) = state.lock().unwrap().codes.remove(&code) else {
+        return HttpResponse::BadRequest().json(json!({"error": "invalid_grant"}));
+    };
+    if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != challenge {
+        return HttpResponse::BadRequest().json(json!({"error": "invalid_grant"}));
+    }
+    HttpResponse::Ok().json(json!({"access_token": "opaque", "token_type": "Bearer", "id_token": id_token}))
+}
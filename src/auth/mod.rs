@@ -0,0 +1,9 @@
+pub mod access;
+pub mod authenticate;
+pub mod identity;
+pub mod jwt;
+pub mod oauth;
+pub mod policy;
+pub mod session;
+#[cfg(test)]
+pub(crate) mod mock_idp;
\ No newline at end of file
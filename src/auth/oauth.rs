@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use crate::auth::identity::Identity;
+use crate::auth::jwt::JwtValidator;
+use crate::auth::session::{CookieSigner, LOGIN_COOKIE, SESSION_COOKIE};
+use crate::config::config::{JwtConfig, OAuthConfig, SecurityConfig};
+
+/// How long a user has to finish logging in at the provider, in seconds.
+const LOGIN_TTL_SECS: i64 = 10 * 60;
+
+/// The OAuth2 authorization-code flow (with PKCE) against the configured
+/// provider, and the signed cookies that carry its result.
+///
+/// The session cookie holds the user's subject and roles, and only those of
+/// their other claims that a row filter refers to; see [`Self::new`].
+pub struct OAuthClient {
+    config: OAuthConfig,
+    pub cookies: CookieSigner,
+    http: reqwest::Client,
+    /// Checks the provider's `id_token`s, when `oauth.jwks_url` is set.
+    id_tokens: Option<Arc<JwtValidator>>,
+    /// The claims kept in sessions.
+    session_claims: HashSet<String>,
+}
+
+/// A login in progress, kept in a signed cookie between `/auth/login` and
+/// `/auth/callback`.
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    code_verifier: String,
+    return_to: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+impl OAuthClient {
+    /// `session_claims` are the claims to keep in sessions besides the
+    /// subject and roles: those row filters use
+    /// ([`AccessControl::claims_used`](crate::auth::access::AccessControl::claims_used)).
+    ///
+    /// Fails if the provider's user can't be verified: that needs either
+    /// `jwks_url` and `issuer`, to check an `id_token`, or `userinfo_url`.
+    pub async fn new(security: &SecurityConfig, session_claims: HashSet<String>) -> Result<Self> {
+        let config = &security.oauth;
+        let id_tokens = match &config.jwks_url {
+            Some(jwks_url) => {
+                let issuer = config
+                    .issuer
+                    .clone()
+                    .context("oauth.jwks_url needs oauth.issuer to check id_tokens against")?;
+                let validator = Arc::new(
+                    JwtValidator::new(&JwtConfig {
+                        enabled: true,
+                        issuer,
+                        audience: config.client_id.clone(),
+                        jwks_url: Some(jwks_url.clone()),
+                        subject_claim: config.subject_claim.clone(),
+                        roles_claim: config.roles_claim.clone(),
+                        ..JwtConfig::default()
+                    })
+                    .await?,
+                );
+                tokio::spawn(validator.clone().refresh_periodically());
+                Some(validator)
+            }
+            None if config.userinfo_url.is_none() => {
+                bail!("OAuth needs oauth.jwks_url and oauth.issuer to verify id_tokens, or an oauth.userinfo_url")
+            }
+            None => None,
+        };
+        Ok(OAuthClient {
+            config: config.clone(),
+            cookies: CookieSigner::new(security)?,
+            http: reqwest::Client::builder()
+                .user_agent(concat!("hydrocube/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+            id_tokens,
+            session_claims,
+        })
+    }
+
+    /// The signed-in user, if the request carries a valid session cookie.
+    pub fn session(&self, req: &HttpRequest) -> Option<Identity> {
+        self.cookies.open(req, SESSION_COOKIE)
+    }
+
+    /// The provider's authorization URL for a new login.
+    fn authorization_url(&self, login: &PendingLogin) -> Result<Url> {
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(login.code_verifier.as_bytes()));
+        let scope = self.config.scopes.join(" ");
+        Ok(Url::parse_with_params(
+            &self.config.auth_url,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("scope", scope.as_str()),
+                ("state", login.state.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?)
+    }
+
+    /// Exchanges an authorization code for tokens and works out who logged in.
+    ///
+    /// The user's claims come from the OpenID Connect `id_token`, once its
+    /// signature, issuer, audience and expiry check out, when `jwks_url` is
+    /// set and the provider returns one, and otherwise from `userinfo_url`.
+    async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<Identity> {
+        let response = self
+            .http
+            .post(&self.config.token_url)
+            .header(header::ACCEPT.as_str(), "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+            .context("Token request failed")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            bail!("Token endpoint returned {}: {}", status, body);
+        }
+        let tokens: TokenResponse = response.json().await.context("Invalid token response")?;
+
+        if let (Some(id_token), Some(id_tokens)) = (&tokens.id_token, &self.id_tokens) {
+            return id_tokens.validate(id_token).await.context("Invalid id_token");
+        }
+        let Some(userinfo_url) = &self.config.userinfo_url else {
+            bail!("Token response has no id_token and no oauth.userinfo_url is configured");
+        };
+        let claims = self
+            .http
+            .get(userinfo_url)
+            .bearer_auth(&tokens.access_token)
+            .send()
+            .await
+            .context("Userinfo request failed")?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Invalid userinfo response")?;
+        Identity::from_claims(claims, &self.config.subject_claim, &self.config.roles_claim)
+    }
+
+    /// What a session keeps of `identity`: everything but the claims no row
+    /// filter needs.
+    fn session_identity(&self, mut identity: Identity) -> Identity {
+        identity.claims.retain(|name, _| self.session_claims.contains(name));
+        identity
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    /// Where to send the user once they are logged in.
+    #[serde(default)]
+    return_to: Option<String>,
+}
+
+/// Starts a login: remembers a fresh state and PKCE verifier in a signed
+/// cookie and redirects to the provider.
+pub async fn auth_login(query: web::Query<LoginQuery>, oauth: web::Data<OAuthClient>) -> impl Responder {
+    let login = PendingLogin {
+        state: random_token(),
+        code_verifier: random_token(),
+        return_to: safe_return_to(query.return_to.as_deref()),
+    };
+    let result = oauth
+        .authorization_url(&login)
+        .and_then(|url| Ok((url, oauth.cookies.seal(LOGIN_COOKIE, login, LOGIN_TTL_SECS)?)));
+    match result {
+        Ok((url, cookie)) => HttpResponse::Found()
+            .append_header((header::LOCATION, url.to_string()))
+            .cookie(cookie)
+            .finish(),
+        Err(e) => {
+            eprintln!("Cannot start OAuth login: {:?}", e);
+            HttpResponse::InternalServerError().body("Login is misconfigured")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Finishes a login: checks the state against the pending login, exchanges
+/// the code, and swaps the login cookie for a session cookie.
+pub async fn auth_callback(
+    req: HttpRequest,
+    query: web::Query<CallbackQuery>,
+    oauth: web::Data<OAuthClient>,
+) -> impl Responder {
+    if let Some(error) = &query.error {
+        let description = query.error_description.as_deref().unwrap_or("");
+        return HttpResponse::Unauthorized().body(format!("Login failed: {} {}", error, description));
+    }
+    let Some(login) = oauth.cookies.open::<PendingLogin>(&req, LOGIN_COOKIE) else {
+        return HttpResponse::BadRequest().body("Login expired or was not started here; please try again");
+    };
+    if query.state.as_deref() != Some(login.state.as_str()) {
+        return HttpResponse::BadRequest().body("Login state does not match");
+    }
+    let Some(code) = &query.code else {
+        return HttpResponse::BadRequest().body("Missing authorization code");
+    };
+
+    let identity = match oauth.exchange_code(code, &login.code_verifier).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("OAuth code exchange failed: {:?}", e);
+            return HttpResponse::BadGateway().body("Could not complete login with the identity provider");
+        }
+    };
+    println!("User {} logged in", identity.subject);
+
+    let ttl = oauth.config.session_ttl_secs as i64;
+    match oauth.cookies.seal(SESSION_COOKIE, oauth.session_identity(identity), ttl) {
+        Ok(session) => HttpResponse::Found()
+            .append_header((header::LOCATION, login.return_to))
+            .cookie(session)
+            .cookie(oauth.cookies.removal(LOGIN_COOKIE))
+            .finish(),
+        Err(e) => {
+            eprintln!("Cannot create session: {:?}", e);
+            HttpResponse::InternalServerError().body("Could not create session")
+        }
+    }
+}
+
+/// Ends the session.
+pub async fn auth_logout(oauth: web::Data<OAuthClient>) -> impl Responder {
+    HttpResponse::Found()
+        .append_header((header::LOCATION, "/"))
+        .cookie(oauth.cookies.removal(SESSION_COOKIE))
+        .finish()
+}
+
+/// 32 random bytes, base64url-encoded: a state value or PKCE verifier.
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// A local path to return to after login. Anything else (in particular
+/// another site, or a protocol-relative `//host`) falls back to `/`.
+fn safe_return_to(return_to: Option<&str>) -> String {
+    match return_to {
+        Some(path) if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") => {
+            path.to_string()
+        }
+        _ => "/".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use super::*;
+    use crate::auth::mock_idp::{claims, MockIdp, CLIENT_ID, ISSUER};
+    use crate::config::config::OAuthConfig;
+
+    async fn client(idp: &MockIdp) -> web::Data<OAuthClient> {
+        let security = SecurityConfig {
+            oauth: OAuthConfig {
+                enabled: true,
+                client_id: CLIENT_ID.to_string(),
+                auth_url: format!("{}/authorize", idp.url),
+                token_url: format!("{}/token", idp.url),
+                redirect_url: "http://localhost/auth/callback".to_string(),
+                jwks_url: Some(format!("{}/jwks", idp.url)),
+                issuer: Some(ISSUER.to_string()),
+                ..OAuthConfig::default()
+            },
+            ..SecurityConfig::default()
+        };
+        let session_claims = HashSet::from(["region".to_string()]);
+        web::Data::new(OAuthClient::new(&security, session_claims).await.unwrap())
+    }
+
+    /// A started login: its state, PKCE challenge and login cookie.
+    struct Login {
+        state: String,
+        challenge: String,
+        cookie: Cookie<'static>,
+    }
+
+    fn cookie(response: &ServiceResponse, name: &str) -> Option<Cookie<'static>> {
+        response.response().cookies().find(|c| c.name() == name).map(|c| c.into_owned())
+    }
+
+    fn login_request() -> test::TestRequest {
+        test::TestRequest::get().uri("/auth/login?return_to=/dashboard")
+    }
+
+    /// The login `/auth/login` started, from its response.
+    fn started(response: ServiceResponse) -> Login {
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = Url::parse(response.headers().get(header::LOCATION).unwrap().to_str().unwrap()).unwrap();
+        let params: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        Login {
+            state: params["state"].clone(),
+            challenge: params["code_challenge"].clone(),
+            cookie: cookie(&response, LOGIN_COOKIE).unwrap(),
+        }
+    }
+
+    fn callback_request(code: &str, state: &str, login_cookie: Option<Cookie<'static>>) -> test::TestRequest {
+        let request = test::TestRequest::get().uri(&format!("/auth/callback?code={}&state={}", code, state));
+        match login_cookie {
+            Some(login_cookie) => request.cookie(login_cookie),
+            None => request,
+        }
+    }
+
+    /// Starts a login on `$app`.
+    macro_rules! start_login {
+        ($app:expr) => {
+            started(test::call_service(&$app, login_request().to_request()).await)
+        };
+    }
+
+    /// Calls `$app`'s callback with `callback_request`'s arguments.
+    macro_rules! callback {
+        ($app:expr, $($arg:expr),+) => {
+            test::call_service(&$app, callback_request($($arg),+).to_request()).await
+        };
+    }
+
+    macro_rules! app {
+        ($oauth:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($oauth.clone())
+                    .route("/auth/login", web::get().to(auth_login))
+                    .route("/auth/callback", web::get().to(auth_callback)),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn session_keeps_only_the_claims_row_filters_use() {
+        let idp = MockIdp::start("one").await;
+        let oauth = client(&idp).await;
+        let app = app!(oauth);
+
+        let login = start_login!(app);
+        let extra = json!({"roles": ["analyst"], "region": "emea", "email": "alice@example.com"});
+        idp.issue_code("code", &login.challenge, idp.sign("one", &claims("alice", 300, extra)));
+        let response = callback!(app, "code", &login.state, Some(login.cookie));
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/dashboard");
+
+        let session = cookie(&response, SESSION_COOKIE).unwrap();
+        let request = test::TestRequest::get().cookie(session).to_http_request();
+        let identity = oauth.session(&request).unwrap();
+        assert_eq!(identity.subject, "alice");
+        assert_eq!(identity.roles, vec!["analyst"]);
+        assert_eq!(identity.claims.len(), 1);
+        assert_eq!(identity.claims["region"], "emea");
+    }
+
+    #[actix_web::test]
+    async fn callback_checks_state_and_login_cookie() {
+        let idp = MockIdp::start("one").await;
+        let oauth = client(&idp).await;
+        let app = app!(oauth);
+
+        let login = start_login!(app);
+        idp.issue_code("code", &login.challenge, idp.sign("one", &claims("alice", 300, json!({}))));
+        let response = callback!(app, "code", "another-state", Some(login.cookie.clone()));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // The PKCE verifier is in the login cookie, so a callback can't complete without it.
+        let response = callback!(app, "code", &login.state, None);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // A cookie from another login carries another verifier, which the provider refuses.
+        let other = start_login!(app);
+        let response = callback!(app, "code", &other.state, Some(other.cookie));
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(cookie(&response, SESSION_COOKIE).is_none());
+    }
+
+    #[actix_web::test]
+    async fn refuses_forged_and_expired_id_tokens() {
+        let idp = MockIdp::start("one").await;
+        let oauth = client(&idp).await;
+        let app = app!(oauth);
+
+        for id_token in [
+            idp.forge("one", &claims("mallory", 300, json!({}))),
+            idp.sign("one", &claims("alice", -120, json!({}))),
+            idp.sign("one", &claims("alice", 300, json!({"aud": "another-client"}))),
+        ] {
+            let login = start_login!(app);
+            idp.issue_code("code", &login.challenge, id_token);
+            let response = callback!(app, "code", &login.state, Some(login.cookie));
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+            assert!(cookie(&response, SESSION_COOKIE).is_none());
+        }
+    }
+
+    #[actix_web::test]
+    async fn needs_a_way_to_verify_the_user() {
+        let security = SecurityConfig {
+            oauth: OAuthConfig {
+                enabled: true,
+                ..OAuthConfig::default()
+            },
+            ..SecurityConfig::default()
+        };
+        assert!(OAuthClient::new(&security, HashSet::new()).await.is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Result};
+use serde_json::Value as JsonValue;
+use crate::auth::identity::Identity;
+use crate::config::config::{MaskFunction, PolicyConfig};
+use crate::query::sql::{quote_identifier, quote_literal};
+
+/// A table's row filter and column masks, from its dataset's or aggregate's
+/// `policy`.
+///
+/// Applying it means reading the table through [`TablePolicy::source_query`]
+/// instead of directly, so filters, masks and whatever else the caller asks
+/// for all act on rows the caller is allowed to see.
+pub struct TablePolicy {
+    table: String,
+    row_filter: Option<Vec<TemplatePart>>,
+    masks: Vec<(String, MaskFunction)>,
+    exempt_roles: Vec<String>,
+}
+
+/// A piece of a row filter: SQL as written, or a caller attribute.
+enum TemplatePart {
+    Sql(String),
+    Subject,
+    Roles,
+    /// A claim, by its dotted path.
+    Claim(Vec<String>),
+}
+
+impl TablePolicy {
+    /// Parses the row filter, failing on placeholders that name no attribute.
+    pub fn new(table: &str, config: &PolicyConfig) -> Result<Self> {
+        Ok(TablePolicy {
+            table: table.to_string(),
+            row_filter: config.row_filter.as_deref().map(parse_template).transpose()?,
+            masks: config
+                .masked_columns
+                .iter()
+                .map(|m| (m.column.clone(), m.mask))
+                .collect(),
+            exempt_roles: config.exempt_roles.clone(),
+        })
+    }
+
+    /// The top-level claims the row filter refers to.
+    pub fn claims(&self) -> impl Iterator<Item = &str> {
+        self.row_filter.iter().flatten().filter_map(|part| match part {
+            TemplatePart::Claim(path) => Some(path[0].as_str()),
+            _ => None,
+        })
+    }
+
+    /// The query that stands in for the table for this caller, or `None` if
+    /// one of their roles is exempt. Callers without an identity are never
+    /// exempt, and their attributes are all NULL.
+    pub fn source_query(&self, identity: Option<&Identity>) -> Option<String> {
+        let exempt = identity.is_some_and(|identity| {
+            identity.roles.iter().any(|role| self.exempt_roles.contains(role))
+        });
+        if exempt {
+            return None;
+        }
+
+        let projection = if self.masks.is_empty() {
+            "*".to_string()
+        } else {
+            let replacements: Vec<String> = self
+                .masks
+                .iter()
+                .map(|(column, mask)| format!("{} AS {}", mask_sql(column, *mask), quote_identifier(column)))
+                .collect();
+            format!("* REPLACE ({})", replacements.join(", "))
+        };
+        // Qualified, so a CTE named after the table still reads the table itself.
+        let mut sql = format!("SELECT {} FROM main.{}", projection, quote_identifier(&self.table));
+        if let Some(parts) = &self.row_filter {
+            sql.push_str(&format!(" WHERE ({})", render_template(parts, identity)));
+        }
+        Some(sql)
+    }
+}
+
+/// Splits a row filter around its `{{...}}` placeholders.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else {
+            bail!("Unclosed {{{{ in row_filter: {}", template);
+        };
+        parts.push(TemplatePart::Sql(rest[..start].to_string()));
+        let name = rest[start + 2..start + length].trim();
+        parts.push(match name.split_once('.') {
+            None if name == "subject" => TemplatePart::Subject,
+            None if name == "roles" => TemplatePart::Roles,
+            Some(("claims", path)) if !path.is_empty() => {
+                TemplatePart::Claim(path.split('.').map(str::to_string).collect())
+            }
+            _ => bail!(
+                "Unknown placeholder {{{{{}}}}} in row_filter; use subject, roles or claims.<name>",
+                name
+            ),
+        });
+        rest = &rest[start + length + 2..];
+    }
+    parts.push(TemplatePart::Sql(rest.to_string()));
+    Ok(parts)
+}
+
+/// The row filter with each placeholder replaced by a SQL literal.
+fn render_template(parts: &[TemplatePart], identity: Option<&Identity>) -> String {
+    parts
+        .iter()
+        .map(|part| match (part, identity) {
+            (TemplatePart::Sql(sql), _) => sql.clone(),
+            (TemplatePart::Subject, Some(identity)) => quote_literal(&identity.subject),
+            (TemplatePart::Roles, Some(identity)) => literal(&JsonValue::from(identity.roles.clone())),
+            (TemplatePart::Claim(path), Some(identity)) => {
+                let first = identity.claims.get(&path[0]);
+                let value = path[1..].iter().fold(first, |value, name| value.and_then(|v| v.get(name)));
+                literal(value.unwrap_or(&JsonValue::Null))
+            }
+            (_, None) => "NULL".to_string(),
+        })
+        .collect()
+}
+
+/// A claim value as SQL: a list becomes comma-separated literals (NULL if
+/// empty), so it fits in `IN (...)`.
+fn literal(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null => "NULL".to_string(),
+        JsonValue::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        JsonValue::Number(n) => n.to_string(),
+        JsonValue::String(s) => quote_literal(s),
+        JsonValue::Array(items) if items.is_empty() => "NULL".to_string(),
+        JsonValue::Array(items) => items.iter().map(literal).collect::<Vec<_>>().join(", "),
+        JsonValue::Object(_) => quote_literal(&value.to_string()),
+    }
+}
+
+fn mask_sql(column: &str, mask: MaskFunction) -> String {
+    let column = quote_identifier(column);
+    match mask {
+        MaskFunction::Null => format!("CASE WHEN FALSE THEN {} END", column),
+        MaskFunction::Redact => "'****'".to_string(),
+        MaskFunction::Hash => format!("md5(CAST({} AS VARCHAR))", column),
+        MaskFunction::Last4 => format!("'****' || right(CAST({} AS VARCHAR), 4)", column),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use duckdb::Connection;
+    use serde_json::json;
+    use super::*;
+
+    fn table_policy(yaml: &str) -> TablePolicy {
+        TablePolicy::new("people", &serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn identity(roles: &[&str], claims: JsonValue) -> Identity {
+        let JsonValue::Object(claims) = claims else { panic!("claims must be an object") };
+        Identity {
+            subject: "o'brien".into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            claims,
+        }
+    }
+
+    fn people() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE people (name VARCHAR, region VARCHAR, card VARCHAR, salary DOUBLE);
+             INSERT INTO people VALUES
+                ('alice', 'emea', '4111111111111111', 100),
+                ('bob', 'apac', '5500000000000004', 200),
+                ('o''brien', 'amer', NULL, 300)",
+        )
+        .unwrap();
+        conn
+    }
+
+    /// The names `source` lets through, in order.
+    fn names(conn: &Connection, source: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("SELECT name FROM ({}) ORDER BY name", source)).unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn rejects_unknown_and_unclosed_placeholders() {
+        let config = |filter: &str| serde_yaml::from_str::<PolicyConfig>(&format!("row_filter: {:?}", filter)).unwrap();
+        for filter in ["region = {{region}}", "region = {{claims.}}", "region = {{claims.region"] {
+            assert!(TablePolicy::new("people", &config(filter)).is_err(), "{}", filter);
+        }
+        let policy = TablePolicy::new("people", &config("region = {{ claims.region }} OR {{subject}} = name")).unwrap();
+        assert_eq!(policy.claims().collect::<Vec<_>>(), vec!["region"]);
+    }
+
+    #[test]
+    fn masks_each_kind_of_column() {
+        let conn = people();
+        let policy = table_policy(
+            "
+masked_columns:
+  - {column: name, mask: hash}
+  - {column: region, mask: redact}
+  - {column: card, mask: last4}
+  - {column: salary, mask: 'null'}
+",
+        );
+        let source = policy.source_query(None).unwrap();
+        let row: (String, String, String, Option<f64>) = conn
+            .query_row(
+                &format!("SELECT name, region, card, salary FROM ({}) WHERE card LIKE '%1111'", source),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        let hash: String = conn.query_row("SELECT md5('alice')", [], |row| row.get(0)).unwrap();
+        assert_eq!(row, (hash, "****".to_string(), "****1111".to_string(), None));
+
+        // The null mask keeps the column's type.
+        let salary_type: String = conn
+            .query_row(&format!("SELECT typeof(salary) FROM ({}) LIMIT 1", source), [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(salary_type, "DOUBLE");
+    }
+
+    #[test]
+    fn claims_are_rendered_as_literals() {
+        let conn = people();
+        let policy = table_policy(r#"row_filter: "region IN ({{claims.regions}}) OR name = {{subject}}""#);
+
+        let caller = identity(&[], json!({"regions": ["emea", "x') OR (TRUE"]}));
+        assert_eq!(names(&conn, &policy.source_query(Some(&caller)).unwrap()), vec!["alice", "o'brien"]);
+
+        // A claim full of SQL is only ever a string to compare against.
+        let injected = identity(&[], json!({"regions": "apac' OR '1'='1"}));
+        assert_eq!(names(&conn, &policy.source_query(Some(&injected)).unwrap()), vec!["o'brien"]);
+
+        let nested = policy_with_nested_claim();
+        let caller = identity(&[], json!({"org": {"region": "apac"}}));
+        assert_eq!(names(&conn, &nested.source_query(Some(&caller)).unwrap()), vec!["bob"]);
+    }
+
+    fn policy_with_nested_claim() -> TablePolicy {
+        table_policy(r#"row_filter: "region = {{claims.org.region}}""#)
+    }
+
+    #[test]
+    fn missing_claims_and_anonymous_callers_see_no_rows() {
+        let conn = people();
+        let policy = policy_with_nested_claim();
+        let without_claim = identity(&[], json!({}));
+        assert!(names(&conn, &policy.source_query(Some(&without_claim)).unwrap()).is_empty());
+
+        let anonymous = policy.source_query(None).unwrap();
+        assert!(anonymous.ends_with("WHERE (region = NULL)"), "{}", anonymous);
+        assert!(names(&conn, &anonymous).is_empty());
+
+        let empty_list = table_policy(r#"row_filter: "region IN ({{roles}})""#);
+        assert!(names(&conn, &empty_list.source_query(Some(&without_claim)).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn exempt_roles_read_the_table_itself() {
+        let policy = table_policy(
+            "
+row_filter: \"region = {{claims.region}}\"
+masked_columns: [{column: card, mask: redact}]
+exempt_roles: [auditor]
+",
+        );
+        assert!(policy.source_query(Some(&identity(&["analyst", "auditor"], json!({})))).is_none());
+        assert!(policy.source_query(Some(&identity(&["analyst"], json!({})))).is_some());
+        assert!(policy.source_query(None).is_some());
+    }
+
+    #[test]
+    fn reads_the_table_even_when_a_cte_shadows_it() {
+        let conn = people();
+        let source = table_policy("row_filter: \"region = 'emea'\"").source_query(None).unwrap();
+        let sql = format!("WITH people AS ({}) SELECT name FROM people", source);
+        let name: String = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        assert_eq!(name, "alice");
+    }
+
+    #[test]
+    fn literals_of_each_json_type() {
+        assert_eq!(literal(&json!(null)), "NULL");
+        assert_eq!(literal(&json!(true)), "TRUE");
+        assert_eq!(literal(&json!(1.5)), "1.5");
+        assert_eq!(literal(&json!("it's")), "'it''s'");
+        assert_eq!(literal(&json!([])), "NULL");
+        assert_eq!(literal(&json!(["a", 1])), "'a', 1");
+        assert_eq!(literal(&json!({"a": "b'"})), r#"'{"a":"b''"}'"#);
+    }
+}
@@ -0,0 +1,94 @@
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::HttpRequest;
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::config::config::SecurityConfig;
+
+/// Holds the signed-in user's [`Identity`](crate::auth::identity::Identity), with only the
+/// claims row filters use.
+pub const SESSION_COOKIE: &str = "hydrocube_session";
+
+/// Holds the state and PKCE verifier of a login in progress.
+pub const LOGIN_COOKIE: &str = "hydrocube_login";
+
+/// Signs and verifies HydroCube's cookies.
+///
+/// Values are JSON, base64url-encoded so they are safe in a cookie, and
+/// signed so the browser can carry them but not alter them. Each carries its
+/// own expiry, which is checked on the way back in; the cookie's Max-Age only
+/// tells the browser when to drop it.
+pub struct CookieSigner {
+    key: Key,
+    secure: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Expiring<T> {
+    value: T,
+    /// Unix time, in seconds, after which the value is no longer accepted.
+    expires_at: i64,
+}
+
+impl CookieSigner {
+    pub fn new(security: &SecurityConfig) -> Result<Self> {
+        let key = match &security.oauth.session_secret {
+            Some(secret) if secret.len() < 32 => {
+                bail!("oauth.session_secret must be at least 32 bytes long")
+            }
+            Some(secret) => Key::derive_from(secret.as_bytes()),
+            None => {
+                if security.oauth.enabled {
+                    println!("No oauth.session_secret is configured; sessions will end when HydroCube restarts");
+                }
+                Key::generate()
+            }
+        };
+        Ok(CookieSigner {
+            key,
+            secure: security.https.enabled,
+        })
+    }
+
+    /// A signed cookie holding `value` for `ttl_secs` seconds.
+    pub fn seal<T: Serialize>(&self, name: &'static str, value: T, ttl_secs: i64) -> Result<Cookie<'static>> {
+        let expiring = Expiring {
+            value,
+            expires_at: chrono::Utc::now().timestamp() + ttl_secs,
+        };
+        let encoded = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&expiring)?);
+
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&self.key).add(
+            Cookie::build(name, encoded)
+                .path("/")
+                .http_only(true)
+                .secure(self.secure)
+                // Lax, so the cookie comes back on the provider's redirect to the callback.
+                .same_site(SameSite::Lax)
+                .max_age(Duration::seconds(ttl_secs))
+                .finish(),
+        );
+        Ok(jar.get(name).cloned().expect("cookie was just added"))
+    }
+
+    /// The value of a signed cookie, if it is present, untampered and unexpired.
+    pub fn open<T: DeserializeOwned>(&self, req: &HttpRequest, name: &str) -> Option<T> {
+        let mut jar = CookieJar::new();
+        jar.add_original(req.cookie(name)?);
+        let cookie = jar.signed(&self.key).get(name)?;
+        let bytes = URL_SAFE_NO_PAD.decode(cookie.value()).ok()?;
+        let expiring: Expiring<T> = serde_json::from_slice(&bytes).ok()?;
+        (expiring.expires_at > chrono::Utc::now().timestamp()).then_some(expiring.value)
+    }
+
+    /// A cookie that makes the browser drop `name`.
+    pub fn removal(&self, name: &'static str) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, "").path("/").finish();
+        cookie.make_removal();
+        cookie
+    }
+}
@@ -1,10 +1,38 @@
-use rustls::quic::Tag;
 use serde::Deserialize;
+use crate::scheduler::schedule::Schedule;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub datasets: Vec<DatasetConfig>,
     pub security: SecurityConfig,
+    #[serde(default)]
+    pub aggregates: Vec<AggregateConfig>,
+    #[serde(default)]
+    pub publishers: Vec<PublisherConfig>,
+    #[serde(default)]
+    pub flight: FlightConfig,
+    #[serde(default)]
+    pub postgres: PostgresConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+}
+
+impl AppConfig {
+    /// The DuckDB table behind a dataset or aggregate name, if either is configured.
+    pub fn table_for(&self, name: &str) -> Option<&str> {
+        self.datasets
+            .iter()
+            .find(|d| d.name == name)
+            .map(|d| d.table_name())
+            .or_else(|| {
+                self.aggregates
+                    .iter()
+                    .find(|a| a.name == name)
+                    .map(|a| a.name.as_str())
+            })
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -17,11 +45,34 @@ pub struct DatasetConfig {
     #[serde(default)]
     pub pattern: Option<String>,
 
+    /// Quiet period in milliseconds: a file is only ingested once it has had no
+    /// filesystem events and its size has stopped changing for this long.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+
     pub format: FileFormat,
 
     // If the format is Kafka, this field will hold Kafka-specific settings.
     #[serde(default)]
     pub kafka: Option<KafkaTopicConfig>,
+
+    /// Row-level security and column masking applied whenever the dataset is read.
+    #[serde(default)]
+    pub policy: Option<PolicyConfig>,
+}
+
+impl DatasetConfig {
+    /// The DuckDB table this dataset is ingested into.
+    pub fn table_name(&self) -> &str {
+        match &self.kafka {
+            Some(kafka) => &kafka.table_name,
+            None => &self.name,
+        }
+    }
+}
+
+fn default_debounce_ms() -> u64 {
+    1000
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -54,27 +105,360 @@ pub struct SchemaField {
     pub json_path: String,
 }
 
+// ------------------------------------------------------
+// Aggregation
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AggregateConfig {
+    /// Name of the aggregate; also the DuckDB table it is materialized into.
+    pub name: String,
+
+    /// The base table to aggregate.
+    pub table_name: String,
+
+    pub measures: Vec<MeasureConfig>,
+
+    /// Columns to group by. If omitted, every base column that isn't a measure
+    /// or an ingestion lineage column is used.
+    #[serde(default)]
+    pub dimensions: Option<Vec<String>>,
+
+    /// When to refresh. Defaults to `on_change`, i.e. whenever the base table
+    /// ingests new data; an interval or cron schedule refreshes on a clock instead.
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+
+    /// Row-level security and column masking applied whenever the aggregate is read.
+    #[serde(default)]
+    pub policy: Option<PolicyConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MeasureConfig {
+    /// The base column to aggregate, or `*` for `count`.
+    pub column: String,
+
+    pub function: AggregateFunction,
+
+    /// Output column name. Defaults to `<function>_<column>`, or just
+    /// `<function>` for `*`.
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregateFunction {
+    Sum,
+    Count,
+    Avg,
+    Min,
+    Max,
+}
+
+// ------------------------------------------------------
+// Row-level security and masking
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicyConfig {
+    /// SQL predicate a row must satisfy to be seen. `{{subject}}`, `{{roles}}`
+    /// and `{{claims.<name>}}` are replaced with the caller's attributes as
+    /// SQL literals (`{{roles}}` as a comma-separated list, for `IN (...)`).
+    #[serde(default)]
+    pub row_filter: Option<String>,
+
+    /// Columns whose values are replaced before anyone sees them.
+    #[serde(default)]
+    pub masked_columns: Vec<MaskedColumn>,
+
+    /// Roles that see every row, unmasked.
+    #[serde(default)]
+    pub exempt_roles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaskedColumn {
+    pub column: String,
+    pub mask: MaskFunction,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskFunction {
+    /// NULL, keeping the column's type.
+    Null,
+    /// The text `****`.
+    Redact,
+    /// The MD5 hash of the value as text, so equal values still match.
+    Hash,
+    /// `****` followed by the last four characters of the value.
+    Last4,
+}
+
+// ------------------------------------------------------
+// Publishing
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublisherConfig {
+    /// Name clients subscribe to over the WebSocket.
+    pub name: String,
+
+    /// The aggregate whose rows are published.
+    pub aggregate: String,
+
+    /// Column identifying a row, so clients can apply deltas as partial updates.
+    pub key_column: String,
+
+    /// Only rows whose value in this column advanced since the last push are sent.
+    #[serde(default = "default_change_detection_column")]
+    pub change_detection_column: String,
+
+    /// When to look for changes. Defaults to `on_change`.
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+fn default_change_detection_column() -> String {
+    "last_update".into()
+}
+
+// ------------------------------------------------------
+// HTTP server
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServerConfig {
+    #[serde(default = "default_server_host")]
+    pub host: String,
+    /// Serves the app when HTTPS is off, and the redirect when it's on.
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
+    #[serde(default = "default_https_port")]
+    pub https_port: u16,
+    /// Actix worker threads; one per core when unset.
+    pub workers: Option<usize>,
+    /// How long an idle connection is kept open. Actix's default (5 seconds)
+    /// when unset; 0 closes connections after every response.
+    pub keep_alive_secs: Option<u64>,
+    /// Largest request body accepted, in bytes.
+    #[serde(default = "default_max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// With HTTPS on, also listens on `http_port` and redirects every
+    /// request to HTTPS.
+    #[serde(default)]
+    pub redirect_http: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: default_server_host(),
+            http_port: default_http_port(),
+            https_port: default_https_port(),
+            workers: None,
+            keep_alive_secs: None,
+            max_payload_bytes: default_max_payload_bytes(),
+            redirect_http: false,
+        }
+    }
+}
+
+fn default_server_host() -> String {
+    "0.0.0.0".into()
+}
+
+fn default_http_port() -> u16 {
+    8080
+}
+
+fn default_https_port() -> u16 {
+    8443
+}
+
+fn default_max_payload_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+// ------------------------------------------------------
+// DuckDB database
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DatabaseConfig {
+    /// Database file, or `:memory:` for a database that lives only as long
+    /// as the process.
+    #[serde(default = "default_database_path")]
+    pub path: String,
+    /// DuckDB's `memory_limit`, e.g. `"4GB"`. DuckDB's own default (80% of
+    /// RAM) when unset.
+    pub memory_limit: Option<String>,
+    /// DuckDB's worker threads. One per core when unset.
+    pub threads: Option<u32>,
+    /// Where DuckDB spills data that doesn't fit in memory.
+    pub temp_directory: Option<String>,
+    #[serde(default)]
+    pub pool: PoolConfig,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: default_database_path(),
+            memory_limit: None,
+            threads: None,
+            temp_directory: None,
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+fn default_database_path() -> String {
+    "/tmp/hydrocube.duckdb".into()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PoolConfig {
+    /// Most connections open at once.
+    #[serde(default = "default_pool_max_size")]
+    pub max_size: u32,
+    /// Idle connections kept open; `max_size` when unset.
+    pub min_idle: Option<u32>,
+    /// How long to wait for a free connection before failing.
+    #[serde(default = "default_connection_timeout_secs")]
+    pub connection_timeout_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: default_pool_max_size(),
+            min_idle: None,
+            connection_timeout_secs: default_connection_timeout_secs(),
+        }
+    }
+}
+
+fn default_pool_max_size() -> u32 {
+    10
+}
+
+fn default_connection_timeout_secs() -> u64 {
+    30
+}
+
+// ------------------------------------------------------
+// Arrow Flight SQL
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FlightConfig {
+    /// Starts a gRPC Flight SQL listener next to the HTTP server.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_flight_host")]
+    pub host: String,
+    #[serde(default = "default_flight_port")]
+    pub port: u16,
+}
+
+impl Default for FlightConfig {
+    fn default() -> Self {
+        FlightConfig {
+            enabled: false,
+            host: default_flight_host(),
+            port: default_flight_port(),
+        }
+    }
+}
+
+fn default_flight_host() -> String {
+    "0.0.0.0".into()
+}
+
+fn default_flight_port() -> u16 {
+    50051
+}
+
+// ------------------------------------------------------
+// PostgreSQL wire protocol
+// ------------------------------------------------------
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PostgresConfig {
+    /// Starts a PostgreSQL wire-protocol listener next to the HTTP server.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_postgres_host")]
+    pub host: String,
+    #[serde(default = "default_postgres_port")]
+    pub port: u16,
+    /// Logins accepted by the listener (MD5 password authentication).
+    #[serde(default)]
+    pub users: Vec<PostgresUser>,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        PostgresConfig {
+            enabled: false,
+            host: default_postgres_host(),
+            port: default_postgres_port(),
+            users: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PostgresUser {
+    pub username: String,
+    pub password: String,
+    /// Roles from `security.roles` that decide which datasets this login may read.
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+fn default_postgres_host() -> String {
+    "0.0.0.0".into()
+}
+
+fn default_postgres_port() -> u16 {
+    5432
+}
 
 // ------------------------------------------------------
 // Security-related structs (unchanged, except for minor
 // formatting or comments).
 // ------------------------------------------------------
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct SecurityConfig {
     pub oauth: OAuthConfig,
     pub https: HttpsConfig,
+    #[serde(default)]
+    pub jwt: JwtConfig,
+    /// Which datasets and aggregates each role may read. With no roles, every
+    /// caller may read everything.
+    #[serde(default)]
+    pub roles: Vec<RoleConfig>,
 }
 
-impl Default for SecurityConfig {
-    fn default() -> Self {
-        SecurityConfig {
-            oauth: OAuthConfig::default(),
-            https: HttpsConfig::default(),
-        }
-    }
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleConfig {
+    /// Matched against the roles in a caller's identity.
+    pub name: String,
+    /// Datasets this role may read, by name; `*` for all of them.
+    #[serde(default)]
+    pub datasets: Vec<String>,
+    /// Aggregates this role may read, by name; `*` for all of them. Publishers
+    /// are readable wherever their aggregate is.
+    #[serde(default)]
+    pub aggregates: Vec<String>,
 }
 
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct OAuthConfig {
     pub enabled: bool,
@@ -90,6 +474,30 @@ pub struct OAuthConfig {
     /// Optional scopes for OAuth.
     #[serde(default)]
     pub scopes: Vec<String>,
+    /// Endpoint returning the signed-in user's claims, for providers whose
+    /// token response has no OpenID Connect `id_token` (e.g. GitHub).
+    #[serde(default)]
+    pub userinfo_url: Option<String>,
+    /// The provider's JWKS endpoint. An OpenID Connect `id_token` is only
+    /// accepted if one of its keys signed it.
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// Required `iss` claim of the `id_token`; needed with `jwks_url`.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// The claim identifying the user.
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+    /// The claim listing the user's roles; may be a dotted path.
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// Secret (at least 32 bytes) used to sign session cookies. If omitted, a
+    /// random one is generated and sessions end when HydroCube restarts.
+    #[serde(default)]
+    pub session_secret: Option<String>,
+    /// How long a login lasts, in seconds.
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
 }
 
 impl Default for OAuthConfig {
@@ -103,10 +511,82 @@ impl Default for OAuthConfig {
             token_url: "".into(),
             redirect_url: "".into(),
             scopes: vec![],
+            userinfo_url: None,
+            jwks_url: None,
+            issuer: None,
+            subject_claim: default_subject_claim(),
+            roles_claim: default_roles_claim(),
+            session_secret: None,
+            session_ttl_secs: default_session_ttl_secs(),
         }
     }
 }
 
+fn default_subject_claim() -> String {
+    "sub".into()
+}
+
+fn default_roles_claim() -> String {
+    "roles".into()
+}
+
+fn default_session_ttl_secs() -> u64 {
+    8 * 60 * 60
+}
+
+/// Validation of `Authorization: Bearer` tokens, for API clients that can't
+/// follow a browser login.
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Required `iss` claim.
+    pub issuer: String,
+    /// Required `aud` claim.
+    pub audience: String,
+    /// A local JWKS file with the signing keys. Either this or `jwks_url` is required.
+    #[serde(default)]
+    pub jwks_path: Option<String>,
+    /// The identity provider's JWKS endpoint.
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// How often to reload the keys, in seconds. Keys are also reloaded when
+    /// a token is signed with one that isn't known yet.
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds.
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            enabled: false,
+            issuer: "".into(),
+            audience: "".into(),
+            jwks_path: None,
+            jwks_url: None,
+            jwks_refresh_secs: default_jwks_refresh_secs(),
+            leeway_secs: default_jwt_leeway_secs(),
+            subject_claim: default_subject_claim(),
+            roles_claim: default_roles_claim(),
+        }
+    }
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct HttpsConfig {
     pub enabled: bool,
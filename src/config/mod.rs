@@ -1,2 +1,3 @@
 pub mod cli;
+#[allow(clippy::module_inception)]
 pub mod config;
\ No newline at end of file
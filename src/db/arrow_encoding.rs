@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use anyhow::Result;
+use arrow::csv::WriterBuilder;
+use arrow::datatypes::Schema;
+use arrow::ipc::writer::StreamWriter;
+use arrow::json::ArrayWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+
+/// Serializes batches as an Arrow IPC stream (schema message, batches, end marker).
+pub fn encode_ipc_stream(schema: &Schema, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut writer = StreamWriter::try_new(&mut buffer, schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    drop(writer);
+    Ok(buffer)
+}
+
+/// Serializes batches as a JSON array of row objects.
+pub fn encode_json_rows(batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut writer = ArrayWriter::new(Vec::new());
+    writer.write_batches(&batches.iter().collect::<Vec<_>>())?;
+    writer.finish()?;
+    let mut buffer = writer.into_inner();
+    if buffer.is_empty() {
+        // The writer emits nothing at all for zero rows.
+        buffer.extend_from_slice(b"[]");
+    }
+    Ok(buffer)
+}
+
+/// Serializes batches as CSV with a header row.
+pub fn encode_csv(schema: &Schema, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if batches.is_empty() {
+        // The writer only emits the header alongside the first batch.
+        let header: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        buffer.extend_from_slice(header.join(",").as_bytes());
+        buffer.push(b'\n');
+        return Ok(buffer);
+    }
+    let mut writer = WriterBuilder::new().with_header(true).build(&mut buffer);
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    drop(writer);
+    Ok(buffer)
+}
+
+/// Serializes batches as a single Parquet file.
+pub fn encode_parquet(schema: &Schema, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, Arc::new(schema.clone()), None)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.close()?;
+    Ok(buffer)
+}
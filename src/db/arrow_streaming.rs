@@ -0,0 +1,220 @@
+use std::io;
+use actix_web::web::Bytes;
+use anyhow::{anyhow, bail, Result};
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use duckdb::types::Value;
+use duckdb::{params_from_iter, Connection};
+use r2d2::Pool;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::db::interrupt::QueryInterrupt;
+
+/// How many batches may wait for the consumer before the query pauses.
+const BATCHES_IN_FLIGHT: usize = 4;
+
+pub type BatchReceiver = mpsc::Receiver<Result<RecordBatch>>;
+
+pub type ChunkStream = ReceiverStream<Result<Bytes, io::Error>>;
+
+/// Runs a query on a blocking thread and hands its record batches over a
+/// bounded channel as DuckDB produces them.
+///
+/// DuckDB produces batches only as fast as they are received: a slow
+/// consumer parks the query thread. If the receiver is dropped the query is
+/// interrupted, even before its first batch, which releases its pooled
+/// connection. A failure partway through arrives as a final `Err` item.
+///
+/// The query runs in a transaction that is always rolled back, as
+/// [`run_read_only_query`](crate::query::executor::run_read_only_query)'s
+/// do, so nothing it does outlives it on the pooled connection.
+///
+/// Returns once the query has started, so errors such as a missing table are
+/// reported here rather than halfway through a response.
+pub async fn stream_batches(
+    pool: Pool<DuckDBConnectionManager>,
+    sql: String,
+    params: Vec<Value>,
+) -> Result<(SchemaRef, BatchReceiver)> {
+    let (batches_tx, batches_rx) = mpsc::channel(BATCHES_IN_FLIGHT);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let interrupt = QueryInterrupt::default();
+    let watcher = {
+        let batches_tx = batches_tx.clone();
+        let interrupt = interrupt.clone();
+        tokio::spawn(async move {
+            batches_tx.closed().await;
+            interrupt.interrupt();
+        })
+    };
+
+    task::spawn_blocking(move || {
+        let mut ready = Some(ready_tx);
+        let result = send_batches(&pool, &sql, &params, &batches_tx, &mut ready, &interrupt);
+        // Its sender would keep the receiver waiting for more.
+        watcher.abort();
+        match (result, ready) {
+            (Ok(()), _) => {}
+            // Failed before the first batch: let the caller pick the response.
+            (Err(e), Some(ready)) => {
+                let _ = ready.send(Err(e));
+            }
+            (Err(_), None) if batches_tx.is_closed() => {
+                println!("Client disconnected; abandoned query: {}", sql);
+            }
+            (Err(e), None) => {
+                eprintln!("Error streaming query {}: {:?}", sql, e);
+                let _ = batches_tx.blocking_send(Err(e));
+            }
+        }
+    });
+
+    let schema = ready_rx
+        .await
+        .map_err(|_| anyhow!("Query task ended before starting"))??;
+    Ok((schema, batches_rx))
+}
+
+/// Like [`stream_batches`], but encodes the batches as an Arrow IPC stream,
+/// one chunk per batch, for use as an HTTP response body.
+pub async fn stream_ipc(
+    pool: Pool<DuckDBConnectionManager>,
+    sql: String,
+    params: Vec<Value>,
+) -> Result<ChunkStream> {
+    let (schema, mut batches) = stream_batches(pool, sql, params).await?;
+    let (chunks_tx, chunks_rx) = mpsc::channel(BATCHES_IN_FLIGHT);
+
+    tokio::spawn(async move {
+        let result: Result<()> = async {
+            let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+            send_chunk(&mut writer, &chunks_tx).await?;
+            while let Some(batch) = batches.recv().await {
+                writer.write(&batch?)?;
+                send_chunk(&mut writer, &chunks_tx).await?;
+            }
+            writer.finish()?;
+            send_chunk(&mut writer, &chunks_tx).await
+        }
+        .await;
+
+        // Returning drops `batches`, which stops the query if it's still running.
+        if let Err(e) = result {
+            if !chunks_tx.is_closed() {
+                let _ = chunks_tx.send(Err(io::Error::other(e.to_string()))).await;
+            }
+        }
+    });
+
+    Ok(ReceiverStream::new(chunks_rx))
+}
+
+/// Sends whatever the writer has produced since the last chunk.
+async fn send_chunk(
+    writer: &mut StreamWriter<Vec<u8>>,
+    chunks: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<()> {
+    let chunk = std::mem::take(writer.get_mut());
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    chunks
+        .send(Ok(Bytes::from(chunk)))
+        .await
+        .map_err(|_| anyhow!("client disconnected"))
+}
+
+fn send_batches(
+    pool: &Pool<DuckDBConnectionManager>,
+    sql: &str,
+    params: &[Value],
+    batches_tx: &mpsc::Sender<Result<RecordBatch>>,
+    ready: &mut Option<oneshot::Sender<Result<SchemaRef>>>,
+    interrupt: &QueryInterrupt,
+) -> Result<()> {
+    let mut conn = pool.get()?;
+    let _armed = interrupt.arm(&conn);
+    if interrupt.is_interrupted() {
+        bail!("client disconnected");
+    }
+    // Dropping the transaction on an early return rolls it back too.
+    let tx = conn.transaction()?;
+    {
+        let schema = result_schema(&tx, sql, params)?;
+        if interrupt.is_interrupted() {
+            bail!("client disconnected");
+        }
+        let mut stmt = tx.prepare(sql)?;
+        let batches = stmt.stream_arrow(params_from_iter(params.iter()), schema.clone())?;
+
+        if let Some(ready) = ready.take() {
+            let _ = ready.send(Ok(schema));
+        }
+
+        for batch in batches {
+            batches_tx
+                .blocking_send(Ok(batch))
+                .map_err(|_| anyhow!("client disconnected"))?;
+        }
+        // An interrupted stream just ends.
+        if interrupt.is_interrupted() {
+            bail!("client disconnected");
+        }
+    }
+    tx.rollback()?;
+    Ok(())
+}
+
+/// The schema of a query's result, found by running it with no rows.
+///
+/// A streaming result needs its schema up front, and duckdb only exposes a
+/// statement's schema once it has executed.
+pub fn result_schema(conn: &Connection, sql: &str, params: &[Value]) -> Result<SchemaRef> {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM ({}) LIMIT 0", sql))?;
+    let arrow = stmt.query_arrow(params_from_iter(params.iter()))?;
+    Ok(arrow.get_schema())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    /// Takes far longer than any test before producing its one row.
+    const SLOW_QUERY: &str = "SELECT count(*) FROM range(100000000) a, range(100000000) b WHERE a.range + b.range = -1";
+
+    #[tokio::test]
+    async fn streams_every_batch() {
+        let pool = memory_pool(1);
+        let (schema, mut batches) = stream_batches(pool, "SELECT * FROM range(5000)".to_string(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(schema.fields().len(), 1);
+        let mut rows = 0;
+        while let Some(batch) = batches.recv().await {
+            rows += batch.unwrap().num_rows();
+        }
+        assert_eq!(rows, 5000);
+    }
+
+    #[tokio::test]
+    async fn disconnect_interrupts_a_query_before_its_first_batch() {
+        let pool = memory_pool(1);
+        let started = stream_batches(pool.clone(), SLOW_QUERY.to_string(), Vec::new());
+        // The client gives up while DuckDB is still working towards the first batch.
+        assert!(tokio::time::timeout(Duration::from_millis(500), started).await.is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.state().idle_connections == 0 {
+            assert!(Instant::now() < deadline, "query was not interrupted");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // The connection is usable again, without a stray interrupt pending.
+        let conn = pool.get().unwrap();
+        assert_eq!(conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0)).unwrap(), 1);
+    }
+}
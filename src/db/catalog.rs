@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use anyhow::Result;
+use duckdb::{params, Connection};
+use crate::query::sql::quote_literal;
+
+/// Column names of a table in declaration order; empty if it doesn't exist.
+pub fn table_columns(conn: &Connection, table_name: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT column_name FROM information_schema.columns
+         WHERE table_schema = 'main' AND table_name = ?
+         ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map(params![table_name], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(columns)
+}
+
+/// Columns of a table with their DuckDB types (e.g. `DECIMAL(18,4)`), in
+/// declaration order; empty if it doesn't exist.
+pub fn table_column_types(conn: &Connection, table_name: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT column_name, data_type FROM information_schema.columns
+         WHERE table_schema = 'main' AND table_name = ?
+         ORDER BY ordinal_position",
+    )?;
+    let columns = stmt
+        .query_map(params![table_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    Ok(columns)
+}
+
+/// Catalog relations that describe tables, with the column naming each row's
+/// table, or (for `oid`) the column holding its `pg_class` oid.
+const TABLE_CATALOGS: &[(&str, &str, CatalogKey)] = &[
+    ("information_schema", "tables", CatalogKey::Name("table_name")),
+    ("information_schema", "columns", CatalogKey::Name("table_name")),
+    ("information_schema", "table_constraints", CatalogKey::Name("table_name")),
+    ("information_schema", "key_column_usage", CatalogKey::Name("table_name")),
+    ("information_schema", "constraint_column_usage", CatalogKey::Name("table_name")),
+    ("information_schema", "constraint_table_usage", CatalogKey::Name("table_name")),
+    ("pg_catalog", "pg_class", CatalogKey::Name("relname")),
+    ("pg_catalog", "pg_tables", CatalogKey::Name("tablename")),
+    ("pg_catalog", "pg_views", CatalogKey::Name("viewname")),
+    ("pg_catalog", "pg_indexes", CatalogKey::Name("tablename")),
+    ("pg_catalog", "pg_attribute", CatalogKey::Oid("attrelid")),
+    ("pg_catalog", "pg_attrdef", CatalogKey::Oid("adrelid")),
+    ("pg_catalog", "pg_constraint", CatalogKey::Oid("conrelid")),
+    ("pg_catalog", "pg_index", CatalogKey::Oid("indrelid")),
+];
+
+/// Catalog relations that say nothing about tables, so anyone may read them.
+const NEUTRAL_CATALOGS: &[(&str, &str)] = &[
+    ("information_schema", "schemata"),
+    ("information_schema", "character_sets"),
+    ("pg_catalog", "pg_namespace"),
+    ("pg_catalog", "pg_type"),
+    ("pg_catalog", "pg_database"),
+    ("pg_catalog", "pg_proc"),
+    ("pg_catalog", "pg_settings"),
+    ("pg_catalog", "pg_am"),
+    ("pg_catalog", "pg_enum"),
+    ("pg_catalog", "pg_tablespace"),
+];
+
+#[derive(Clone, Copy)]
+enum CatalogKey {
+    Name(&'static str),
+    Oid(&'static str),
+}
+
+/// A catalog relation as a caller restricted to `readable` (lower-cased
+/// tables) may see it: the relation's unqualified name and a query for only
+/// the rows about those tables.
+///
+/// `name` is a lower-cased reference as written, qualified with
+/// `pg_catalog` or `information_schema`, or a bare `pg_catalog` name.
+/// Returns `None` if it isn't one of the catalog relations clients use to
+/// discover tables.
+pub fn filtered_catalog(name: &str, readable: &HashSet<String>) -> Option<(String, String)> {
+    let (schema, relation) = match name.split_once('.') {
+        Some((schema, relation)) => (schema, relation),
+        None => ("pg_catalog", name),
+    };
+    let qualified = format!("{}.{}", schema, relation);
+    if NEUTRAL_CATALOGS.contains(&(schema, relation)) {
+        return Some((relation.to_string(), format!("SELECT * FROM {}", qualified)));
+    }
+    let (_, _, key) = TABLE_CATALOGS
+        .iter()
+        .find(|(s, r, _)| *s == schema && *r == relation)?;
+
+    let mut tables: Vec<&String> = readable.iter().collect();
+    tables.sort();
+    let names = if tables.is_empty() {
+        "NULL".to_string()
+    } else {
+        tables.iter().map(|t| quote_literal(t)).collect::<Vec<_>>().join(", ")
+    };
+    let filter = match key {
+        CatalogKey::Name(column) => format!("lower({}) IN ({})", column, names),
+        CatalogKey::Oid(column) => format!(
+            "{} IN (SELECT oid FROM pg_catalog.pg_class WHERE lower(relname) IN ({}))",
+            column, names
+        ),
+    };
+    Some((relation.to_string(), format!("SELECT * FROM {} WHERE {}", qualified, filter)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    #[test]
+    fn catalog_relations_exist() {
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        let readable = HashSet::from(["trades".to_string()]);
+        let relations = TABLE_CATALOGS
+            .iter()
+            .map(|(schema, relation, _)| (*schema, *relation))
+            .chain(NEUTRAL_CATALOGS.iter().copied());
+        for (schema, relation) in relations {
+            let (name, query) = filtered_catalog(&format!("{}.{}", schema, relation), &readable).unwrap();
+            assert_eq!(name, relation);
+            conn.prepare(&query).unwrap_or_else(|e| panic!("{}: {}", query, e));
+        }
+    }
+
+    #[test]
+    fn nothing_readable_filters_every_row() {
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch("CREATE TABLE trades (price DOUBLE)").unwrap();
+        let (_, query) = filtered_catalog("pg_class", &HashSet::new()).unwrap();
+        let count: i64 = conn
+            .query_row(&format!("SELECT count(*) FROM ({})", query), [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        assert!(filtered_catalog("pg_catalog.pg_depend", &HashSet::new()).is_none());
+    }
+}
@@ -1,13 +1,102 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Context;
 use duckdb::Connection;
-use r2d2::ManageConnection;
+use r2d2::event::{AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, TimeoutEvent};
+use r2d2::{HandleEvent, ManageConnection, Pool};
+use serde::Serialize;
+use crate::config::config::DatabaseConfig;
+use crate::query::sql::quote_literal;
 
+/// Hands out connections to one DuckDB database.
+///
+/// The database is opened once and every connection is cloned from it, so
+/// they all share the same instance (which is the only way `:memory:` can
+/// be pooled at all). The `database` section's settings are applied to
+/// each connection as it's opened.
+///
+/// A connection going back to the pool is reset: a transaction left open
+/// is rolled back. One that can't be reset is discarded. The settings are
+/// global to the database, so they aren't applied again.
 pub struct DuckDBConnectionManager {
-    connection_string: String,
+    database: Mutex<Connection>,
+    /// `SET` statements for the configured settings.
+    settings: String,
+    metrics: Arc<PoolMetrics>,
+}
+
+/// Counters for the connection pool, served at `GET /api/metrics/pool`.
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    checkouts: AtomicU64,
+    /// Checkouts that left the pool without an idle connection: they took
+    /// the last one, or had to wait for one to be returned or opened.
+    waits: AtomicU64,
+    wait_time_ms: AtomicU64,
+    /// Checkouts that gave up after `connection_timeout_secs`.
+    timeouts: AtomicU64,
+    /// Connections returned with a transaction still open.
+    rollbacks: AtomicU64,
+    /// Connections discarded because they couldn't be reset.
+    broken: AtomicU64,
+}
+
+/// A snapshot of [`PoolMetrics`] and the pool's current size.
+#[derive(Debug, Serialize)]
+pub struct PoolMetricsSnapshot {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub checkouts: u64,
+    pub waits: u64,
+    pub wait_time_ms: u64,
+    pub timeouts: u64,
+    pub rollbacks: u64,
+    pub broken: u64,
 }
 
 impl DuckDBConnectionManager {
-    pub fn new(connection_string: String) -> Self {
-        Self { connection_string }
+    /// Opens the database, failing if it can't be opened or a setting is
+    /// rejected.
+    pub fn new(config: &DatabaseConfig) -> duckdb::Result<Self> {
+        let database = if config.path == ":memory:" {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(&config.path)?
+        };
+
+        let mut settings = String::new();
+        if let Some(memory_limit) = &config.memory_limit {
+            settings.push_str(&format!("SET memory_limit = {};", quote_literal(memory_limit)));
+        }
+        if let Some(threads) = config.threads {
+            settings.push_str(&format!("SET threads = {};", threads));
+        }
+        if let Some(temp_directory) = &config.temp_directory {
+            settings.push_str(&format!("SET temp_directory = {};", quote_literal(temp_directory)));
+        }
+        if !settings.is_empty() {
+            database.execute_batch(&settings)?;
+        }
+
+        Ok(Self {
+            database: Mutex::new(database),
+            settings,
+            metrics: Arc::new(PoolMetrics::default()),
+        })
+    }
+
+    /// Rolls back a transaction left open.
+    ///
+    /// `ROLLBACK` succeeds inside any transaction, even one that failed, and
+    /// fails outside one. Only when it fails does the connection have to be
+    /// checked, to tell an idle connection from a broken one.
+    fn reset(&self, conn: &mut Connection) -> duckdb::Result<()> {
+        if conn.execute_batch("ROLLBACK").is_ok() {
+            self.metrics.rollbacks.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.is_valid(conn)
     }
 }
 
@@ -16,15 +105,195 @@ impl ManageConnection for DuckDBConnectionManager {
     type Error = duckdb::Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        Connection::open(&self.connection_string)
+        let conn = self.database.lock().unwrap().try_clone()?;
+        if !self.settings.is_empty() {
+            conn.execute_batch(&self.settings)?;
+        }
+        Ok(conn)
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        conn.execute("SELECT 1", [])?;
+        conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0))?;
         Ok(())
     }
 
-    fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
-        false
+    /// Called by r2d2 whenever a connection is returned, so this is where
+    /// connections are reset.
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        match self.reset(conn) {
+            Ok(()) => false,
+            Err(e) => {
+                eprintln!("Discarding DuckDB connection that couldn't be reset: {}", e);
+                self.metrics.broken.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+}
+
+impl PoolMetrics {
+    pub fn snapshot(&self, pool: &Pool<DuckDBConnectionManager>) -> PoolMetricsSnapshot {
+        let state = pool.state();
+        PoolMetricsSnapshot {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            waits: self.waits.load(Ordering::Relaxed),
+            wait_time_ms: self.wait_time_ms.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            rollbacks: self.rollbacks.load(Ordering::Relaxed),
+            broken: self.broken.load(Ordering::Relaxed),
+        }
     }
 }
+
+/// Feeds r2d2's events into [`PoolMetrics`].
+///
+/// The handler belongs to the pool, so it can't hold the pool to ask for its
+/// state; it counts idle connections from the events instead.
+#[derive(Debug)]
+struct MetricsEventHandler {
+    metrics: Arc<PoolMetrics>,
+    idle: AtomicI64,
+}
+
+impl HandleEvent for MetricsEventHandler {
+    fn handle_acquire(&self, _event: AcquireEvent) {
+        self.idle.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_release(&self, _event: ReleaseEvent) {
+        self.idle.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.metrics.checkouts.fetch_add(1, Ordering::Relaxed);
+        if self.idle.fetch_sub(1, Ordering::Relaxed) <= 1 {
+            self.metrics.waits.fetch_add(1, Ordering::Relaxed);
+            self.metrics
+                .wait_time_ms
+                .fetch_add(event.duration().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn handle_checkin(&self, _event: CheckinEvent) {
+        self.idle.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Opens the database and builds the connection pool, sized by the
+/// `database.pool` section, along with the pool's metrics.
+pub fn create_pool(
+    config: &DatabaseConfig,
+) -> anyhow::Result<(Pool<DuckDBConnectionManager>, Arc<PoolMetrics>)> {
+    let manager = DuckDBConnectionManager::new(config)
+        .with_context(|| format!("Cannot open DuckDB database {}", config.path))?;
+    let metrics = manager.metrics.clone();
+    let pool = Pool::builder()
+        .max_size(config.pool.max_size)
+        .min_idle(config.pool.min_idle)
+        .connection_timeout(Duration::from_secs(config.pool.connection_timeout_secs))
+        .test_on_check_out(false)
+        .event_handler(Box::new(MetricsEventHandler {
+            metrics: metrics.clone(),
+            idle: AtomicI64::new(0),
+        }))
+        .build(manager)
+        .context("Failed to create DuckDB connection pool")?;
+    Ok((pool, metrics))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::config::PoolConfig;
+
+    /// A pool over a fresh in-memory database, with up to `max_size` connections.
+    pub(crate) fn memory_pool(max_size: u32) -> Pool<DuckDBConnectionManager> {
+        metered_pool(max_size).0
+    }
+
+    fn metered_pool(max_size: u32) -> (Pool<DuckDBConnectionManager>, Arc<PoolMetrics>) {
+        let config = DatabaseConfig {
+            path: ":memory:".to_string(),
+            pool: PoolConfig {
+                max_size,
+                connection_timeout_secs: 1,
+                ..PoolConfig::default()
+            },
+            ..DatabaseConfig::default()
+        };
+        create_pool(&config).unwrap()
+    }
+
+    #[test]
+    fn connections_without_a_transaction_come_back_as_they_are() {
+        let (pool, metrics) = metered_pool(1);
+        pool.get().unwrap().execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        pool.get().unwrap().execute_batch("INSERT INTO t VALUES (1)").unwrap();
+
+        let conn = pool.get().unwrap();
+        let count: i64 = conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        let snapshot = metrics.snapshot(&pool);
+        assert_eq!((snapshot.rollbacks, snapshot.broken), (0, 0));
+    }
+
+    #[test]
+    fn returned_transaction_is_rolled_back() {
+        let (pool, metrics) = metered_pool(1);
+        pool.get().unwrap().execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        {
+            let conn = pool.get().unwrap();
+            conn.execute_batch("BEGIN TRANSACTION; INSERT INTO t VALUES (1)").unwrap();
+        }
+        let conn = pool.get().unwrap();
+        let count: i64 = conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+        // Back in autocommit mode, so there's nothing left to roll back.
+        assert!(conn.execute_batch("ROLLBACK").is_err());
+        assert_eq!(metrics.snapshot(&pool).rollbacks, 1);
+    }
+
+    #[test]
+    fn failed_transaction_is_rolled_back() {
+        let (pool, metrics) = metered_pool(1);
+        {
+            let conn = pool.get().unwrap();
+            conn.execute_batch("BEGIN TRANSACTION").unwrap();
+            assert!(conn.execute_batch("SELECT * FROM missing_table").is_err());
+        }
+        let conn = pool.get().unwrap();
+        assert_eq!(conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0)).unwrap(), 1);
+        let snapshot = metrics.snapshot(&pool);
+        assert_eq!((snapshot.rollbacks, snapshot.broken), (1, 0));
+    }
+
+    #[test]
+    fn checkouts_that_leave_no_idle_connection_count_as_waits() {
+        let (pool, metrics) = metered_pool(2);
+        let first = pool.get().unwrap();
+        assert_eq!(metrics.snapshot(&pool).waits, 0);
+
+        let second = pool.get().unwrap();
+        assert_eq!(metrics.snapshot(&pool).waits, 1);
+
+        // The pool is exhausted, so this one waits for `first` to come back.
+        let waiter = {
+            let pool = pool.clone();
+            std::thread::spawn(move || drop(pool.get().unwrap()))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(first);
+        waiter.join().unwrap();
+        drop(second);
+
+        let snapshot = metrics.snapshot(&pool);
+        assert_eq!((snapshot.checkouts, snapshot.waits), (3, 2));
+        assert!(snapshot.wait_time_ms >= 50);
+    }
+}
\ No newline at end of file
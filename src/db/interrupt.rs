@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+use duckdb::{Connection, InterruptHandle};
+
+/// Lets another thread cancel the query running on a pooled connection, once
+/// whoever was waiting for its results has gone away.
+///
+/// DuckDB only checks for an interrupt while a query runs, so a query that
+/// hasn't produced its first batch yet stops too. The interrupt only reaches
+/// the connection while it is [armed](QueryInterrupt::arm): disarming comes
+/// before the connection goes back to the pool, so a late interrupt can't
+/// cancel the next borrower's query.
+#[derive(Clone, Default)]
+pub struct QueryInterrupt(Arc<Mutex<InterruptState>>);
+
+#[derive(Default)]
+struct InterruptState {
+    handle: Option<Arc<InterruptHandle>>,
+    interrupted: bool,
+}
+
+/// Disarms its [`QueryInterrupt`] when dropped.
+pub struct Armed<'a>(&'a QueryInterrupt);
+
+impl QueryInterrupt {
+    /// Points the interrupt at `conn` until the returned guard is dropped;
+    /// keep the guard in a variable declared after the connection's, so it
+    /// goes first.
+    pub fn arm(&self, conn: &Connection) -> Armed<'_> {
+        self.0.lock().unwrap().handle = Some(conn.interrupt_handle());
+        Armed(self)
+    }
+
+    /// Cancels the running query, if any, and every later check of
+    /// [`Self::is_interrupted`].
+    pub fn interrupt(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.interrupted = true;
+        if let Some(handle) = &state.handle {
+            handle.interrupt();
+        }
+    }
+
+    /// Whether [`Self::interrupt`] was called. DuckDB forgets an interrupt
+    /// that arrives between two statements once the next one starts, so
+    /// check this before starting each.
+    pub fn is_interrupted(&self) -> bool {
+        self.0.lock().unwrap().interrupted
+    }
+}
+
+impl Drop for Armed<'_> {
+    fn drop(&mut self) {
+        self.0 .0.lock().unwrap().handle = None;
+    }
+}
@@ -0,0 +1,24 @@
+use duckdb::types::Value;
+use serde_json::Value as JsonValue;
+
+/// Converts a JSON value into the DuckDB value bound in its place.
+///
+/// Integers stay integers, other numbers become doubles, and arrays and
+/// objects are bound as their JSON text. A missing value binds as NULL.
+pub fn to_duckdb_value(value: Option<&JsonValue>) -> Value {
+    match value {
+        None | Some(JsonValue::Null) => Value::Null,
+        Some(JsonValue::Bool(b)) => Value::Boolean(*b),
+        Some(JsonValue::Number(n)) => {
+            if let Some(i) = n.as_i64() {
+                Value::BigInt(i)
+            } else if let Some(u) = n.as_u64() {
+                Value::UBigInt(u)
+            } else {
+                Value::Double(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Some(JsonValue::String(s)) => Value::Text(s.clone()),
+        Some(other) => Value::Text(other.to_string()),
+    }
+}
@@ -1,2 +1,6 @@
-pub mod db_manager;
-pub mod db_pool;
\ No newline at end of file
+pub mod arrow_encoding;
+pub mod arrow_streaming;
+pub mod catalog;
+pub mod db_pool;
+pub mod interrupt;
+pub mod json_value;
\ No newline at end of file
@@ -0,0 +1,724 @@
+// tonic's Status is large, but it's the error type the Flight SQL traits require.
+#![allow(clippy::result_large_err)]
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::ipc::writer::IpcWriteOptions;
+use arrow::record_batch::RecordBatch;
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::error::FlightError;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::sql::metadata::{SqlInfoData, SqlInfoDataBuilder};
+use arrow_flight::sql::server::FlightSqlService;
+use arrow_flight::sql::{
+    ActionClosePreparedStatementRequest, ActionCreatePreparedStatementRequest,
+    ActionCreatePreparedStatementResult, CommandGetCatalogs, CommandGetDbSchemas,
+    CommandGetSqlInfo, CommandGetTableTypes, CommandGetTables, CommandPreparedStatementQuery,
+    CommandStatementQuery, ProstMessageExt, SqlInfo, TicketStatementQuery,
+};
+use arrow_flight::{
+    Action, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo, IpcMessage, SchemaAsIpc,
+    Ticket,
+};
+use prost::Message;
+use r2d2::Pool;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use crate::auth::access::{AccessControl, Permissions};
+use crate::auth::identity::Identity;
+use crate::auth::jwt::JwtValidator;
+use crate::config::config::AppConfig;
+use crate::db::arrow_streaming::{result_schema, stream_batches};
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::query::read_only::ensure_read_only;
+use crate::server::tls::Certificates;
+
+/// Only DuckDB's default schema holds HydroCube tables.
+const SCHEMA_NAME: &str = "main";
+
+/// How long a client gets to complete its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pauses after a failed accept, doubling up to the maximum while accepts
+/// keep failing (e.g. when out of file descriptors).
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+type FlightDataStream = Pin<Box<dyn Stream<Item = Result<FlightData, Status>> + Send + 'static>>;
+
+/// Starts the Arrow Flight SQL listener and serves until it fails.
+///
+/// Uses TLS with the certificate and key from `security.https` when HTTPS is
+/// enabled (`certificates`), so Flight clients connect with the same trust as
+/// browsers, and pick up a renewed certificate just the same.
+pub async fn run_flight_server(
+    pool: Pool<DuckDBConnectionManager>,
+    config: &AppConfig,
+    certificates: Option<Arc<Certificates>>,
+) -> Result<()> {
+    let address: SocketAddr = format!("{}:{}", config.flight.host, config.flight.port)
+        .parse()
+        .with_context(|| format!("Invalid Flight SQL address {}", config.flight.host))?;
+    let jwt = if config.security.jwt.enabled {
+        let validator = Arc::new(JwtValidator::new(&config.security.jwt).await?);
+        tokio::spawn(validator.clone().refresh_periodically());
+        Some(validator)
+    } else {
+        None
+    };
+
+    if certificates.is_some() {
+        println!("Starting Flight SQL server (TLS) on {}", address);
+    } else {
+        println!("Starting Flight SQL server on {}", address);
+    }
+    if jwt.is_some() {
+        println!("Flight SQL clients must send a JWT bearer token");
+    } else {
+        if !config.security.roles.is_empty() {
+            println!("Flight SQL clients are not authenticated, so they may only read what the anonymous role grants");
+        }
+        if config.datasets.iter().any(|d| d.policy.is_some()) || config.aggregates.iter().any(|a| a.policy.is_some()) {
+            println!("Flight SQL clients see tables with policies as a caller with no identity would");
+        }
+    }
+    let service = HydroCubeFlightSql::new(pool, config, jwt)?;
+
+    let router = Server::builder().add_service(FlightServiceServer::new(service));
+    match certificates {
+        Some(certificates) => {
+            let mut tls_config = certificates.server_config();
+            tls_config.alpn_protocols = vec![b"h2".to_vec()];
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Cannot bind Flight SQL listener to {}", address))?;
+            router
+                .serve_with_incoming(tls_incoming(listener, TlsAcceptor::from(Arc::new(tls_config))))
+                .await?;
+        }
+        None => router.serve(address).await?,
+    }
+    Ok(())
+}
+
+/// Accepts connections and completes their TLS handshakes, each in its own
+/// task so a slow client can't hold up the others. A handshake that takes
+/// longer than [`HANDSHAKE_TIMEOUT`] is dropped.
+fn tls_incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> ReceiverStream<std::io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        while !tx.is_closed() {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    socket
+                }
+                Err(e) => {
+                    eprintln!("Flight SQL accept error: {:?}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => eprintln!("Flight SQL TLS handshake failed: {:?}", e),
+                    Err(_) => eprintln!("Flight SQL TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Flight SQL over the DuckDB pool.
+///
+/// Statements are read-only, as for `POST /api/query`, and run in a
+/// transaction that is rolled back; a prepared statement's handle is simply
+/// its SQL, so nothing is held between calls. The catalog lists the tables
+/// behind the configured datasets and aggregates.
+///
+/// With JWT enabled every call must carry `authorization: Bearer <token>`,
+/// and the token's roles and claims apply as they do over HTTP. Otherwise
+/// clients are anonymous, and see only what a caller with no identity would.
+pub struct HydroCubeFlightSql {
+    pool: Pool<DuckDBConnectionManager>,
+    tables: Vec<String>,
+    sql_info: Arc<SqlInfoData>,
+    access: AccessControl,
+    jwt: Option<Arc<JwtValidator>>,
+}
+
+impl HydroCubeFlightSql {
+    pub fn new(
+        pool: Pool<DuckDBConnectionManager>,
+        config: &AppConfig,
+        jwt: Option<Arc<JwtValidator>>,
+    ) -> Result<Self> {
+        let mut tables: Vec<String> = config
+            .datasets
+            .iter()
+            .map(|d| d.table_name().to_string())
+            .chain(config.aggregates.iter().map(|a| a.name.clone()))
+            .collect();
+        tables.sort();
+        tables.dedup();
+
+        let mut sql_info = SqlInfoDataBuilder::new();
+        sql_info.append(SqlInfo::FlightSqlServerName, "HydroCube");
+        sql_info.append(SqlInfo::FlightSqlServerVersion, env!("CARGO_PKG_VERSION"));
+        sql_info.append(SqlInfo::FlightSqlServerArrowVersion, "1.3");
+        sql_info.append(SqlInfo::FlightSqlServerReadOnly, true);
+
+        let access = AccessControl::new(config)?;
+        Ok(HydroCubeFlightSql {
+            pool,
+            tables,
+            sql_info: Arc::new(sql_info.build().expect("valid SqlInfo")),
+            access,
+            jwt,
+        })
+    }
+
+    /// Who is calling: the identity in the request's bearer token when JWT
+    /// is enabled, in which case a call without a valid one is refused, and
+    /// no one otherwise.
+    async fn authenticate(&self, metadata: &MetadataMap) -> Result<Option<Identity>, Status> {
+        let Some(jwt) = &self.jwt else {
+            return Ok(None);
+        };
+        let token = bearer_token(metadata).ok_or_else(|| Status::unauthenticated("Login required"))?;
+        jwt.validate(&token)
+            .await
+            .map(Some)
+            .map_err(|e| Status::unauthenticated(format!("Invalid bearer token: {}", e)))
+    }
+
+    /// What the caller may read; see [`Self::authenticate`].
+    async fn permissions(&self, metadata: &MetadataMap) -> Result<Permissions, Status> {
+        let identity = self.authenticate(metadata).await?;
+        Ok(self.access.permissions(identity.as_ref()))
+    }
+
+    /// The statement as the caller may run it; see [`AccessControl::secure_query`].
+    fn secure(&self, permissions: &Permissions, sql: &str) -> Result<String, Status> {
+        ensure_read_only(sql).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.access
+            .secure_query(permissions, sql)
+            .map_err(|e| Status::permission_denied(e.to_string()))
+    }
+
+    /// The schema a read-only statement would return.
+    async fn statement_schema(&self, permissions: &Permissions, sql: String) -> Result<SchemaRef, Status> {
+        let sql = self.secure(permissions, &sql)?;
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || -> Result<SchemaRef> {
+            let mut conn = pool.get()?;
+            let tx = conn.transaction()?;
+            let schema = result_schema(&tx, &sql, &[])?;
+            tx.rollback()?;
+            Ok(schema)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::invalid_argument(e.to_string()))
+    }
+
+    /// Runs a read-only statement and streams its batches as Flight data.
+    async fn execute(&self, permissions: &Permissions, sql: String) -> Result<Response<FlightDataStream>, Status> {
+        let sql = self.secure(permissions, &sql)?;
+        let (schema, batches) = stream_batches(self.pool.clone(), sql, Vec::new())
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let batches = ReceiverStream::new(batches)
+            .map(|batch| batch.map_err(|e| FlightError::from_external_error(e.into())));
+        Ok(encode(schema, batches))
+    }
+
+    /// The database name DuckDB reports, used as the single catalog.
+    async fn catalog_name(&self) -> Result<String, Status> {
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || -> Result<String> {
+            Ok(pool.get()?.query_row("SELECT current_database()", [], |row| row.get(0))?)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    /// Schemas of the configured tables that exist yet.
+    async fn table_schemas(&self, permissions: Permissions) -> Result<Vec<(String, SchemaRef)>, Status> {
+        let pool = self.pool.clone();
+        let tables = self.tables.clone();
+        task::spawn_blocking(move || -> Result<Vec<(String, SchemaRef)>> {
+            let conn = pool.get()?;
+            let mut schemas = Vec::new();
+            for table in tables.into_iter().filter(|table| permissions.can_read_table(table)) {
+                // As read through any policy, whose masks may change column types.
+                let sql = format!("SELECT * FROM {}", permissions.source(&table));
+                // Tables are created on first ingest, so some may not exist yet.
+                if let Ok(schema) = result_schema(&conn, &sql, &[]) {
+                    schemas.push((table, schema));
+                }
+            }
+            Ok(schemas)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// A FlightInfo whose single endpoint's ticket is `command` itself.
+fn flight_info(
+    command: impl ProstMessageExt,
+    schema: &Schema,
+    descriptor: FlightDescriptor,
+) -> Result<Response<FlightInfo>, Status> {
+    let ticket = Ticket::new(command.as_any().encode_to_vec());
+    let info = FlightInfo::new()
+        .try_with_schema(schema)
+        .map_err(|e| Status::internal(format!("Unable to encode schema: {}", e)))?
+        .with_endpoint(FlightEndpoint::new().with_ticket(ticket))
+        .with_descriptor(descriptor);
+    Ok(Response::new(info))
+}
+
+fn encode<S>(schema: SchemaRef, batches: S) -> Response<FlightDataStream>
+where
+    S: Stream<Item = Result<RecordBatch, FlightError>> + Send + 'static,
+{
+    let stream = FlightDataEncoderBuilder::new()
+        .with_schema(schema)
+        .build(batches)
+        .map(|data| data.map_err(Status::from));
+    Response::new(Box::pin(stream))
+}
+
+fn encode_one(
+    schema: SchemaRef,
+    batch: Result<RecordBatch, FlightError>,
+) -> Response<FlightDataStream> {
+    encode(schema, tokio_stream::once(batch))
+}
+
+#[tonic::async_trait]
+impl FlightSqlService for HydroCubeFlightSql {
+    type FlightService = HydroCubeFlightSql;
+
+    async fn get_flight_info_statement(
+        &self,
+        query: CommandStatementQuery,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        let schema = self.statement_schema(&permissions, query.query.clone()).await?;
+        let ticket = TicketStatementQuery {
+            statement_handle: query.query.into_bytes().into(),
+        };
+        flight_info(ticket, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_prepared_statement(
+        &self,
+        query: CommandPreparedStatementQuery,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        let sql = handle_to_sql(&query.prepared_statement_handle)?;
+        let schema = self.statement_schema(&permissions, sql).await?;
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_catalogs(
+        &self,
+        query: CommandGetCatalogs,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let schema = query.into_builder().schema();
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_schemas(
+        &self,
+        query: CommandGetDbSchemas,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let schema = query.clone().into_builder().schema();
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_tables(
+        &self,
+        query: CommandGetTables,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let schema = query.clone().into_builder().schema();
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_table_types(
+        &self,
+        query: CommandGetTableTypes,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let schema = query.into_builder().schema();
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn get_flight_info_sql_info(
+        &self,
+        query: CommandGetSqlInfo,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let schema = query.clone().into_builder(&self.sql_info).schema();
+        flight_info(query, &schema, request.into_inner())
+    }
+
+    async fn do_get_statement(
+        &self,
+        ticket: TicketStatementQuery,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        self.execute(&permissions, handle_to_sql(&ticket.statement_handle)?).await
+    }
+
+    async fn do_get_prepared_statement(
+        &self,
+        query: CommandPreparedStatementQuery,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        self.execute(&permissions, handle_to_sql(&query.prepared_statement_handle)?).await
+    }
+
+    async fn do_get_catalogs(
+        &self,
+        query: CommandGetCatalogs,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let mut builder = query.into_builder();
+        builder.append(self.catalog_name().await?);
+        Ok(encode_one(builder.schema(), builder.build()))
+    }
+
+    async fn do_get_schemas(
+        &self,
+        query: CommandGetDbSchemas,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let mut builder = query.into_builder();
+        builder.append(self.catalog_name().await?, SCHEMA_NAME);
+        Ok(encode_one(builder.schema(), builder.build()))
+    }
+
+    async fn do_get_tables(
+        &self,
+        query: CommandGetTables,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        let catalog = self.catalog_name().await?;
+        let mut builder = query.into_builder();
+        for (table, schema) in self.table_schemas(permissions).await? {
+            builder
+                .append(&catalog, SCHEMA_NAME, table, "TABLE", &schema)
+                .map_err(Status::from)?;
+        }
+        Ok(encode_one(builder.schema(), builder.build()))
+    }
+
+    async fn do_get_table_types(
+        &self,
+        query: CommandGetTableTypes,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let mut builder = query.into_builder();
+        builder.append("TABLE");
+        Ok(encode_one(builder.schema(), builder.build()))
+    }
+
+    async fn do_get_sql_info(
+        &self,
+        query: CommandGetSqlInfo,
+        request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        self.authenticate(request.metadata()).await?;
+        let builder = query.into_builder(&self.sql_info);
+        Ok(encode_one(builder.schema(), builder.build()))
+    }
+
+    async fn do_action_create_prepared_statement(
+        &self,
+        query: ActionCreatePreparedStatementRequest,
+        request: Request<Action>,
+    ) -> Result<ActionCreatePreparedStatementResult, Status> {
+        let permissions = self.permissions(request.metadata()).await?;
+        let schema = self.statement_schema(&permissions, query.query.clone()).await?;
+        let IpcMessage(dataset_schema) = SchemaAsIpc::new(&schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow::error::ArrowError| Status::internal(e.to_string()))?;
+        Ok(ActionCreatePreparedStatementResult {
+            prepared_statement_handle: query.query.into_bytes().into(),
+            dataset_schema,
+            parameter_schema: Default::default(),
+        })
+    }
+
+    async fn do_action_close_prepared_statement(
+        &self,
+        _query: ActionClosePreparedStatementRequest,
+        _request: Request<Action>,
+    ) -> Result<(), Status> {
+        // Handles carry their SQL, so there's nothing to release.
+        Ok(())
+    }
+
+    async fn register_sql_info(&self, _id: i32, _result: &SqlInfo) {}
+}
+
+fn handle_to_sql(handle: &[u8]) -> Result<String, Status> {
+    String::from_utf8(handle.to_vec())
+        .map_err(|_| Status::invalid_argument("Statement handle is not valid UTF-8"))
+}
+
+/// The token from an `authorization: Bearer <token>` header, if there is one.
+fn bearer_token(metadata: &MetadataMap) -> Option<String> {
+    let value = metadata.get("authorization")?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{Array, Float64Array, StringArray};
+    use arrow_flight::decode::FlightRecordBatchStream;
+    use arrow_flight::sql::Any;
+    use futures::TryStreamExt;
+    use serde_json::json;
+    use tonic::Code;
+    use super::*;
+    use crate::auth::mock_idp::{claims, MockIdp, CLIENT_ID, ISSUER};
+    use crate::config::config::JwtConfig;
+    use crate::db::db_pool::tests::memory_pool;
+
+    /// `trades` and `quotes`; the desk role may read only `trades`, and only
+    /// its own desk's rows of it.
+    fn config() -> AppConfig {
+        serde_yaml::from_str(
+            r#"
+datasets:
+  - name: trades
+    format: csv
+    policy:
+      row_filter: "desk = {{claims.desk}}"
+  - name: quotes
+    format: csv
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+  roles:
+    - {name: desk, datasets: [trades]}
+"#,
+        )
+        .unwrap()
+    }
+
+    async fn service(idp: &MockIdp) -> HydroCubeFlightSql {
+        let pool = memory_pool(2);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE trades (desk VARCHAR, price DOUBLE);
+                 INSERT INTO trades VALUES ('fx', 1.5), ('rates', 2.5);
+                 CREATE TABLE quotes (bid DOUBLE)",
+            )
+            .unwrap();
+        let jwt = JwtValidator::new(&JwtConfig {
+            enabled: true,
+            issuer: ISSUER.to_string(),
+            audience: CLIENT_ID.to_string(),
+            jwks_url: Some(format!("{}/jwks", idp.url)),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+        HydroCubeFlightSql::new(pool, &config(), Some(Arc::new(jwt))).unwrap()
+    }
+
+    /// A request carrying `authorization: <authorization>`, if given.
+    fn request<T>(message: T, authorization: Option<&str>) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = authorization {
+            request.metadata_mut().insert("authorization", authorization.parse().unwrap());
+        }
+        request
+    }
+
+    /// A desk trader's bearer token.
+    fn bearer(idp: &MockIdp) -> String {
+        let token = idp.sign("one", &claims("alice", 300, json!({"roles": ["desk"], "desk": "fx"})));
+        format!("Bearer {}", token)
+    }
+
+    async fn batches(response: Response<FlightDataStream>) -> Vec<RecordBatch> {
+        let data = response.into_inner().map_err(FlightError::from);
+        FlightRecordBatchStream::new_from_flight_data(data).try_collect().await.unwrap()
+    }
+
+    fn strings(batches: &[RecordBatch], column: &str) -> Vec<String> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                let array = batch.column_by_name(column).unwrap();
+                let array = array.as_any().downcast_ref::<StringArray>().unwrap().clone();
+                (0..array.len()).map(move |i| array.value(i).to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_bearer_tokens() {
+        let token = |value: &str| {
+            let mut metadata = MetadataMap::new();
+            metadata.insert("authorization", value.parse().unwrap());
+            bearer_token(&metadata)
+        };
+        assert_eq!(token("Bearer abc"), Some("abc".to_string()));
+        assert_eq!(token("bearer  abc "), Some("abc".to_string()));
+        assert_eq!(token("Basic abc"), None);
+        assert_eq!(token("Bearer"), None);
+        assert_eq!(bearer_token(&MetadataMap::new()), None);
+    }
+
+    #[actix_web::test]
+    async fn refuses_calls_without_a_valid_token() {
+        let idp = MockIdp::start("one").await;
+        let service = service(&idp).await;
+        let forged = format!("Bearer {}", idp.forge("one", &claims("alice", 300, json!({"roles": ["desk"]}))));
+        for authorization in [None, Some("Bearer not-a-jwt"), Some(forged.as_str())] {
+            let status = service
+                .do_get_tables(CommandGetTables::default(), request(Ticket::default(), authorization))
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(status.code(), Code::Unauthenticated, "{:?}", authorization);
+        }
+        assert!(service
+            .do_get_tables(CommandGetTables::default(), request(Ticket::default(), Some(&bearer(&idp))))
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn lists_only_readable_tables() {
+        let idp = MockIdp::start("one").await;
+        let service = service(&idp).await;
+        let response = service
+            .do_get_tables(CommandGetTables::default(), request(Ticket::default(), Some(&bearer(&idp))))
+            .await
+            .unwrap();
+        assert_eq!(strings(&batches(response).await, "table_name"), vec!["trades"]);
+
+        let no_roles = format!("Bearer {}", idp.sign("one", &claims("bob", 300, json!({}))));
+        let response = service
+            .do_get_tables(CommandGetTables::default(), request(Ticket::default(), Some(&no_roles)))
+            .await
+            .unwrap();
+        assert!(strings(&batches(response).await, "table_name").is_empty());
+    }
+
+    #[actix_web::test]
+    async fn statements_round_trip_through_their_ticket_as_the_caller_may_see_them() {
+        let idp = MockIdp::start("one").await;
+        let service = service(&idp).await;
+        let authorization = bearer(&idp);
+        let query = CommandStatementQuery {
+            query: "SELECT desk, price FROM trades ORDER BY price".to_string(),
+            transaction_id: None,
+        };
+        let descriptor = FlightDescriptor::new_cmd(query.as_any().encode_to_vec());
+        let info = service
+            .get_flight_info_statement(query, request(descriptor, Some(&authorization)))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let ticket = info.endpoint[0].ticket.clone().unwrap();
+        let statement = Any::decode(ticket.ticket.clone())
+            .unwrap()
+            .unpack::<TicketStatementQuery>()
+            .unwrap()
+            .unwrap();
+        let response = service
+            .do_get_statement(statement.clone(), request(ticket.clone(), Some(&authorization)))
+            .await
+            .unwrap();
+        let batches = batches(response).await;
+        assert_eq!(strings(&batches, "desk"), vec!["fx"]);
+        let prices = batches[0].column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(prices.value(0), 1.5);
+
+        // The ticket is no use without the caller's token.
+        let status = service.do_get_statement(statement, request(ticket, None)).await.err().unwrap();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        for (sql, code) in [
+            ("SELECT * FROM quotes", Code::PermissionDenied),
+            ("SELECT * FROM main.trades", Code::PermissionDenied),
+            ("DELETE FROM trades", Code::InvalidArgument),
+        ] {
+            let query = CommandStatementQuery { query: sql.to_string(), transaction_id: None };
+            let descriptor = FlightDescriptor::new_cmd(query.as_any().encode_to_vec());
+            let status = service
+                .get_flight_info_statement(query, request(descriptor, Some(&authorization)))
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(status.code(), code, "{}", sql);
+        }
+    }
+}
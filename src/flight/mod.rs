@@ -0,0 +1 @@
+pub mod flight_sql;
\ No newline at end of file
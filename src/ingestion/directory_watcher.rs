@@ -1,19 +1,37 @@
-use anyhow::Result;
-use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use r2d2::Pool;
-use std::path::Path;
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::task;
+use tokio::time::{self, Instant};
 use crate::config::config::{DatasetConfig, FileFormat};
-use crate::db::db_pool::DuckDBConnectionManager;
-use crate::ingestion::handlers::{ingest_csv, ingest_json, ingest_parquet};
+use crate::ingestion::handlers::{backfill_dataset, file_pattern, ingest_dataset_files};
+use crate::ingestion::writer::Writer;
 
+/// A file that has seen events but has not yet been quiet for long enough.
+struct PendingFile {
+    last_event: Instant,
+    last_size: Option<u64>,
+}
+
+/// Watches a file-based dataset's directory and ingests new or changed files
+/// through the [`Writer`].
 pub async fn directory_watcher(
     watch_path: &str,
-    pool: Pool<DuckDBConnectionManager>,
+    writer: Writer,
     dataset: DatasetConfig,
 ) -> Result<()> {
+    if matches!(dataset.format, FileFormat::Kafka) {
+        anyhow::bail!(
+            "Dataset {} is a Kafka dataset and cannot be watched",
+            dataset.name
+        );
+    }
+
     // Create an asynchronous channel to receive events.
     let (tx, mut rx) = mpsc::channel::<Event>(100);
 
@@ -41,55 +59,229 @@ pub async fn directory_watcher(
 
     println!("Started watching directory: {}", watch_path);
 
-    // Process file system events as they come in.
-    while let Some(event) = rx.recv().await {
-        println!("Received event in {}: {:?}", dataset.name, event);
-
-        // For each event, spawn a blocking task that performs ingestion.
-        // Clone the pool and dataset config so the task can run independently.
-        let pool_clone = pool.clone();
+    // Backfill whatever is already on disk before handling any events. The
+    // watch is registered first so files dropped in meanwhile are not missed;
+    // the manifest makes their events harmless if backfill already took them.
+    let backfill = {
         let dataset_clone = dataset.clone();
+        writer
+            .write_exclusive(dataset.table_name(), move |conn| backfill_dataset(conn, &dataset_clone))
+            .await
+    };
+    match backfill {
+        Ok(changed) => {
+            println!("Backfilled dataset {} ({} file(s) changed)", dataset.name, changed);
+        }
+        Err(e) => eprintln!("Error backfilling dataset {}: {:?}", dataset.name, e),
+    }
 
-        task::spawn_blocking(move || {
-            // Get a connection from the pool.
-            let conn = match pool_clone.get() {
-                Ok(conn) => conn,
-                Err(e) => {
-                    eprintln!("Error getting connection from pool: {:?}", e);
-                    return;
-                }
-            };
-
-            // Based on the dataset's file format, trigger the appropriate ingestion.
-            // (Assume these functions—ingest_csv, ingest_parquet, ingest_json—are defined in your ingest module.)
-            match dataset_clone.format {
-                FileFormat::Csv => {
-                    if let Err(e) = ingest_csv(&conn, &dataset_clone) {
-                        eprintln!("Error ingesting CSV for {}: {:?}", dataset_clone.name, e);
-                    } else {
-                        println!("Successfully ingested CSV dataset {}", dataset_clone.name);
-                    }
+    // Event paths are matched relative to the canonical watch directory.
+    let watch_dir = fs::canonicalize(watch_path)
+        .with_context(|| format!("Cannot resolve directory {}", watch_path))?;
+    let pattern = Pattern::new(file_pattern(&dataset))
+        .with_context(|| format!("Invalid pattern for dataset {}", dataset.name))?;
+
+    let quiet_period = Duration::from_millis(dataset.debounce_ms);
+    let mut ticker = time::interval((quiet_period / 2).max(Duration::from_millis(50)));
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let Some(event) = event else { break };
+                if !is_content_event(&event.kind) {
+                    continue;
                 }
-                FileFormat::Parquet => {
-                    if let Err(e) = ingest_parquet(&conn, &dataset_clone) {
-                        eprintln!("Error ingesting Parquet for {}: {:?}", dataset_clone.name, e);
-                    } else {
-                        println!("Successfully ingested Parquet dataset {}", dataset_clone.name);
+
+                for path in event.paths {
+                    let Ok(canonical) = fs::canonicalize(&path) else {
+                        // Already gone (e.g. the source side of a rename).
+                        continue;
+                    };
+                    if !matches_pattern(&watch_dir, &pattern, &canonical) {
+                        continue;
                     }
+
+                    let last_size = fs::metadata(&canonical).ok().map(|m| m.len());
+                    pending.insert(canonical, PendingFile { last_event: Instant::now(), last_size });
                 }
-                FileFormat::Json => {
-                    if let Err(e) = ingest_json(&conn, &dataset_clone) {
-                        eprintln!("Error ingesting JSON for {}: {:?}", dataset_clone.name, e);
-                    } else {
-                        println!("Successfully ingested JSON dataset {}", dataset_clone.name);
-                    }
+            }
+            _ = ticker.tick() => {
+                let settled = take_settled_files(&mut pending, quiet_period);
+                if settled.is_empty() {
+                    continue;
                 }
-                FileFormat::Kafka => {
-                    todo!("Implement Kafka ingestion here");
+
+                println!(
+                    "Ingesting {} settled file(s) for {}",
+                    settled.len(),
+                    dataset.name
+                );
+
+                // Wait for the writer, so events keep piling up in
+                // `pending` meanwhile instead of queueing ingests.
+                let dataset_clone = dataset.clone();
+                let result = writer
+                    .write_exclusive(dataset.table_name(), move |conn| {
+                        ingest_dataset_files(conn, &dataset_clone, Some(&settled))
+                    })
+                    .await;
+
+                match result {
+                    Ok(_) => println!("Successfully ingested dataset {}", dataset.name),
+                    Err(e) => eprintln!("Error ingesting dataset {}: {:?}", dataset.name, e),
                 }
             }
-        });
+        }
     }
 
     Ok(())
 }
+
+/// Only creations, data writes and renames can change what a file contains;
+/// access and metadata-only events are ignored.
+fn is_content_event(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Modify(ModifyKind::Any)
+    )
+}
+
+/// Matches a canonical path against the dataset pattern, relative to the
+/// watched directory. Like DuckDB's glob, `*` does not cross directories.
+fn matches_pattern(watch_dir: &Path, pattern: &Pattern, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(watch_dir) else {
+        return false;
+    };
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::default()
+    };
+    pattern.matches_path_with(relative, options)
+}
+
+/// Removes and returns files that have been quiet for the whole period and
+/// whose size has not changed since their last event. Files that are still
+/// growing get their quiet period restarted.
+fn take_settled_files(
+    pending: &mut HashMap<PathBuf, PendingFile>,
+    quiet_period: Duration,
+) -> HashSet<PathBuf> {
+    let now = Instant::now();
+    let mut settled = HashSet::new();
+
+    pending.retain(|path, file| {
+        if now.duration_since(file.last_event) < quiet_period {
+            return true;
+        }
+
+        let Ok(metadata) = fs::metadata(path) else {
+            // Deleted before it settled; nothing to ingest.
+            return false;
+        };
+        if file.last_size != Some(metadata.len()) {
+            file.last_size = Some(metadata.len());
+            file.last_event = now;
+            return true;
+        }
+
+        settled.insert(path.clone());
+        false
+    });
+
+    settled
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind, RenameMode};
+    use tempfile::TempDir;
+    use super::*;
+
+    const QUIET: Duration = Duration::from_millis(500);
+
+    /// A pending file whose last event was `ago`, recorded at `size` bytes.
+    fn pending_since(ago: Duration, size: Option<u64>) -> PendingFile {
+        PendingFile { last_event: Instant::now() - ago, last_size: size }
+    }
+
+    #[test]
+    fn only_content_events_count() {
+        for kind in [
+            EventKind::Create(CreateKind::File),
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            EventKind::Modify(ModifyKind::Any),
+        ] {
+            assert!(is_content_event(&kind), "{:?}", kind);
+        }
+        for kind in [
+            EventKind::Access(AccessKind::Any),
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+            EventKind::Remove(RemoveKind::File),
+            EventKind::Any,
+        ] {
+            assert!(!is_content_event(&kind), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn patterns_match_relative_to_the_watched_directory() {
+        let dir = Path::new("/data/trades");
+        let csv = Pattern::new("*.csv").unwrap();
+        assert!(matches_pattern(dir, &csv, Path::new("/data/trades/a.csv")));
+        assert!(!matches_pattern(dir, &csv, Path::new("/data/trades/a.json")));
+        // `*` does not cross directories, like DuckDB's glob.
+        assert!(!matches_pattern(dir, &csv, Path::new("/data/trades/2024/a.csv")));
+        assert!(!matches_pattern(dir, &csv, Path::new("/data/other/a.csv")));
+
+        let nested = Pattern::new("*/*.csv").unwrap();
+        assert!(matches_pattern(dir, &nested, Path::new("/data/trades/2024/a.csv")));
+        assert!(!matches_pattern(dir, &nested, Path::new("/data/trades/a.csv")));
+    }
+
+    #[test]
+    fn files_settle_once_quiet_for_the_whole_period() {
+        let dir = TempDir::new().unwrap();
+        let (quiet, busy) = (dir.path().join("quiet.csv"), dir.path().join("busy.csv"));
+        fs::write(&quiet, "a,b\n").unwrap();
+        fs::write(&busy, "a,b\n").unwrap();
+        let mut pending = HashMap::from([
+            (quiet.clone(), pending_since(QUIET * 2, Some(4))),
+            (busy.clone(), pending_since(QUIET / 10, Some(4))),
+        ]);
+
+        assert_eq!(take_settled_files(&mut pending, QUIET), HashSet::from([quiet]));
+        assert_eq!(pending.keys().collect::<Vec<_>>(), [&busy]);
+    }
+
+    #[test]
+    fn a_file_still_growing_restarts_its_quiet_period() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("growing.csv");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+        let mut pending = HashMap::from([(path.clone(), pending_since(QUIET * 2, Some(4)))]);
+
+        assert!(take_settled_files(&mut pending, QUIET).is_empty());
+        let file = &pending[&path];
+        assert_eq!(file.last_size, Some(8));
+        assert!(file.last_event.elapsed() < QUIET);
+
+        // Once it has stopped growing for a whole period, it settles.
+        pending.get_mut(&path).unwrap().last_event = Instant::now() - QUIET * 2;
+        assert_eq!(take_settled_files(&mut pending, QUIET), HashSet::from([path]));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn files_deleted_before_settling_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut pending = HashMap::from([(dir.path().join("gone.csv"), pending_since(QUIET * 2, Some(4)))]);
+
+        assert!(take_settled_files(&mut pending, QUIET).is_empty());
+        assert!(pending.is_empty());
+    }
+}
@@ -1,55 +1,548 @@
 use crate::config::config::{DatasetConfig, FileFormat};
-use anyhow::Result;
-use duckdb::Connection;
+use crate::ingestion::{INSERT_TIMESTAMP_COLUMN, SOURCE_COLUMN};
+use crate::query::sql::{quote_identifier, quote_literal};
+use anyhow::{Context, Result};
+use duckdb::{params, Connection, OptionalExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
-pub fn ingest_dataset(conn: &Connection, dataset: &DatasetConfig) -> Result<()> {
+pub fn ingest_dataset(conn: &mut Connection, dataset: &DatasetConfig) -> Result<usize> {
+    ingest_dataset_files(conn, dataset, None)
+}
+
+/// Brings a file-based dataset in line with its directory on startup.
+///
+/// Rows from files that were deleted while the server was down are removed,
+/// then every new or changed file is ingested. Files already recorded in the
+/// manifest with the same size, mtime and checksum are left alone.
+/// Returns the number of files whose rows were removed or written.
+pub fn backfill_dataset(conn: &mut Connection, dataset: &DatasetConfig) -> Result<usize> {
+    let removed = remove_deleted_files(conn, dataset)?;
+    Ok(removed + ingest_dataset(conn, dataset)?)
+}
+
+/// Ingests the dataset's new or changed files. When `only` is given, files
+/// matching the pattern are further restricted to those (canonical) paths.
+/// Returns the number of files whose rows were written.
+pub fn ingest_dataset_files(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<usize> {
     match dataset.format {
-        FileFormat::Csv => ingest_csv(conn, dataset),
-        FileFormat::Parquet => ingest_parquet(conn, dataset),
-        FileFormat::Json => ingest_json(conn, dataset), // Add this line
-        FileFormat::Kafka => todo!("Implement Kafka ingestion here"),
+        FileFormat::Csv => ingest_csv(conn, dataset, only),
+        FileFormat::Parquet => ingest_parquet(conn, dataset, only),
+        FileFormat::Json => ingest_json(conn, dataset, only), // Add this line
+        FileFormat::Kafka => anyhow::bail!(
+            "Dataset {} is a Kafka dataset and is ingested by the Kafka consumer",
+            dataset.name
+        ),
     }
 }
 
-pub fn ingest_csv(conn: &Connection, dataset: &DatasetConfig) -> Result<()> {
-    let directory = dataset.directory.as_deref().unwrap_or(".");
-    let pattern = dataset.pattern.as_deref().unwrap_or("*.csv");
-    let sql = format!(
-        r#"
-        CREATE TABLE IF NOT EXISTS "{table_name}" AS
-        SELECT * FROM read_csv_auto('{directory}/{pattern}');
-        "#,
-        table_name = dataset.name,
-    );
-
-    conn.execute(&sql, [])?;
-    Ok(())
+pub fn ingest_csv(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<usize> {
+    ingest_files(conn, dataset, only, |path| {
+        format!("read_csv_auto({})", quote_literal(path))
+    })
 }
 
-pub fn ingest_parquet(conn: &Connection, dataset: &DatasetConfig) -> Result<()> {
+pub fn ingest_parquet(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<usize> {
+    ingest_files(conn, dataset, only, |path| {
+        format!("parquet_scan({})", quote_literal(path))
+    })
+}
+
+pub fn ingest_json(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<usize> {
+    conn.execute("INSTALL httpfs; LOAD httpfs;", [])?;
+    ingest_files(conn, dataset, only, |path| {
+        format!("read_ndjson_auto({})", quote_literal(path))
+    })
+}
+
+/// The dataset's file pattern, falling back to the format's default extension.
+pub fn file_pattern(dataset: &DatasetConfig) -> &str {
+    dataset
+        .pattern
+        .as_deref()
+        .unwrap_or(match dataset.format {
+            FileFormat::Csv => "*.csv",
+            FileFormat::Parquet => "*.parquet",
+            FileFormat::Json => "*.json",
+            FileFormat::Kafka => "*",
+        })
+}
+
+/// Appends every new or changed file matching the dataset's pattern to its table.
+///
+/// Each file is tracked in `hydrocube_file_manifest` by path, size, mtime and
+/// SHA-256 checksum. Unchanged files are skipped, new files are appended, and
+/// a changed file has its previous rows replaced. Every file is handled in its
+/// own transaction together with its manifest entry.
+///
+/// Every row is stamped with its source file and an insert timestamp.
+fn ingest_files(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    only: Option<&HashSet<PathBuf>>,
+    reader: impl Fn(&str) -> String,
+) -> Result<usize> {
     let directory = dataset.directory.as_deref().unwrap_or(".");
-    let pattern = dataset.pattern.as_deref().unwrap_or("*.parquet");
-    let sql = format!(
-        r#"
-        CREATE TABLE IF NOT EXISTS "{table_name}" AS
-        SELECT * FROM parquet_scan('{directory}/{pattern}');
-        "#,
-        table_name = dataset.name,
-    );
-
-    conn.execute(&sql, [])?;
-    Ok(())
+    let pattern = file_pattern(dataset);
+
+    create_manifest_table_if_not_exists(conn)?;
+    if table_exists(conn, &dataset.name)? {
+        // Tables created by older versions may lack the lineage columns.
+        conn.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {source} VARCHAR;
+             ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {ts} TIMESTAMPTZ;",
+            table = quote_identifier(&dataset.name),
+            source = SOURCE_COLUMN,
+            ts = INSERT_TIMESTAMP_COLUMN,
+        ))?;
+    }
+
+    let files = list_files(conn, &format!("{}/{}", directory, pattern))?;
+    let mut ingested = 0;
+    let mut failures = 0;
+    for path in files {
+        if let Some(only) = only {
+            match fs::canonicalize(&path) {
+                Ok(canonical) if only.contains(&canonical) => {}
+                _ => continue,
+            }
+        }
+
+        match ingest_file(conn, dataset, &path, &reader) {
+            Ok(true) => {
+                println!("Ingested {} into {}", path, dataset.name);
+                ingested += 1;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Error ingesting {} into {}: {:?}", path, dataset.name, e);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{} file(s) failed to ingest into {}", failures, dataset.name);
+    }
+    Ok(ingested)
+}
+
+/// Ingests a single file if the manifest says it is new or has changed.
+/// Returns whether any rows were written.
+fn ingest_file(
+    conn: &mut Connection,
+    dataset: &DatasetConfig,
+    path: &str,
+    reader: &impl Fn(&str) -> String,
+) -> Result<bool> {
+    let metadata = fs::metadata(path).with_context(|| format!("Cannot stat {}", path))?;
+    let size = metadata.len() as i64;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+
+    let previous: Option<(i64, i64, String)> = conn
+        .query_row(
+            "SELECT size, mtime, checksum FROM hydrocube_file_manifest
+             WHERE dataset = ? AND path = ?",
+            params![dataset.name, path],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    if let Some((prev_size, prev_mtime, _)) = &previous {
+        if *prev_size == size && *prev_mtime == mtime {
+            return Ok(false);
+        }
+    }
+
+    let checksum = file_checksum(path)?;
+    let content_changed = previous
+        .as_ref()
+        .is_none_or(|(_, _, prev_checksum)| *prev_checksum != checksum);
+
+    let tx = conn.transaction()?;
+    if content_changed {
+        // Every row of the file shares the transaction's timestamp.
+        let select = format!(
+            "SELECT *, {} AS {}, current_timestamp AS {} FROM {}",
+            quote_literal(path),
+            SOURCE_COLUMN,
+            INSERT_TIMESTAMP_COLUMN,
+            reader(path)
+        );
+        if table_exists(&tx, &dataset.name)? {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE {} = ?", quote_identifier(&dataset.name), SOURCE_COLUMN),
+                params![path],
+            )?;
+            tx.execute(
+                &format!("INSERT INTO {} BY NAME {}", quote_identifier(&dataset.name), select),
+                [],
+            )?;
+        } else {
+            tx.execute(
+                &format!("CREATE TABLE {} AS {}", quote_identifier(&dataset.name), select),
+                [],
+            )?;
+        }
+    }
+    tx.execute(
+        "INSERT INTO hydrocube_file_manifest (dataset, path, size, mtime, checksum, ingested_at)
+         VALUES (?, ?, ?, ?, ?, current_timestamp)
+         ON CONFLICT (dataset, path) DO UPDATE SET
+            size = excluded.size,
+            mtime = excluded.mtime,
+            checksum = excluded.checksum,
+            ingested_at = excluded.ingested_at",
+        params![dataset.name, path, size, mtime, checksum],
+    )?;
+    tx.commit()?;
+
+    Ok(content_changed)
 }
 
-pub fn ingest_json(conn: &Connection, dataset: &DatasetConfig) -> Result<()> {
+/// Drops the rows and manifest entries of files that no longer match the
+/// dataset's pattern on disk. Returns how many files were removed.
+fn remove_deleted_files(conn: &mut Connection, dataset: &DatasetConfig) -> Result<usize> {
+    create_manifest_table_if_not_exists(conn)?;
+
     let directory = dataset.directory.as_deref().unwrap_or(".");
-    let pattern = dataset.pattern.as_deref().unwrap_or("*.json");
-    conn.execute("INSTALL httpfs; LOAD httpfs;", [])?;
-    let sql = format!(
-        "CREATE TABLE \"{table_name}\" AS
-         SELECT * FROM read_ndjson_auto('{directory}/{pattern}')",
-        table_name = dataset.name,
-    );
-    conn.execute(&sql, [])?;
+    let glob = format!("{}/{}", directory, file_pattern(dataset));
+    let on_disk: HashSet<String> = list_files(conn, &glob)?.into_iter().collect();
+
+    let mut stmt = conn.prepare("SELECT path FROM hydrocube_file_manifest WHERE dataset = ?")?;
+    let deleted = stmt
+        .query_map(params![dataset.name], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| !on_disk.contains(path))
+        .collect::<Vec<_>>();
+    drop(stmt);
+
+    if deleted.is_empty() {
+        return Ok(0);
+    }
+
+    let has_table = table_exists(conn, &dataset.name)?;
+    let tx = conn.transaction()?;
+    for path in &deleted {
+        if has_table {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE {} = ?", quote_identifier(&dataset.name), SOURCE_COLUMN),
+                params![path],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM hydrocube_file_manifest WHERE dataset = ? AND path = ?",
+            params![dataset.name, path],
+        )?;
+        println!("Removed rows for deleted file {} from {}", path, dataset.name);
+    }
+    tx.commit()?;
+    Ok(deleted.len())
+}
+
+fn create_manifest_table_if_not_exists(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS hydrocube_file_manifest (
+            dataset VARCHAR NOT NULL,
+            path VARCHAR NOT NULL,
+            size BIGINT NOT NULL,
+            mtime BIGINT NOT NULL,
+            checksum VARCHAR NOT NULL,
+            ingested_at TIMESTAMP NOT NULL,
+            PRIMARY KEY (dataset, path)
+        );",
+        [],
+    )?;
     Ok(())
 }
+
+fn table_exists(conn: &Connection, table_name: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) FROM information_schema.tables
+         WHERE table_schema = 'main' AND table_name = ?",
+        params![table_name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Expands a glob with DuckDB's own matcher, so the files we track are exactly
+/// the files the readers would see.
+fn list_files(conn: &Connection, glob: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT file FROM glob({}) ORDER BY file",
+        quote_literal(glob)
+    ))?;
+    let files = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(files)
+}
+
+fn file_checksum(path: &str) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Cannot open {}", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+    use super::*;
+
+    fn dataset(directory: &Path) -> DatasetConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: trades, format: csv, directory: {:?}}}",
+            directory.to_str().unwrap()
+        ))
+        .unwrap()
+    }
+
+    fn write(path: &Path, rows: &[(&str, i32)]) {
+        let mut csv = "sym,qty\n".to_string();
+        for (sym, qty) in rows {
+            csv.push_str(&format!("{},{}\n", sym, qty));
+        }
+        fs::write(path, csv).unwrap();
+    }
+
+    /// `(sym, qty, source)` of every row, ordered by symbol.
+    fn rows(conn: &Connection) -> Vec<(String, i32, String)> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT sym, qty, {} FROM trades ORDER BY sym", SOURCE_COLUMN))
+            .unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    fn manifest_mtime(conn: &Connection, path: &str) -> Option<i64> {
+        conn.query_row("SELECT mtime FROM hydrocube_file_manifest WHERE path = ?", [path], |row| row.get(0))
+            .optional()
+            .unwrap()
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        dataset: DatasetConfig,
+        a: PathBuf,
+        b: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let dataset = dataset(dir.path());
+            let (a, b) = (dir.path().join("a.csv"), dir.path().join("b.csv"));
+            Fixture { dir, dataset, a, b }
+        }
+
+        /// A path as the manifest records it.
+        fn key(path: &Path) -> String {
+            path.to_str().unwrap().to_string()
+        }
+    }
+
+    #[test]
+    fn ingests_new_files_and_skips_unchanged_ones() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1), ("B", 2)]);
+        write(&fixture.b, &[("C", 3)]);
+
+        assert_eq!(ingest_dataset(&mut conn, &fixture.dataset).unwrap(), 2);
+        let (a, b) = (Fixture::key(&fixture.a), Fixture::key(&fixture.b));
+        assert_eq!(
+            rows(&conn),
+            vec![("A".into(), 1, a.clone()), ("B".into(), 2, a.clone()), ("C".into(), 3, b.clone())]
+        );
+
+        assert_eq!(ingest_dataset(&mut conn, &fixture.dataset).unwrap(), 0);
+        assert_eq!(rows(&conn).len(), 3);
+    }
+
+    #[test]
+    fn replaces_the_rows_of_a_changed_file() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1), ("B", 2)]);
+        write(&fixture.b, &[("C", 3)]);
+        ingest_dataset(&mut conn, &fixture.dataset).unwrap();
+
+        write(&fixture.a, &[("A", 10)]);
+        assert_eq!(ingest_dataset(&mut conn, &fixture.dataset).unwrap(), 1);
+        let (a, b) = (Fixture::key(&fixture.a), Fixture::key(&fixture.b));
+        assert_eq!(rows(&conn), vec![("A".into(), 10, a), ("C".into(), 3, b)]);
+    }
+
+    #[test]
+    fn a_touched_file_with_the_same_content_is_not_reingested() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1)]);
+        ingest_dataset(&mut conn, &fixture.dataset).unwrap();
+        let a = Fixture::key(&fixture.a);
+        let stamped = |conn: &Connection| -> String {
+            conn.query_row(&format!("SELECT CAST({} AS VARCHAR) FROM trades", INSERT_TIMESTAMP_COLUMN), [], |row| row.get(0))
+                .unwrap()
+        };
+        let before = stamped(&conn);
+
+        let touched = SystemTime::now() + Duration::from_secs(60);
+        set_mtime(&fixture.a, touched);
+        assert_eq!(ingest_dataset(&mut conn, &fixture.dataset).unwrap(), 0);
+        assert_eq!(rows(&conn), vec![("A".into(), 1, a.clone())]);
+        assert_eq!(stamped(&conn), before);
+
+        // The new mtime is recorded, so the next pass doesn't even checksum it.
+        let millis = touched.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert_eq!(manifest_mtime(&conn, &a), Some(millis));
+    }
+
+    #[test]
+    fn removes_the_rows_of_deleted_files() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1)]);
+        write(&fixture.b, &[("B", 2)]);
+        ingest_dataset(&mut conn, &fixture.dataset).unwrap();
+
+        fs::remove_file(&fixture.b).unwrap();
+        assert_eq!(remove_deleted_files(&mut conn, &fixture.dataset).unwrap(), 1);
+        assert_eq!(rows(&conn), vec![("A".into(), 1, Fixture::key(&fixture.a))]);
+        assert_eq!(manifest_mtime(&conn, &Fixture::key(&fixture.b)), None);
+        assert_eq!(remove_deleted_files(&mut conn, &fixture.dataset).unwrap(), 0);
+    }
+
+    #[test]
+    fn backfill_after_a_restart_only_reconciles_changed_and_deleted_files() {
+        let fixture = Fixture::new();
+        let c = fixture.a.with_file_name("c.csv");
+        let db = fixture.dir.path().join("hydrocube.db");
+        write(&fixture.a, &[("A", 1)]);
+        write(&fixture.b, &[("B", 2)]);
+        write(&c, &[("C", 3)]);
+        let stamp_of = |conn: &Connection, sym: &str| -> String {
+            conn.query_row(
+                &format!("SELECT CAST({} AS VARCHAR) FROM trades WHERE sym = ?", INSERT_TIMESTAMP_COLUMN),
+                [sym],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        let before = {
+            let mut conn = Connection::open(&db).unwrap();
+            assert_eq!(backfill_dataset(&mut conn, &fixture.dataset).unwrap(), 3);
+            stamp_of(&conn, "A")
+        };
+
+        // While the server is down, b changes and c is deleted.
+        write(&fixture.b, &[("B", 20), ("D", 4)]);
+        fs::remove_file(&c).unwrap();
+
+        let mut conn = Connection::open(&db).unwrap();
+        assert_eq!(backfill_dataset(&mut conn, &fixture.dataset).unwrap(), 2);
+        let (a, b) = (Fixture::key(&fixture.a), Fixture::key(&fixture.b));
+        assert_eq!(
+            rows(&conn),
+            vec![("A".into(), 1, a), ("B".into(), 20, b.clone()), ("D".into(), 4, b)]
+        );
+        assert_eq!(stamp_of(&conn, "A"), before);
+        assert_eq!(manifest_mtime(&conn, &Fixture::key(&c)), None);
+
+        assert_eq!(backfill_dataset(&mut conn, &fixture.dataset).unwrap(), 0);
+    }
+
+    #[test]
+    fn stamps_every_row_with_its_source_and_insert_time() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1), ("B", 2)]);
+        ingest_dataset(&mut conn, &fixture.dataset).unwrap();
+
+        let (from_a, stamped): (i64, i64) = conn
+            .query_row(
+                &format!(
+                    "SELECT count(*) FILTER (WHERE {source} = ?),
+                            count(*) FILTER (WHERE {ts} <= now())
+                     FROM trades",
+                    source = SOURCE_COLUMN,
+                    ts = INSERT_TIMESTAMP_COLUMN,
+                ),
+                [Fixture::key(&fixture.a)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((from_a, stamped), (2, 2));
+    }
+
+    #[test]
+    fn adds_the_lineage_columns_to_tables_from_older_versions() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE trades (sym VARCHAR, qty INTEGER); INSERT INTO trades VALUES ('Z', 9);")
+            .unwrap();
+        write(&fixture.a, &[("A", 1)]);
+
+        assert_eq!(ingest_dataset(&mut conn, &fixture.dataset).unwrap(), 1);
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT sym, {}, {} IS NOT NULL FROM trades ORDER BY sym",
+                SOURCE_COLUMN, INSERT_TIMESTAMP_COLUMN
+            ))
+            .unwrap();
+        let rows: Vec<(String, Option<String>, bool)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![("A".into(), Some(Fixture::key(&fixture.a)), true), ("Z".into(), None, false)]
+        );
+    }
+
+    #[test]
+    fn only_restricts_ingest_to_the_given_files() {
+        let fixture = Fixture::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        write(&fixture.a, &[("A", 1)]);
+        write(&fixture.b, &[("B", 2)]);
+        let only = HashSet::from([fs::canonicalize(&fixture.b).unwrap()]);
+
+        assert_eq!(ingest_dataset_files(&mut conn, &fixture.dataset, Some(&only)).unwrap(), 1);
+        assert_eq!(rows(&conn), vec![("B".into(), 2, Fixture::key(&fixture.b))]);
+    }
+}
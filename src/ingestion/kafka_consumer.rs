@@ -0,0 +1,526 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use duckdb::Connection;
+use r2d2::Pool;
+use rdkafka::client::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{
+    BaseConsumer, Consumer, ConsumerContext, RebalanceProtocol, StreamConsumer,
+};
+use rdkafka::types::RDKafkaRespErr;
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde_json::Value as JsonValue;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use crate::config::config::{DatasetConfig, KafkaTopicConfig};
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::ingestion::kafka_utils::{
+    create_dead_letters_table_if_not_exists, create_offsets_table_if_not_exists,
+    create_table_if_not_exists, insert_dead_letter, insert_message, load_offsets, message_fits,
+};
+use crate::ingestion::writer::{PendingWrite, Writer};
+
+/// Messages read but not yet known to be stored.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// How long to wait before trying again to load the stored offsets for a
+/// new assignment.
+const OFFSETS_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Consumes the dataset's Kafka topic and inserts every JSON message as a row
+/// in the topic's table, creating the table from the configured schema first.
+///
+/// The consumer subscribes as a member of `group_id`, so instances sharing a
+/// group split the partitions between them. Offsets are stored in DuckDB
+/// alongside the rows, and partitions are positioned at them whenever
+/// they're assigned; see [`StoredOffsets`]. Each row is therefore ingested
+/// exactly once, even if the process dies mid-message.
+///
+/// Malformed messages, ones that aren't valid JSON or hold a value that
+/// can't be cast to its column's type, are recorded in
+/// `hydrocube_kafka_dead_letters` in the row's place, so the consumer moves
+/// past them without losing them. A well-formed message whose row still
+/// can't be stored is a problem with the database, not the message: the
+/// consumer stops, and neither its offset nor any later one is committed, so
+/// it's read again on restart.
+///
+/// Rows go through the [`Writer`], in offset order. The consumer keeps
+/// reading while earlier rows wait to commit, so a burst of messages is
+/// committed in a few transactions rather than one per message.
+pub async fn kafka_consumer(
+    pool: Pool<DuckDBConnectionManager>,
+    writer: Writer,
+    dataset: DatasetConfig,
+) -> Result<()> {
+    let topic_config = Arc::new(
+        dataset
+            .kafka
+            .clone()
+            .with_context(|| format!("Dataset {} has no kafka section", dataset.name))?,
+    );
+
+    {
+        let topic_config = topic_config.clone();
+        writer
+            .write_exclusive(&topic_config.table_name.clone(), move |conn| {
+                create_table_if_not_exists(conn, &topic_config)?;
+                create_offsets_table_if_not_exists(conn)?;
+                create_dead_letters_table_if_not_exists(conn)?;
+                Ok(0)
+            })
+            .await?;
+    }
+
+    let context = StoredOffsets {
+        runtime: Handle::current(),
+        pool,
+        writer: writer.clone(),
+        topic_config: topic_config.clone(),
+    };
+    // Offsets are never committed to the group: DuckDB is the only place
+    // they're kept, so there's nothing for the two to disagree about.
+    let consumer: StreamConsumer<StoredOffsets> = ClientConfig::new()
+        .set("bootstrap.servers", &topic_config.brokers)
+        .set("group.id", &topic_config.group_id)
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .create_with_context(context)
+        .with_context(|| format!("Failed to create Kafka consumer for {}", dataset.name))?;
+    consumer
+        .subscribe(&[&topic_config.topic])
+        .with_context(|| format!("Failed to subscribe to Kafka topic {}", topic_config.topic))?;
+
+    println!(
+        "Started consuming Kafka topic {} into table {} (group {})",
+        topic_config.topic, topic_config.table_name, topic_config.group_id
+    );
+
+    // Results are awaited in the order the messages were read, so the
+    // consumer stops at the first message that couldn't be stored.
+    let (in_flight, mut results) = mpsc::channel::<(i32, i64, PendingWrite)>(MAX_IN_FLIGHT);
+    let mut watcher = {
+        let name = dataset.name.clone();
+        tokio::spawn(async move {
+            while let Some((partition, offset, pending)) = results.recv().await {
+                // A failed write has already been dead-lettered; an error
+                // here means it couldn't be, or shouldn't have been.
+                pending.wait().await.with_context(|| {
+                    format!(
+                        "Cannot ingest or dead-letter Kafka message for {} (partition {}, offset {})",
+                        name, partition, offset
+                    )
+                })?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+    };
+
+    // Set once a well-formed message couldn't be stored. Writes queued after
+    // it then fail too, so none of them moves the offset past it.
+    let stalled = Arc::new(AtomicBool::new(false));
+
+    loop {
+        let message = tokio::select! {
+            message = consumer.recv() => message,
+            // Stop reading rather than move past a message that couldn't be stored.
+            result = &mut watcher => {
+                return match result {
+                    Ok(Ok(())) => Err(anyhow!("Kafka write watcher stopped")),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(e.into()),
+                };
+            }
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Kafka error on topic {}: {:?}", topic_config.topic, e);
+                continue;
+            }
+        };
+
+        let Some(payload) = message.payload() else {
+            // Tombstones and empty messages carry nothing to insert.
+            continue;
+        };
+        let partition = message.partition();
+        let offset = message.offset();
+        let parsed = serde_json::from_slice::<JsonValue>(payload).map(Arc::new);
+        let on_failure = dead_letter(
+            topic_config.clone(),
+            partition,
+            offset,
+            payload.to_vec(),
+            parsed.as_ref().ok().cloned(),
+            stalled.clone(),
+        );
+        let pending = match parsed {
+            Ok(row) => {
+                let topic_clone = topic_config.clone();
+                let stalled = stalled.clone();
+                writer
+                    .enqueue(
+                        &topic_config.table_name,
+                        move |conn| {
+                            if stalled.load(Ordering::Relaxed) {
+                                bail!("An earlier message couldn't be stored");
+                            }
+                            insert_message(conn, &topic_clone, partition, offset, &row)?;
+                            Ok(1)
+                        },
+                        on_failure,
+                    )
+                    .await?
+            }
+            // Fails like a row that can't be inserted, and is dead-lettered the same way.
+            Err(e) => {
+                let error = format!("Not valid JSON: {}", e);
+                writer
+                    .enqueue(&topic_config.table_name, move |_| Err(anyhow!(error.clone())), on_failure)
+                    .await?
+            }
+        };
+        in_flight
+            .send((partition, offset, pending))
+            .await
+            .context("Kafka write watcher stopped")?;
+    }
+}
+
+/// What to write in place of a message that couldn't be ingested: a dead
+/// letter holding its payload and the error, if the message is malformed.
+/// `row` is the message parsed as JSON, if it could be.
+///
+/// A well-formed message isn't dead-lettered. It sets `stalled` and fails
+/// instead, and so do the messages after it.
+fn dead_letter(
+    topic_config: Arc<KafkaTopicConfig>,
+    partition: i32,
+    offset: i64,
+    payload: Vec<u8>,
+    row: Option<Arc<JsonValue>>,
+    stalled: Arc<AtomicBool>,
+) -> impl Fn(&Connection, &anyhow::Error) -> Result<usize> + Send + 'static {
+    move |conn, error| {
+        if stalled.load(Ordering::Relaxed) {
+            bail!("An earlier message couldn't be stored");
+        }
+        // If it can't even be checked, it's kept to be read again too.
+        let malformed = match &row {
+            Some(row) => !message_fits(conn, &topic_config, row).unwrap_or(true),
+            None => true,
+        };
+        if !malformed {
+            stalled.store(true, Ordering::Relaxed);
+            bail!("The message is well-formed, so it's left to be read again");
+        }
+        eprintln!(
+            "Dead-lettering Kafka message from {} (partition {}, offset {}): {:#}",
+            topic_config.topic, partition, offset, error
+        );
+        insert_dead_letter(conn, &topic_config, partition, offset, &payload, &format!("{:#}", error))?;
+        Ok(0)
+    }
+}
+
+/// Handles the group's rebalances with the offsets stored in DuckDB.
+///
+/// Assigned partitions start at the next offset stored for them, or at the
+/// beginning if they've never been consumed. Before partitions are given up,
+/// every row already read is committed, so whichever consumer gets them next
+/// finds their offsets up to date.
+struct StoredOffsets {
+    /// The consumer's runtime, to wait on the writer from inside a rebalance.
+    runtime: Handle,
+    pool: Pool<DuckDBConnectionManager>,
+    writer: Writer,
+    topic_config: Arc<KafkaTopicConfig>,
+}
+
+impl ClientContext for StoredOffsets {}
+
+impl ConsumerContext for StoredOffsets {
+    fn rebalance(
+        &self,
+        consumer: &BaseConsumer<Self>,
+        err: RDKafkaRespErr,
+        partitions: &mut TopicPartitionList,
+    ) {
+        let cooperative = matches!(consumer.rebalance_protocol(), RebalanceProtocol::Cooperative);
+        let result = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS => {
+                let assignment = self.positioned(partitions);
+                println!(
+                    "Assigned {} partition(s) of Kafka topic {}",
+                    assignment.count(),
+                    self.topic_config.topic
+                );
+                if cooperative {
+                    consumer.incremental_assign(&assignment)
+                } else {
+                    consumer.assign(&assignment)
+                }
+            }
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => {
+                self.finish_writes();
+                if cooperative {
+                    consumer.incremental_unassign(partitions)
+                } else {
+                    consumer.unassign()
+                }
+            }
+            err => {
+                eprintln!("Kafka rebalance error on topic {}: {:?}", self.topic_config.topic, err);
+                consumer.unassign()
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error applying Kafka rebalance on topic {}: {:?}", self.topic_config.topic, e);
+        }
+    }
+}
+
+impl StoredOffsets {
+    /// `partitions`, each at its stored next offset.
+    ///
+    /// Starting anywhere else would skip or repeat rows, so this keeps trying
+    /// until the offsets can be read.
+    fn positioned(&self, partitions: &TopicPartitionList) -> TopicPartitionList {
+        let stored = loop {
+            let loaded = self
+                .pool
+                .get()
+                .map_err(anyhow::Error::from)
+                .and_then(|conn| load_offsets(&conn, &self.topic_config));
+            match loaded {
+                Ok(stored) => break stored,
+                Err(e) => {
+                    eprintln!("Cannot load Kafka offsets for {}: {:?}", self.topic_config.topic, e);
+                    std::thread::sleep(OFFSETS_RETRY_DELAY);
+                }
+            }
+        };
+
+        let mut assignment = TopicPartitionList::new();
+        for element in partitions.elements() {
+            let offset = match stored.get(&element.partition()) {
+                Some(next) => Offset::Offset(*next),
+                None => Offset::Beginning,
+            };
+            if let Err(e) = assignment.add_partition_offset(element.topic(), element.partition(), offset) {
+                eprintln!("Cannot position Kafka partition {}: {:?}", element.partition(), e);
+            }
+        }
+        assignment
+    }
+
+    /// Waits for every row read so far to commit, and its offset with it.
+    fn finish_writes(&self) {
+        // Rebalances run inside `recv`, on one of the runtime's workers, so
+        // the worker's other tasks are handed off while this one waits. The
+        // writer has its own thread, so waiting for it can't deadlock.
+        let flushed = tokio::task::block_in_place(|| self.runtime.block_on(self.writer.flush()));
+        if let Err(e) = flushed {
+            eprintln!("Cannot finish Kafka writes for {}: {:?}", self.topic_config.topic, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use duckdb::params;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::{DefaultProducerContext, FutureProducer, FutureRecord};
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    const TOPIC: &str = "trades";
+    const GROUP: &str = "hydrocube";
+
+    fn dataset(brokers: String) -> DatasetConfig {
+        serde_yaml::from_str(&format!(
+            "
+name: {TOPIC}
+format: kafka
+kafka:
+  brokers: \"{brokers}\"
+  group_id: {GROUP}
+  topic: {TOPIC}
+  table_name: {TOPIC}
+  schema:
+    - {{column: sym, field_type: VARCHAR, json_path: $.sym}}
+    - {{column: price, field_type: DOUBLE, json_path: $.price}}
+"
+        ))
+        .unwrap()
+    }
+
+    /// A one-broker cluster whose topic holds `payloads`, at offsets 0, 1, ...
+    async fn cluster_with(payloads: &[&str]) -> MockCluster<'static, DefaultProducerContext> {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic(TOPIC, 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+        for payload in payloads {
+            producer
+                .send(FutureRecord::<(), _>::to(TOPIC).payload(*payload), Duration::from_secs(10))
+                .await
+                .unwrap();
+        }
+        cluster
+    }
+
+    /// Starts consuming the cluster's topic into `pool`.
+    fn start(
+        cluster: &MockCluster<'static, DefaultProducerContext>,
+        pool: &Pool<DuckDBConnectionManager>,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let (ingested, _) = mpsc::unbounded_channel();
+        let writer = Writer::start(pool.clone(), ingested);
+        tokio::spawn(kafka_consumer(pool.clone(), writer, dataset(cluster.bootstrap_servers())))
+    }
+
+    /// Consumes the cluster's topic into `pool` until `done` returns true
+    /// for the database, failing after a generous deadline.
+    async fn consume_until(
+        cluster: &MockCluster<'static, DefaultProducerContext>,
+        pool: &Pool<DuckDBConnectionManager>,
+        done: impl Fn(&Connection) -> bool,
+    ) {
+        let consumer = start(cluster, pool);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while !done(&pool.get().unwrap()) {
+            assert!(!consumer.is_finished(), "consumer stopped");
+            assert!(Instant::now() < deadline, "messages weren't consumed in time");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        consumer.abort();
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    fn next_offset(conn: &Connection) -> Option<i64> {
+        conn.query_row(
+            "SELECT next_offset FROM hydrocube_kafka_offsets WHERE group_id = ? AND topic = ? AND partition = 0",
+            params![GROUP, TOPIC],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn dead_letters_malformed_messages_and_carries_on() {
+        let cluster = cluster_with(&[
+            r#"{"sym": "A", "price": 1.5}"#,
+            "not json",
+            r#"{"sym": "B"}"#,
+            r#"{"sym": "C", "price": "high"}"#,
+        ])
+        .await;
+        let pool = memory_pool(4);
+        consume_until(&cluster, &pool, |conn| next_offset(conn) == Some(4)).await;
+
+        let conn = pool.get().unwrap();
+        let mut statement = conn
+            .prepare("SELECT sym, price, source FROM trades ORDER BY source")
+            .unwrap();
+        let rows: Vec<(String, Option<f64>, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("A".to_string(), Some(1.5), "trades/0/0".to_string()),
+                ("B".to_string(), None, "trades/0/2".to_string()),
+            ]
+        );
+
+        let mut statement = conn
+            .prepare("SELECT message_offset, payload, error FROM hydrocube_kafka_dead_letters ORDER BY message_offset")
+            .unwrap();
+        let dead_letters: Vec<(i64, Vec<u8>, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let [(1, not_json, json_error), (3, bad_price, cast_error)] = dead_letters.as_slice() else {
+            panic!("unexpected dead letters: {:?}", dead_letters);
+        };
+        assert_eq!(not_json, b"not json");
+        assert!(json_error.starts_with("Not valid JSON"), "{}", json_error);
+        assert_eq!(bad_price, br#"{"sym": "C", "price": "high"}"#);
+        assert!(cast_error.contains("high"), "{}", cast_error);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stops_without_committing_a_well_formed_message_it_cannot_store() {
+        let cluster = cluster_with(&[
+            r#"{"sym": "A", "price": 1}"#,
+            r#"{"sym": "B", "price": -2}"#,
+            r#"{"sym": "C", "price": 3}"#,
+        ])
+        .await;
+        let pool = memory_pool(4);
+        // A constraint the messages know nothing about stands in for a
+        // database that won't take the row.
+        pool.get()
+            .unwrap()
+            .execute_batch("CREATE TABLE trades (sym VARCHAR, price DOUBLE CHECK (price > 0))")
+            .unwrap();
+
+        let consumer = start(&cluster, &pool);
+        let error = tokio::time::timeout(Duration::from_secs(60), consumer)
+            .await
+            .expect("consumer didn't stop")
+            .unwrap()
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("offset 1"), "{:#}", error);
+
+        // Offset 1 is read again on restart, and C waits behind it.
+        let conn = pool.get().unwrap();
+        assert_eq!(next_offset(&conn), Some(1));
+        assert_eq!(count(&conn, "trades"), 1);
+        assert_eq!(count(&conn, "hydrocube_kafka_dead_letters"), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn resumes_from_the_stored_offset() {
+        let cluster = cluster_with(&[
+            r#"{"sym": "A", "price": 1}"#,
+            r#"{"sym": "B", "price": 2}"#,
+            r#"{"sym": "C", "price": 3}"#,
+        ])
+        .await;
+        let pool = memory_pool(4);
+        {
+            // Offset 0 was ingested before a restart; another group's offset doesn't count.
+            let conn = pool.get().unwrap();
+            create_offsets_table_if_not_exists(&conn).unwrap();
+            conn.execute_batch(
+                "INSERT INTO hydrocube_kafka_offsets VALUES ('hydrocube', 'trades', 0, 1), ('other', 'trades', 0, 3)",
+            )
+            .unwrap();
+        }
+        consume_until(&cluster, &pool, |conn| next_offset(conn) == Some(3)).await;
+
+        let conn = pool.get().unwrap();
+        let mut statement = conn.prepare("SELECT sym FROM trades ORDER BY source").unwrap();
+        let symbols: Vec<String> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(symbols, vec!["B", "C"]);
+        assert_eq!(count(&conn, "hydrocube_kafka_dead_letters"), 0);
+    }
+}
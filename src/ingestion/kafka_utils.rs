@@ -1,28 +1,373 @@
 // ingestion/kafka_utils.rs (for example)
-use r2d2::Pool;
-use crate::db::db_pool::DuckDBConnectionManager;
+use std::collections::HashMap;
+use anyhow::Context;
+use duckdb::types::Value;
+use duckdb::{params, params_from_iter, Connection};
+use serde_json::Value as JsonValue;
+use crate::db::json_value::to_duckdb_value;
 use crate::config::config::{KafkaTopicConfig};
+use crate::ingestion::{INSERT_TIMESTAMP_COLUMN, SOURCE_COLUMN};
+use crate::query::sql::{check_column_type, quote_identifier};
 
-/// Creates the table if it doesn't exist
+/// Creates the table if it doesn't exist. Fails, before touching the
+/// database, if a column's `type` isn't a DuckDB type.
 pub fn create_table_if_not_exists(
-    pool: &Pool<DuckDBConnectionManager>,
+    conn: &Connection,
     topic_config: &KafkaTopicConfig
 ) -> anyhow::Result<()> {
-    let mut conn = pool.get()?;
-
-    // Build a CREATE TABLE statement from the config's schema
+    // Build a CREATE TABLE statement from the config's schema. Types go in
+    // as written, so they have to be checked first.
     let columns_ddl: String = topic_config
         .schema
         .iter()
-        .map(|field| format!("{} {}", field.column, field.field_type))
-        .collect::<Vec<_>>()
+        .map(|field| {
+            check_column_type(&field.field_type)
+                .with_context(|| format!("Column {} of {}", field.column, topic_config.table_name))?;
+            Ok(format!("{} {}", quote_identifier(&field.column), field.field_type))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
         .join(", ");
 
     let create_sql = format!(
         "CREATE TABLE IF NOT EXISTS {} ({});",
-        topic_config.table_name, columns_ddl
+        quote_identifier(&topic_config.table_name),
+        columns_ddl
     );
 
     conn.execute(&create_sql, [])?;
+
+    // Lineage columns, added separately so tables from older versions get them too.
+    conn.execute_batch(&format!(
+        "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {source} VARCHAR;
+         ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {ts} TIMESTAMPTZ;",
+        table = quote_identifier(&topic_config.table_name),
+        source = SOURCE_COLUMN,
+        ts = INSERT_TIMESTAMP_COLUMN,
+    ))?;
+    Ok(())
+}
+
+/// Creates the system table that holds the next offset to consume for every
+/// partition. Offsets are written in the same transaction as the rows they
+/// produced, so the table is the source of truth on restart.
+pub fn create_offsets_table_if_not_exists(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS hydrocube_kafka_offsets (
+            group_id VARCHAR NOT NULL,
+            topic VARCHAR NOT NULL,
+            partition INTEGER NOT NULL,
+            next_offset BIGINT NOT NULL,
+            PRIMARY KEY (group_id, topic, partition)
+        );",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Creates the system table that holds messages that couldn't be ingested,
+/// with the reason, so skipping them loses nothing.
+pub fn create_dead_letters_table_if_not_exists(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS hydrocube_kafka_dead_letters (
+            group_id VARCHAR NOT NULL,
+            topic VARCHAR NOT NULL,
+            partition INTEGER NOT NULL,
+            message_offset BIGINT NOT NULL,
+            payload BLOB,
+            error VARCHAR NOT NULL,
+            failed_at TIMESTAMPTZ NOT NULL
+        );",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Loads the stored next offset for each partition of the topic.
+pub fn load_offsets(
+    conn: &Connection,
+    topic_config: &KafkaTopicConfig,
+) -> anyhow::Result<HashMap<i32, i64>> {
+    let mut stmt = conn.prepare(
+        "SELECT partition, next_offset FROM hydrocube_kafka_offsets
+         WHERE group_id = ? AND topic = ?",
+    )?;
+    let offsets = stmt
+        .query_map(params![topic_config.group_id, topic_config.topic], |row| {
+            Ok((row.get::<_, i32>(0)?, row.get::<_, i64>(1)?))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(offsets)
+}
+
+/// Inserts one row into the topic's table from a message parsed as JSON,
+/// pulling each column out of the message with its `json_path`, and records
+/// the partition's next offset.
+///
+/// Run it inside a transaction, so a crash can never leave the row without
+/// the offset or the other way round. The row is stamped with an insert
+/// timestamp and a `topic/partition/offset` source.
+/// Fields missing from the message are inserted as NULL.
+pub fn insert_message(
+    conn: &Connection,
+    topic_config: &KafkaTopicConfig,
+    partition: i32,
+    offset: i64,
+    message: &JsonValue,
+) -> anyhow::Result<()> {
+    let columns = topic_config
+        .schema
+        .iter()
+        .map(|field| quote_identifier(&field.column))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let placeholders = vec!["?"; topic_config.schema.len()].join(", ");
+    let insert_sql = format!(
+        "INSERT INTO {} ({}, {}, {}) VALUES ({}, ?, current_timestamp)",
+        quote_identifier(&topic_config.table_name),
+        columns,
+        SOURCE_COLUMN,
+        INSERT_TIMESTAMP_COLUMN,
+        placeholders
+    );
+
+    let source = format!("{}/{}/{}", topic_config.topic, partition, offset);
+    let values = message_values(topic_config, message).chain(std::iter::once(Value::Text(source)));
+
+    conn.prepare_cached(&insert_sql)?
+        .execute(params_from_iter(values))?;
+    store_offset(conn, topic_config, partition, offset)
+}
+
+/// Whether every value in the message can be cast to its column's type,
+/// checked without touching any table. A message that can't is malformed;
+/// one that can but still isn't inserted failed for some other reason.
+///
+/// `TRY_CAST` keeps a value that doesn't fit from failing the statement,
+/// and with it the transaction it runs in.
+pub fn message_fits(
+    conn: &Connection,
+    topic_config: &KafkaTopicConfig,
+    message: &JsonValue,
+) -> anyhow::Result<bool> {
+    let checks = topic_config
+        .schema
+        .iter()
+        .map(|field| {
+            check_column_type(&field.field_type)?;
+            Ok(format!("(? IS NULL OR TRY_CAST(? AS {}) IS NOT NULL)", field.field_type))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .join(" AND ");
+    let values = message_values(topic_config, message).flat_map(|value| [value.clone(), value]);
+    let fits = conn
+        .prepare_cached(&format!("SELECT {}", checks))?
+        .query_row(params_from_iter(values), |row| row.get(0))?;
+    Ok(fits)
+}
+
+/// The message's value for each column of the schema, in order.
+fn message_values<'a>(
+    topic_config: &'a KafkaTopicConfig,
+    message: &'a JsonValue,
+) -> impl Iterator<Item = Value> + 'a {
+    topic_config
+        .schema
+        .iter()
+        .map(|field| to_duckdb_value(extract_json_path(message, &field.json_path)))
+}
+
+/// Records a message that couldn't be ingested as a dead letter, with the
+/// reason, and moves the partition's next offset past it, so the consumer
+/// can carry on without losing it.
+pub fn insert_dead_letter(
+    conn: &Connection,
+    topic_config: &KafkaTopicConfig,
+    partition: i32,
+    offset: i64,
+    payload: &[u8],
+    error: &str,
+) -> anyhow::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO hydrocube_kafka_dead_letters
+            (group_id, topic, partition, message_offset, payload, error, failed_at)
+         VALUES (?, ?, ?, ?, ?, ?, current_timestamp)",
+    )?
+    .execute(params![
+        topic_config.group_id,
+        topic_config.topic,
+        partition,
+        offset,
+        payload,
+        error
+    ])?;
+    store_offset(conn, topic_config, partition, offset)
+}
+
+/// Records that the partition is consumed up to and including `offset`.
+fn store_offset(
+    conn: &Connection,
+    topic_config: &KafkaTopicConfig,
+    partition: i32,
+    offset: i64,
+) -> anyhow::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO hydrocube_kafka_offsets (group_id, topic, partition, next_offset)
+         VALUES (?, ?, ?, ?)
+         ON CONFLICT (group_id, topic, partition)
+         DO UPDATE SET next_offset = excluded.next_offset",
+    )?
+    .execute(params![
+        topic_config.group_id,
+        topic_config.topic,
+        partition,
+        offset + 1
+    ])?;
     Ok(())
 }
+
+/// Resolves a simple JSON path against a message.
+///
+/// Supports `$.a.b`, `$.items[0].price`, `$['a']` and bare keys such as `a.b`.
+/// Returns `None` if any step of the path does not exist.
+pub fn extract_json_path<'a>(value: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    let path = path.trim();
+    let path = path.strip_prefix('$').unwrap_or(path);
+
+    let mut current = value;
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            rest = after_dot;
+            continue;
+        }
+
+        if let Some(after_bracket) = rest.strip_prefix('[') {
+            let end = after_bracket.find(']')?;
+            let token = after_bracket[..end].trim();
+            rest = &after_bracket[end + 1..];
+
+            current = if let Ok(index) = token.parse::<usize>() {
+                current.get(index)?
+            } else {
+                let key = token.trim_matches(|c| c == '\'' || c == '"');
+                current.get(key)?
+            };
+            continue;
+        }
+
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        current = current.get(&rest[..end])?;
+        rest = &rest[end..];
+    }
+
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn topic_config() -> KafkaTopicConfig {
+        serde_yaml::from_str(
+            "brokers: localhost:9092
+group_id: hydrocube
+topic: trades
+table_name: trades
+schema:
+  - {column: sym, field_type: VARCHAR, json_path: $.sym}
+  - {column: qty, field_type: INTEGER, json_path: $.qty}",
+        )
+        .unwrap()
+    }
+
+    fn columns(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT column_name FROM information_schema.columns WHERE table_name = 'trades' ORDER BY ordinal_position")
+            .unwrap();
+        let columns = stmt.query_map([], |row| row.get(0)).unwrap();
+        columns.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn adds_the_lineage_columns_to_tables_from_older_versions() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE trades (sym VARCHAR, qty INTEGER); INSERT INTO trades VALUES ('Z', 9);")
+            .unwrap();
+
+        create_table_if_not_exists(&conn, &topic_config()).unwrap();
+        assert_eq!(columns(&conn), ["sym", "qty", SOURCE_COLUMN, INSERT_TIMESTAMP_COLUMN]);
+        // Running it again against an up-to-date table is a no-op.
+        create_table_if_not_exists(&conn, &topic_config()).unwrap();
+        assert_eq!(columns(&conn).len(), 4);
+    }
+
+    #[test]
+    fn stamps_messages_with_their_topic_partition_and_offset() {
+        let conn = Connection::open_in_memory().unwrap();
+        let config = topic_config();
+        create_table_if_not_exists(&conn, &config).unwrap();
+        create_offsets_table_if_not_exists(&conn).unwrap();
+
+        insert_message(&conn, &config, 3, 41, &json!({"sym": "A", "qty": 1})).unwrap();
+        let (source, stamped): (String, bool) = conn
+            .query_row(
+                &format!("SELECT {}, {} IS NOT NULL FROM trades", SOURCE_COLUMN, INSERT_TIMESTAMP_COLUMN),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((source.as_str(), stamped), ("trades/3/41", true));
+    }
+
+    #[test]
+    fn follows_dotted_and_bracketed_paths() {
+        let message = json!({
+            "sym": "A",
+            "quote": {"bid": 1.5, "ask": null},
+            "items": [{"price": 10}, {"price": 20}],
+            "odd.key": true,
+        });
+        assert_eq!(extract_json_path(&message, "$.sym"), Some(&json!("A")));
+        assert_eq!(extract_json_path(&message, "sym"), Some(&json!("A")));
+        assert_eq!(extract_json_path(&message, " $.quote.bid "), Some(&json!(1.5)));
+        assert_eq!(extract_json_path(&message, "quote.ask"), Some(&JsonValue::Null));
+        assert_eq!(extract_json_path(&message, "$.items[1].price"), Some(&json!(20)));
+        assert_eq!(extract_json_path(&message, "$['quote'][\"bid\"]"), Some(&json!(1.5)));
+        assert_eq!(extract_json_path(&message, "$['odd.key']"), Some(&json!(true)));
+        assert_eq!(extract_json_path(&message, "$"), Some(&message));
+    }
+
+    #[test]
+    fn missing_steps_are_none() {
+        let message = json!({"quote": {"bid": 1.5}, "items": [{"price": 10}]});
+        for path in [
+            "$.missing",
+            "$.quote.ask",
+            "$.quote.bid.more",
+            "$.items[1]",
+            "$.items[0].size",
+            "$.items[x]",
+            "$.quote[0]",
+            "$.items[0",
+        ] {
+            assert_eq!(extract_json_path(&message, path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn only_values_that_fit_their_columns_pass_the_check() {
+        let conn = Connection::open_in_memory().unwrap();
+        let topic_config = topic_config();
+        for message in [
+            json!({"sym": "A", "qty": 5}),
+            json!({"sym": "A", "qty": "5"}),
+            json!({"sym": 7}),
+        ] {
+            assert!(message_fits(&conn, &topic_config, &message).unwrap(), "{}", message);
+        }
+        for message in [json!({"sym": "A", "qty": "five"}), json!({"sym": "A", "qty": 1e20})] {
+            assert!(!message_fits(&conn, &topic_config, &message).unwrap(), "{}", message);
+        }
+    }
+}
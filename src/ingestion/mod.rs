@@ -1,3 +1,12 @@
 pub mod directory_watcher;
 pub mod handlers;
-mod kafka_utils;
\ No newline at end of file
+pub mod kafka_consumer;
+mod kafka_utils;
+pub mod writer;
+
+/// Column stamped on every ingested row with the time it was written.
+pub const INSERT_TIMESTAMP_COLUMN: &str = "insert_timestamp";
+
+/// Column recording where every ingested row came from: the file path for
+/// file-based datasets, or `topic/partition/offset` for Kafka.
+pub const SOURCE_COLUMN: &str = "source";
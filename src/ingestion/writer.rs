@@ -0,0 +1,392 @@
+use std::collections::HashSet;
+use std::thread;
+use anyhow::{anyhow, Context, Result};
+use duckdb::Connection;
+use r2d2::Pool;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+use crate::db::db_pool::DuckDBConnectionManager;
+
+/// Most grouped writes committed in one transaction.
+const MAX_GROUP_SIZE: usize = 256;
+
+/// Writes that can be queued before submitters have to wait.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The single writer every ingestion source submits its writes to.
+///
+/// Concurrent transactions that write the same DuckDB table fail with write
+/// conflicts, so ingested rows are only ever written from one thread, in the
+/// order the writes were submitted; writes to one dataset are therefore
+/// applied in order. Readers keep using the pool.
+///
+/// Grouped writes that are queued back to back are committed in one
+/// transaction. If it fails, each is retried in its own, so one bad write
+/// doesn't take the others with it, and a write that fails on its own is
+/// replaced by its `on_failure`. Exclusive writes run on their own and
+/// handle transactions themselves.
+///
+/// Once a write that changed rows commits, its table is sent on `ingested`.
+#[derive(Clone)]
+pub struct Writer {
+    sender: Sender<Job>,
+}
+
+struct Job {
+    table: String,
+    write: Write,
+    reply: oneshot::Sender<Result<usize>>,
+}
+
+type GroupedWrite = Box<dyn Fn(&Connection) -> Result<usize> + Send>;
+type FailedWrite = Box<dyn Fn(&Connection, &anyhow::Error) -> Result<usize> + Send>;
+type ExclusiveWrite = Box<dyn FnOnce(&mut Connection) -> Result<usize> + Send>;
+
+enum Write {
+    Grouped(GroupedWrite, FailedWrite),
+    Exclusive(ExclusiveWrite),
+}
+
+/// A write that has been queued; see [`Writer::enqueue`].
+pub struct PendingWrite(oneshot::Receiver<Result<usize>>);
+
+impl Writer {
+    /// Starts the writer thread. It stops once every `Writer` is dropped and
+    /// the queue has drained.
+    pub fn start(pool: Pool<DuckDBConnectionManager>, ingested: UnboundedSender<String>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("hydrocube-writer".into())
+            .spawn(move || run(pool, receiver, ingested))
+            .expect("Failed to start the writer thread");
+        Writer { sender }
+    }
+
+    /// Queues statements that write to `table`. `write` runs inside a
+    /// transaction the writer opens, which other writes may share, and
+    /// returns how many rows it changed.
+    ///
+    /// If `write` fails even in a transaction of its own, `on_failure` runs
+    /// in its place, given the error, still in submission order; it can
+    /// record what couldn't be written (a dead letter) so the source can move
+    /// on without losing it. The write's result is then `on_failure`'s.
+    ///
+    /// Returns once the write is queued, so a source can keep submitting
+    /// while earlier writes commit; wait on the [`PendingWrite`] for the result.
+    pub async fn enqueue(
+        &self,
+        table: &str,
+        write: impl Fn(&Connection) -> Result<usize> + Send + 'static,
+        on_failure: impl Fn(&Connection, &anyhow::Error) -> Result<usize> + Send + 'static,
+    ) -> Result<PendingWrite> {
+        self.submit(table, Write::Grouped(Box::new(write), Box::new(on_failure)))
+            .await
+    }
+
+    /// Runs `write` on its own, for writes that manage their own
+    /// transactions (such as one per file) or change the schema.
+    pub async fn write_exclusive(
+        &self,
+        table: &str,
+        write: impl FnOnce(&mut Connection) -> Result<usize> + Send + 'static,
+    ) -> Result<usize> {
+        self.submit(table, Write::Exclusive(Box::new(write)))
+            .await?
+            .wait()
+            .await
+    }
+
+    /// Waits until every write queued so far has committed (or failed).
+    pub async fn flush(&self) -> Result<()> {
+        self.write_exclusive("", |_| Ok(0)).await?;
+        Ok(())
+    }
+
+    async fn submit(&self, table: &str, write: Write) -> Result<PendingWrite> {
+        let (reply, receiver) = oneshot::channel();
+        let job = Job {
+            table: table.to_string(),
+            write,
+            reply,
+        };
+        self.sender
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("The writer has stopped"))?;
+        Ok(PendingWrite(receiver))
+    }
+}
+
+impl Job {
+    fn run_grouped(&self, conn: &Connection) -> Result<usize> {
+        match &self.write {
+            Write::Grouped(write, _) => write(conn),
+            Write::Exclusive(_) => unreachable!("only grouped jobs are grouped"),
+        }
+    }
+
+    /// Runs `on_failure` for a grouped write that failed on its own, in a
+    /// transaction of its own.
+    fn run_on_failure(&self, conn: &mut Connection, error: anyhow::Error) -> Result<usize> {
+        let Write::Grouped(_, on_failure) = &self.write else {
+            unreachable!("only grouped jobs are grouped");
+        };
+        in_transaction(conn, |tx| on_failure(tx, &error))
+            .with_context(|| format!("Handling a failed write ({:#})", error))
+    }
+}
+
+impl PendingWrite {
+    /// Waits for the write to commit, returning how many rows it changed.
+    pub async fn wait(self) -> Result<usize> {
+        self.0
+            .await
+            .map_err(|_| anyhow!("The writer stopped before the write finished"))?
+    }
+}
+
+fn run(
+    pool: Pool<DuckDBConnectionManager>,
+    mut receiver: Receiver<Job>,
+    ingested: UnboundedSender<String>,
+) {
+    // An exclusive job that ended a group, to run before taking more.
+    let mut next: Option<Job> = None;
+    while let Some(job) = next.take().or_else(|| receiver.blocking_recv()) {
+        if matches!(job.write, Write::Exclusive(_)) {
+            run_exclusive(&pool, job, &ingested);
+            continue;
+        }
+
+        let mut group = vec![job];
+        while group.len() < MAX_GROUP_SIZE {
+            match receiver.try_recv() {
+                Ok(job) if matches!(job.write, Write::Grouped(..)) => group.push(job),
+                Ok(job) => {
+                    next = Some(job);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        run_group(&pool, group, &ingested);
+    }
+}
+
+fn run_exclusive(pool: &Pool<DuckDBConnectionManager>, job: Job, ingested: &UnboundedSender<String>) {
+    let Write::Exclusive(write) = job.write else {
+        unreachable!("only exclusive jobs run alone");
+    };
+    let result = pool
+        .get()
+        .map_err(anyhow::Error::from)
+        .and_then(|mut conn| write(&mut conn));
+    if matches!(result, Ok(changed) if changed > 0) {
+        let _ = ingested.send(job.table);
+    }
+    let _ = job.reply.send(result);
+}
+
+fn run_group(pool: &Pool<DuckDBConnectionManager>, group: Vec<Job>, ingested: &UnboundedSender<String>) {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            for job in group {
+                let _ = job.reply.send(Err(anyhow!("No connection for the writer: {}", e)));
+            }
+            return;
+        }
+    };
+    let results: Vec<Result<usize>> = match in_transaction(&mut conn, |tx| {
+        group.iter().map(|job| job.run_grouped(tx)).collect::<Result<Vec<_>>>()
+    }) {
+        Ok(counts) => counts.into_iter().map(Ok).collect(),
+        Err(e) if group.len() == 1 => vec![group[0].run_on_failure(&mut conn, e)],
+        Err(e) => {
+            eprintln!(
+                "A group of {} writes failed, retrying them one at a time: {:?}",
+                group.len(),
+                e
+            );
+            group
+                .iter()
+                .map(|job| {
+                    in_transaction(&mut conn, |tx| job.run_grouped(tx))
+                        .or_else(|e| job.run_on_failure(&mut conn, e))
+                })
+                .collect()
+        }
+    };
+
+    let mut changed = HashSet::new();
+    for (job, result) in group.into_iter().zip(results) {
+        if matches!(result, Ok(count) if count > 0) && changed.insert(job.table.clone()) {
+            let _ = ingested.send(job.table);
+        }
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Runs `f` in a transaction, committing if it succeeds.
+fn in_transaction<T>(conn: &mut Connection, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    let tx = conn.transaction()?;
+    let value = f(&tx)?;
+    tx.commit()?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    fn pool() -> Pool<DuckDBConnectionManager> {
+        let pool = memory_pool(2);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE t (x INTEGER PRIMARY KEY);
+                 CREATE TABLE dead (x INTEGER, error VARCHAR)",
+            )
+            .unwrap();
+        pool
+    }
+
+    /// Transaction ids the writes ran in, in the order they ran.
+    type Transactions = Arc<Mutex<Vec<i64>>>;
+
+    /// A grouped job inserting `x` into `t`, which dead-letters it on failure.
+    fn insert(x: i32, transactions: &Transactions) -> (Job, oneshot::Receiver<Result<usize>>) {
+        let transactions = transactions.clone();
+        let write: GroupedWrite = Box::new(move |conn| {
+            transactions.lock().unwrap().push(conn.query_row("SELECT txid_current()", [], |row| row.get(0))?);
+            Ok(conn.execute("INSERT INTO t VALUES (?)", [x])?)
+        });
+        let on_failure: FailedWrite = Box::new(move |conn, error| {
+            Ok(conn.execute("INSERT INTO dead VALUES (?, ?)", duckdb::params![x, error.to_string()])?)
+        });
+        let (reply, receiver) = oneshot::channel();
+        let job = Job {
+            table: "t".to_string(),
+            write: Write::Grouped(write, on_failure),
+            reply,
+        };
+        (job, receiver)
+    }
+
+    fn column(pool: &Pool<DuckDBConnectionManager>, sql: &str) -> Vec<i32> {
+        let conn = pool.get().unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    /// Runs `jobs` as one group, returning each job's result and the
+    /// tables sent on `ingested`.
+    fn run_jobs(
+        pool: &Pool<DuckDBConnectionManager>,
+        jobs: Vec<(Job, oneshot::Receiver<Result<usize>>)>,
+    ) -> (Vec<Result<usize>>, Vec<String>) {
+        let (ingested, mut changed) = mpsc::unbounded_channel();
+        let (group, mut replies): (Vec<_>, Vec<_>) = jobs.into_iter().unzip();
+        run_group(pool, group, &ingested);
+        let results = replies.iter_mut().map(|reply| reply.try_recv().unwrap()).collect();
+        let mut tables = Vec::new();
+        while let Ok(table) = changed.try_recv() {
+            tables.push(table);
+        }
+        (results, tables)
+    }
+
+    #[test]
+    fn commits_a_group_in_one_transaction() {
+        let pool = pool();
+        let transactions = Transactions::default();
+        let jobs = (1..=3).map(|x| insert(x, &transactions)).collect();
+        let (results, tables) = run_jobs(&pool, jobs);
+
+        assert!(results.iter().all(|r| matches!(r, Ok(1))));
+        assert_eq!(tables, vec!["t"]);
+        assert_eq!(column(&pool, "SELECT x FROM t ORDER BY x"), vec![1, 2, 3]);
+        let transactions = transactions.lock().unwrap();
+        assert!(transactions.iter().all(|tx| *tx == transactions[0]), "{:?}", transactions);
+    }
+
+    #[test]
+    fn retries_a_failed_group_one_write_at_a_time() {
+        let pool = pool();
+        pool.get().unwrap().execute("INSERT INTO t VALUES (2)", []).unwrap();
+        let transactions = Transactions::default();
+        let jobs = (1..=3).map(|x| insert(x, &transactions)).collect();
+        let (results, tables) = run_jobs(&pool, jobs);
+
+        // The duplicate is dead-lettered; the writes either side still land.
+        assert!(results.iter().all(|r| matches!(r, Ok(1))));
+        assert_eq!(tables, vec!["t"]);
+        assert_eq!(column(&pool, "SELECT x FROM t ORDER BY x"), vec![1, 2, 3]);
+        assert_eq!(column(&pool, "SELECT x FROM dead"), vec![2]);
+        let error: String = pool
+            .get()
+            .unwrap()
+            .query_row("SELECT error FROM dead", [], |row| row.get(0))
+            .unwrap();
+        assert!(error.contains("PRIMARY KEY") || error.contains("Duplicate key"), "{}", error);
+
+        // Two writes in the failed group, then one transaction per write.
+        let transactions = transactions.lock().unwrap();
+        assert_eq!(transactions.len(), 2 + 3);
+        assert_ne!(transactions[2], transactions[4]);
+    }
+
+    #[test]
+    fn a_write_whose_on_failure_fails_reports_both_errors() {
+        let pool = pool();
+        pool.get().unwrap().execute_batch("INSERT INTO t VALUES (1); DROP TABLE dead").unwrap();
+        let transactions = Transactions::default();
+        let (results, tables) = run_jobs(&pool, vec![insert(1, &transactions), insert(2, &transactions)]);
+
+        let error = format!("{:#}", results[0].as_ref().unwrap_err());
+        assert!(error.starts_with("Handling a failed write"), "{}", error);
+        assert!(matches!(results[1], Ok(1)));
+        assert_eq!(tables, vec!["t"]);
+        assert_eq!(column(&pool, "SELECT x FROM t ORDER BY x"), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn applies_writes_in_submission_order() {
+        let pool = pool();
+        pool.get()
+            .unwrap()
+            .execute_batch("CREATE TABLE log (seq INTEGER, x INTEGER); CREATE SEQUENCE order_seq")
+            .unwrap();
+        let (ingested, _changed) = mpsc::unbounded_channel();
+        let writer = Writer::start(pool.clone(), ingested);
+
+        let mut pending = Vec::new();
+        for x in 0..200 {
+            let write = move |conn: &Connection| -> Result<usize> {
+                Ok(conn.execute("INSERT INTO log VALUES (nextval('order_seq'), ?)", [x])?)
+            };
+            pending.push(writer.enqueue("log", write, |_, e| Err(anyhow!("{:#}", e))).await.unwrap());
+            if x % 50 == 0 {
+                // Exclusive writes split the groups without reordering them.
+                writer
+                    .write_exclusive("log", move |conn| {
+                        Ok(conn.execute("INSERT INTO log VALUES (nextval('order_seq'), ?)", [-x])?)
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        for write in pending {
+            assert_eq!(write.wait().await.unwrap(), 1);
+        }
+        writer.flush().await.unwrap();
+
+        let expected: Vec<i32> = (0..200)
+            .flat_map(|x| if x % 50 == 0 { vec![x, -x] } else { vec![x] })
+            .collect();
+        assert_eq!(column(&pool, "SELECT x FROM log ORDER BY seq"), expected);
+    }
+}
@@ -1,19 +1,34 @@
+mod auth;
 mod config;
 mod db;
+mod flight;
 mod ingestion;
+mod postgres;
 mod server;
 mod aggregation;
+mod publisher;
+mod query;
+mod scheduler;
 
 use actix_web::web;
 use anyhow::{Context, Result};
 use clap::Parser;
 use std::fs;
-use r2d2::Pool;
+use std::sync::Arc;
 use rustls::crypto::{self, CryptoProvider};
+use tokio::sync::{broadcast, mpsc};
+use crate::aggregation::aggregator::{aggregate_refresher, refresh_through_writer};
 use crate::config::cli::Cli;
 use crate::config::config::{AppConfig, FileFormat};
-use crate::db::db_pool::DuckDBConnectionManager;
+use crate::db::db_pool::create_pool;
+use crate::flight::flight_sql::run_flight_server;
 use crate::ingestion::directory_watcher::directory_watcher;
+use crate::ingestion::kafka_consumer::kafka_consumer;
+use crate::ingestion::writer::Writer;
+use crate::postgres::pg_wire::run_postgres_server;
+use crate::publisher::hub::{publish_on_change, PublisherHub};
+use crate::scheduler::scheduler::Scheduler;
+use crate::server::tls::Certificates;
 use crate::server::web_server;
 
 #[actix_web::main]
@@ -32,14 +47,77 @@ async fn main() -> Result<()> {
     let config_data = web::Data::new(config);
 
     // Set up the DuckDB connection pool.
-    let connection_string = "/tmp/hydrocube.duckdb";
-    let manager = DuckDBConnectionManager::new(connection_string.to_string());
-    let pool: Pool<DuckDBConnectionManager> = Pool::new(manager)
-        .expect("Failed to create DuckDB connection pool");
+    let (pool, pool_metrics) = create_pool(&config_data.database)?;
+
+    // Ingestion sources report which tables they wrote to, and the aggregate
+    // refresher rebuilds any aggregate built on top of them. Both ingested
+    // tables and refreshed aggregates are then broadcast to the publishers.
+    let (ingested_tx, ingested_rx) = mpsc::unbounded_channel::<String>();
+    let (changed_tx, changed_rx) = broadcast::channel::<String>(1024);
+
+    // Every ingestion source, and every aggregate refresh, writes through the
+    // one writer, which announces the tables ingestion changed on `ingested`.
+    let writer = Writer::start(pool.clone(), ingested_tx);
+    {
+        let writer_clone = writer.clone();
+        let aggregates = config_data.aggregates.clone();
+        let changed = changed_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = aggregate_refresher(writer_clone, aggregates, ingested_rx, changed).await {
+                eprintln!("Aggregate refresher error: {:?}", e);
+            }
+        });
+    }
+
+    // The publisher hub pushes changed rows to WebSocket subscribers.
+    let hub = Arc::new(PublisherHub::new(pool.clone(), &config_data));
+    {
+        let hub_clone = hub.clone();
+        tokio::spawn(async move {
+            if let Err(e) = publish_on_change(hub_clone, changed_rx).await {
+                eprintln!("Publisher error: {:?}", e);
+            }
+        });
+    }
+
+    // Aggregates and publishers with an interval or cron schedule run on the scheduler.
+    let mut scheduler = Scheduler::new(pool.clone());
+    for aggregate in &config_data.aggregates {
+        let Some(schedule) = aggregate.schedule.clone() else {
+            continue;
+        };
+        let writer_clone = writer.clone();
+        let aggregate_clone = aggregate.clone();
+        let changed = changed_tx.clone();
+        let runtime = tokio::runtime::Handle::current();
+        scheduler.add_job(
+            format!("refresh_aggregate:{}", aggregate.name),
+            schedule,
+            Arc::new(move || {
+                // Jobs run on a blocking thread, so waiting here is fine.
+                runtime.block_on(refresh_through_writer(&writer_clone, &aggregate_clone))?;
+                let _ = changed.send(aggregate_clone.name.clone());
+                Ok(())
+            }),
+        );
+    }
+    for publisher in &config_data.publishers {
+        let Some(schedule) = publisher.schedule.clone() else {
+            continue;
+        };
+        let hub_clone = hub.clone();
+        let name = publisher.name.clone();
+        scheduler.add_job(
+            format!("publish:{}", publisher.name),
+            schedule,
+            Arc::new(move || hub_clone.publish(&name)),
+        );
+    }
+    scheduler.start()?;
 
     // Spawn directory watchers for each dataset.
     for dataset in config_data.datasets.clone() {
-        let pool_clone = pool.clone();
+        let writer_clone = writer.clone();
 
         // Here `dataset` is an owned DatasetConfig if your config_data.datasets is a Vec<DatasetConfig>
         // But let's still clone what we need so they outlive this for-loop.
@@ -51,20 +129,61 @@ async fn main() -> Result<()> {
             tokio::spawn(async move {
                 // We have owned `dir` (String) and owned `dataset_cloned` (DatasetConfig).
                 // No lifetime issues: they live within this async task.
-                if let Err(e) = directory_watcher(&dir, pool_clone, dataset_cloned).await {
+                if let Err(e) = directory_watcher(&dir, writer_clone, dataset_cloned).await {
                     eprintln!("Directory watcher error: {:?}", e);
                 }
             });
+        } else if matches!(dataset.format, FileFormat::Kafka) && dataset.kafka.is_some() {
+            // Kafka datasets get a long-running consumer instead of a watcher.
+            let pool_clone = pool.clone();
+            tokio::spawn(async move {
+                if let Err(e) = kafka_consumer(pool_clone, writer_clone, dataset).await {
+                    eprintln!("Kafka consumer error: {:?}", e);
+                }
+            });
         }
     }
 
 
-    // (Optional) Set up OAuth if enabled.
-    if config_data.security.oauth.enabled {
-        println!("OAuth is enabled for provider: {}", config_data.security.oauth.provider);
-        // Insert your OAuth flow setup here.
+    // With HTTPS enabled, every listener presents the same certificate,
+    // reloaded when it's renewed.
+    let certificates = if config_data.security.https.enabled {
+        Some(Certificates::watch(&config_data.security.https)?)
+    } else {
+        None
+    };
+
+    // (Optional) Serve Arrow Flight SQL alongside HTTP.
+    if config_data.flight.enabled {
+        let pool_clone = pool.clone();
+        let config_clone = config_data.clone();
+        let certificates = certificates.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_flight_server(pool_clone, &config_clone, certificates).await {
+                eprintln!("Flight SQL server error: {:?}", e);
+            }
+        });
+    }
+
+    // (Optional) Serve the PostgreSQL wire protocol for BI tools.
+    if config_data.postgres.enabled {
+        let pool_clone = pool.clone();
+        let config_clone = config_data.clone();
+        let certificates = certificates.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_postgres_server(pool_clone, &config_clone, certificates).await {
+                eprintln!("PostgreSQL server error: {:?}", e);
+            }
+        });
     }
 
     // Start the server.
-    web_server::run_server(pool, config_data).await
+    web_server::run_server(
+        pool,
+        config_data,
+        web::Data::from(hub),
+        web::Data::from(pool_metrics),
+        certificates,
+    )
+    .await
 }
\ No newline at end of file
@@ -0,0 +1,78 @@
+/// Postgres session parameters that clients commonly `SHOW` on connect, and
+/// the values HydroCube reports for them.
+const SESSION_PARAMETERS: &[(&str, &str)] = &[
+    ("server_version", SERVER_VERSION),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("datestyle", "ISO, YMD"),
+    ("timezone", "UTC"),
+    ("standard_conforming_strings", "on"),
+    ("integer_datetimes", "on"),
+    ("transaction_isolation", "read committed"),
+    ("search_path", "main"),
+];
+
+/// The Postgres version HydroCube reports. Drivers gate features on it, so it
+/// must look like a real server release.
+pub const SERVER_VERSION: &str = "14.0";
+
+/// How a statement that DuckDB can't (or shouldn't) run is answered instead.
+#[derive(Debug, PartialEq)]
+pub enum CompatResponse {
+    /// Acknowledge with the given command tag and do nothing.
+    Command(&'static str),
+    TransactionStart,
+    /// Ends the transaction with the given command tag.
+    TransactionEnd(&'static str),
+    /// A single-row, single-column text result.
+    Value { column: String, value: String },
+}
+
+/// Recognizes the session and transaction housekeeping that BI tools and
+/// drivers send around their real queries.
+///
+/// Settings are accepted and ignored, transactions are acknowledged (every
+/// query is read-only anyway), and `SHOW` of a Postgres session parameter or
+/// `SELECT version()` gets a Postgres-looking answer. Anything else returns
+/// `None` and goes to DuckDB, whose `pg_catalog` and `information_schema`
+/// answer the catalog introspection queries.
+pub fn compat_response(statement: &str) -> Option<CompatResponse> {
+    let normalized = statement
+        .trim_end()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let words: Vec<&str> = normalized.split(' ').collect();
+
+    match words.first().copied()? {
+        "set" => Some(CompatResponse::Command("SET")),
+        "reset" => Some(CompatResponse::Command("RESET")),
+        "discard" => Some(CompatResponse::Command("DISCARD ALL")),
+        "deallocate" => Some(CompatResponse::Command("DEALLOCATE")),
+        "close" => Some(CompatResponse::Command("CLOSE CURSOR")),
+        "begin" | "start" => Some(CompatResponse::TransactionStart),
+        "commit" | "end" => Some(CompatResponse::TransactionEnd("COMMIT")),
+        "rollback" | "abort" => Some(CompatResponse::TransactionEnd("ROLLBACK")),
+        "show" => {
+            let name = match &words[1..] {
+                ["transaction", "isolation", "level"] => "transaction_isolation",
+                [name] => name,
+                _ => return None,
+            };
+            let (column, value) = SESSION_PARAMETERS.iter().find(|(p, _)| *p == name)?;
+            Some(CompatResponse::Value {
+                column: column.to_string(),
+                value: value.to_string(),
+            })
+        }
+        "select" if matches!(&words[1..], ["version()"] | ["pg_catalog.version()"]) => {
+            Some(CompatResponse::Value {
+                column: "version".into(),
+                value: format!("PostgreSQL {} (HydroCube {})", SERVER_VERSION, env!("CARGO_PKG_VERSION")),
+            })
+        }
+        _ => None,
+    }
+}
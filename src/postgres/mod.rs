@@ -0,0 +1,2 @@
+pub mod compat;
+pub mod pg_wire;
\ No newline at end of file
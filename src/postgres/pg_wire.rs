@@ -0,0 +1,971 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Context, Result};
+use arrow::datatypes::{DataType, Schema};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use duckdb::types::{TimeUnit, Value};
+use duckdb::{params_from_iter, Connection};
+use futures::{stream, Sink, StreamExt};
+use pgwire::api::auth::md5pass::{hash_md5_password, Md5PasswordAuthStartupHandler};
+use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::copy::NoopCopyHandler;
+use pgwire::api::portal::{Format, Portal};
+use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use pgwire::api::results::{
+    DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse,
+    Response, Tag,
+};
+use pgwire::api::stmt::{NoopQueryParser, StoredStatement};
+use pgwire::api::store::PortalStore;
+use pgwire::api::{
+    ClientInfo, ClientPortalStore, NoopErrorHandler, PgWireServerHandlers, Type, METADATA_USER,
+};
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use pgwire::messages::data::DataRow;
+use pgwire::messages::PgWireBackendMessage;
+use pgwire::tokio::process_socket;
+use postgres_types::FromSqlOwned;
+use r2d2::Pool;
+use rust_decimal::Decimal;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use serde_json::Map;
+use tokio_rustls::TlsAcceptor;
+use crate::auth::access::{AccessControl, Permissions};
+use crate::auth::identity::Identity;
+use crate::config::config::AppConfig;
+use crate::db::arrow_streaming::result_schema;
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::db::interrupt::QueryInterrupt;
+use crate::postgres::compat::{compat_response, CompatResponse, SERVER_VERSION};
+use crate::query::read_only::{ensure_read_only, split_statements};
+use crate::query::sql::quote_identifier;
+use crate::server::tls::Certificates;
+
+/// Starts the PostgreSQL wire-protocol listener and serves until it fails.
+///
+/// Uses TLS with the certificate and key from `security.https` when HTTPS is
+/// enabled (`certificates`); clients that don't ask for TLS can still connect
+/// in plain text.
+pub async fn run_postgres_server(
+    pool: Pool<DuckDBConnectionManager>,
+    config: &AppConfig,
+    certificates: Option<Arc<Certificates>>,
+) -> Result<()> {
+    let address = format!("{}:{}", config.postgres.host, config.postgres.port);
+    let tls = certificates
+        .map(|certificates| Arc::new(TlsAcceptor::from(Arc::new(certificates.server_config()))));
+
+    let listener = TcpListener::bind(&address)
+        .await
+        .with_context(|| format!("Cannot bind PostgreSQL listener to {}", address))?;
+    println!("Starting PostgreSQL wire-protocol server on {}", address);
+    if config.postgres.users.is_empty() {
+        println!("No postgres.users are configured; every login will be refused");
+    }
+
+    let handlers = Arc::new(HydroCubePgHandlers::new(pool, config)?);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let tls = tls.clone();
+        let handlers = handlers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process_socket(socket, tls, handlers).await {
+                eprintln!("PostgreSQL connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Per-connection handlers for pgwire.
+///
+/// The MD5 startup handler keeps the expected password for the login in
+/// progress, so each connection gets its own.
+pub struct HydroCubePgHandlers {
+    backend: Arc<HydroCubePgBackend>,
+    users: Arc<ConfiguredUsers>,
+    parameters: Arc<DefaultServerParameterProvider>,
+}
+
+impl HydroCubePgHandlers {
+    pub fn new(pool: Pool<DuckDBConnectionManager>, config: &AppConfig) -> Result<Self> {
+        let users = config
+            .postgres
+            .users
+            .iter()
+            .map(|u| (u.username.clone(), u.password.clone()))
+            .collect();
+
+        // Drivers parse server_version, so report a Postgres release rather than ours.
+        let mut parameters = DefaultServerParameterProvider::default();
+        parameters.server_version = SERVER_VERSION.to_string();
+
+        let user_roles = config
+            .postgres
+            .users
+            .iter()
+            .map(|u| (u.username.clone(), u.roles.clone()))
+            .collect();
+
+        Ok(HydroCubePgHandlers {
+            backend: Arc::new(HydroCubePgBackend {
+                pool,
+                access: AccessControl::new(config)?,
+                user_roles,
+                query_parser: Arc::new(NoopQueryParser::new()),
+            }),
+            users: Arc::new(ConfiguredUsers { users }),
+            parameters: Arc::new(parameters),
+        })
+    }
+}
+
+impl PgWireServerHandlers for HydroCubePgHandlers {
+    type StartupHandler = Md5PasswordAuthStartupHandler<ConfiguredUsers, DefaultServerParameterProvider>;
+    type SimpleQueryHandler = HydroCubePgBackend;
+    type ExtendedQueryHandler = HydroCubePgBackend;
+    type CopyHandler = NoopCopyHandler;
+    type ErrorHandler = NoopErrorHandler;
+
+    fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+        self.backend.clone()
+    }
+
+    fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+        self.backend.clone()
+    }
+
+    fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+        Arc::new(Md5PasswordAuthStartupHandler::new(
+            self.users.clone(),
+            self.parameters.clone(),
+        ))
+    }
+
+    fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+        Arc::new(NoopCopyHandler)
+    }
+
+    fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+        Arc::new(NoopErrorHandler)
+    }
+}
+
+/// The logins from `postgres.users`.
+pub struct ConfiguredUsers {
+    users: HashMap<String, String>,
+}
+
+#[async_trait]
+impl AuthSource for ConfiguredUsers {
+    async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+        let salt = rand::random::<[u8; 4]>().to_vec();
+        // An unknown user gets a hash no client can produce, so the failure
+        // looks the same as a wrong password.
+        let hash = match login.user().and_then(|u| self.users.get(u).map(|p| (u, p))) {
+            Some((user, password)) => hash_md5_password(user, password, &salt).into_bytes(),
+            None => Vec::new(),
+        };
+        Ok(Password::new(Some(salt), hash))
+    }
+}
+
+/// Runs wire-protocol queries on the DuckDB pool.
+///
+/// Queries must pass [`ensure_read_only`] and run in a transaction that is
+/// rolled back, as for `POST /api/query`. Session and transaction
+/// housekeeping is answered by [`compat_response`] without touching DuckDB.
+/// Logins may only read the tables their `roles` grant, through any
+/// policies on them.
+pub struct HydroCubePgBackend {
+    pool: Pool<DuckDBConnectionManager>,
+    access: AccessControl,
+    /// Each login's roles from `postgres.users`.
+    user_roles: HashMap<String, Vec<String>>,
+    query_parser: Arc<NoopQueryParser>,
+}
+
+/// How many rows go to the client together, and how many such chunks may
+/// wait for it before the query pauses.
+const ROWS_PER_CHUNK: usize = 1024;
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Result column names with their Postgres types.
+type Columns = Vec<(String, Type)>;
+
+type RowChunk = Result<Vec<Vec<Value>>>;
+
+impl HydroCubePgBackend {
+    /// What the connected login may read.
+    fn permissions<C: ClientInfo>(&self, client: &C) -> Permissions {
+        let user = client.metadata().get(METADATA_USER).cloned().unwrap_or_default();
+        let identity = Identity {
+            roles: self.user_roles.get(&user).cloned().unwrap_or_default(),
+            subject: user,
+            claims: Map::new(),
+        };
+        self.access.permissions(Some(&identity))
+    }
+
+    async fn execute(
+        &self,
+        sql: String,
+        params: Vec<Value>,
+        format: &Format,
+        permissions: &Permissions,
+    ) -> PgWireResult<Response<'static>> {
+        if let Some(compat) = compat_response(&sql) {
+            return compat_to_response(compat, format);
+        }
+        let sql = self.access.secure_query(permissions, &sql).map_err(access_error)?;
+
+        let (columns, chunks) = stream_rows(self.pool.clone(), sql, params)
+            .await
+            .map_err(query_error)?;
+
+        let fields = Arc::new(field_infos(&columns, format));
+        let row_fields = fields.clone();
+        let rows = ReceiverStream::new(chunks).flat_map(move |chunk| {
+            let rows: Vec<_> = match chunk {
+                Ok(rows) => rows.into_iter().map(|row| encode_row(&row_fields, row)).collect(),
+                Err(e) => vec![Err(query_error(e))],
+            };
+            stream::iter(rows)
+        });
+        Ok(Response::Query(QueryResponse::new(fields, rows)))
+    }
+
+    /// The parameter count and result columns of a statement, without running it.
+    async fn describe(
+        &self,
+        sql: String,
+        permissions: &Permissions,
+    ) -> PgWireResult<(usize, Vec<(String, Type)>)> {
+        match compat_response(&sql) {
+            Some(CompatResponse::Value { column, .. }) => return Ok((0, vec![(column, Type::VARCHAR)])),
+            Some(_) => return Ok((0, Vec::new())),
+            None => {}
+        }
+        let sql = self.access.secure_query(permissions, &sql).map_err(access_error)?;
+
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || describe_query(&*pool.get()?, &sql))
+            .await
+            .map_err(|e| PgWireError::ApiError(Box::new(e)))?
+            .map_err(query_error)
+    }
+}
+
+#[async_trait]
+impl SimpleQueryHandler for HydroCubePgBackend {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let statements = split_statements(query).map_err(query_error)?;
+        if statements.is_empty() {
+            return Ok(vec![Response::EmptyQuery]);
+        }
+
+        // Like Postgres, stop at the first statement that fails.
+        let permissions = self.permissions(client);
+        let mut responses = Vec::with_capacity(statements.len());
+        for statement in statements {
+            match self
+                .execute(statement.to_string(), Vec::new(), &Format::UnifiedText, &permissions)
+                .await {
+                Ok(response) => responses.push(response),
+                Err(PgWireError::UserError(info)) => {
+                    responses.push(Response::Error(info));
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[async_trait]
+impl ExtendedQueryHandler for HydroCubePgBackend {
+    type Statement = String;
+    type QueryParser = NoopQueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.query_parser.clone()
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let (param_count, columns) = self
+            .describe(target.statement.clone(), &self.permissions(client))
+            .await?;
+        // Parameters the client didn't declare are sent as text, which DuckDB casts.
+        let param_types = (0..param_count)
+            .map(|i| match target.parameter_types.get(i) {
+                Some(t) if *t != Type::UNKNOWN => t.clone(),
+                _ => Type::TEXT,
+            })
+            .collect();
+        Ok(DescribeStatementResponse::new(
+            param_types,
+            field_infos(&columns, &Format::UnifiedText),
+        ))
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let (_, columns) = self
+            .describe(target.statement.statement.clone(), &self.permissions(client))
+            .await?;
+        Ok(DescribePortalResponse::new(field_infos(
+            &columns,
+            &target.result_column_format,
+        )))
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        _max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let params = portal_params(portal)?;
+        self.execute(
+            portal.statement.statement.clone(),
+            params,
+            &portal.result_column_format,
+            &self.permissions(client),
+        )
+        .await
+    }
+}
+
+/// Runs a read-only query on a blocking thread and hands its rows over a
+/// bounded channel in chunks, as [`stream_batches`] does with Arrow batches.
+///
+/// A slow client parks the query thread; one that goes away interrupts the
+/// query, which releases its pooled connection. The query runs in a
+/// transaction that is rolled back. Returns the result's columns once the
+/// query has started, so errors before the first row come back here; a
+/// failure partway through arrives as a final `Err` chunk.
+///
+/// [`stream_batches`]: crate::db::arrow_streaming::stream_batches
+async fn stream_rows(
+    pool: Pool<DuckDBConnectionManager>,
+    sql: String,
+    params: Vec<Value>,
+) -> Result<(Columns, mpsc::Receiver<RowChunk>)> {
+    let (chunks_tx, chunks_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let interrupt = QueryInterrupt::default();
+    let watcher = {
+        let chunks_tx = chunks_tx.clone();
+        let interrupt = interrupt.clone();
+        tokio::spawn(async move {
+            chunks_tx.closed().await;
+            interrupt.interrupt();
+        })
+    };
+
+    task::spawn_blocking(move || {
+        let mut ready = Some(ready_tx);
+        let result = send_rows(&pool, &sql, &params, &chunks_tx, &mut ready, &interrupt);
+        watcher.abort();
+        match (result, ready) {
+            (Ok(()), _) => {}
+            (Err(e), Some(ready)) => {
+                let _ = ready.send(Err(e));
+            }
+            (Err(_), None) if chunks_tx.is_closed() => {
+                println!("PostgreSQL client went away; abandoned query: {}", sql);
+            }
+            (Err(e), None) => {
+                eprintln!("Error streaming query {}: {:?}", sql, e);
+                let _ = chunks_tx.blocking_send(Err(e));
+            }
+        }
+    });
+
+    let columns = ready_rx
+        .await
+        .map_err(|_| anyhow!("Query task ended before starting"))??;
+    Ok((columns, chunks_rx))
+}
+
+fn send_rows(
+    pool: &Pool<DuckDBConnectionManager>,
+    sql: &str,
+    params: &[Value],
+    chunks_tx: &mpsc::Sender<RowChunk>,
+    ready: &mut Option<oneshot::Sender<Result<Columns>>>,
+    interrupt: &QueryInterrupt,
+) -> Result<()> {
+    ensure_read_only(sql)?;
+    let sql = without_terminator(sql);
+
+    let mut conn = pool.get()?;
+    let _armed = interrupt.arm(&conn);
+    if interrupt.is_interrupted() {
+        bail!("client disconnected");
+    }
+    // Dropping the transaction on an early return rolls it back too.
+    let tx = conn.transaction()?;
+    {
+        let schema = result_schema(&tx, sql, params)?;
+        let (sql, columns) = pg_columns(sql, &schema);
+        let width = columns.len();
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(params.iter()))?;
+
+        if let Some(ready) = ready.take() {
+            let _ = ready.send(Ok(columns));
+        }
+
+        let mut chunk = Vec::with_capacity(ROWS_PER_CHUNK);
+        while let Some(row) = rows.next()? {
+            chunk.push((0..width).map(|i| row.get::<_, Value>(i)).collect::<Result<Vec<_>, _>>()?);
+            if chunk.len() == ROWS_PER_CHUNK {
+                chunks_tx
+                    .blocking_send(Ok(std::mem::take(&mut chunk)))
+                    .map_err(|_| anyhow!("client disconnected"))?;
+            }
+        }
+        if !chunk.is_empty() {
+            chunks_tx
+                .blocking_send(Ok(chunk))
+                .map_err(|_| anyhow!("client disconnected"))?;
+        }
+        if interrupt.is_interrupted() {
+            bail!("client disconnected");
+        }
+    }
+    tx.rollback()?;
+    Ok(())
+}
+
+fn describe_query(conn: &Connection, sql: &str) -> Result<(usize, Vec<(String, Type)>)> {
+    ensure_read_only(sql)?;
+    let sql = without_terminator(sql);
+    let param_count = conn.prepare(sql)?.parameter_count();
+    let schema = result_schema(conn, sql, &vec![Value::Null; param_count])?;
+    Ok((param_count, pg_columns(sql, &schema).1))
+}
+
+/// A single statement without its trailing `;`, so it can be nested in a subquery.
+fn without_terminator(sql: &str) -> &str {
+    sql.trim_end().trim_end_matches(';')
+}
+
+/// The Postgres type of each result column, and the query rewritten so that
+/// columns with no Postgres equivalent (lists, structs, intervals, ...) come
+/// back as text.
+fn pg_columns(sql: &str, schema: &Schema) -> (String, Vec<(String, Type)>) {
+    let mut needs_cast = false;
+    let mut projection = Vec::with_capacity(schema.fields().len());
+    let mut columns = Vec::with_capacity(schema.fields().len());
+    for field in schema.fields() {
+        let name = quote_identifier(field.name());
+        match pg_type(field.data_type()) {
+            Some(pg_type) => {
+                projection.push(name);
+                columns.push((field.name().clone(), pg_type));
+            }
+            None => {
+                needs_cast = true;
+                projection.push(format!("CAST({0} AS VARCHAR) AS {0}", name));
+                columns.push((field.name().clone(), Type::VARCHAR));
+            }
+        }
+    }
+
+    if needs_cast {
+        (format!("SELECT {} FROM ({}) AS q", projection.join(", "), sql), columns)
+    } else {
+        (sql.to_string(), columns)
+    }
+}
+
+fn pg_type(data_type: &DataType) -> Option<Type> {
+    Some(match data_type {
+        DataType::Boolean => Type::BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => Type::INT2,
+        DataType::Int32 | DataType::UInt16 => Type::INT4,
+        DataType::Int64 | DataType::UInt32 => Type::INT8,
+        DataType::UInt64 | DataType::Decimal128(..) => Type::NUMERIC,
+        DataType::Float32 => Type::FLOAT4,
+        DataType::Float64 => Type::FLOAT8,
+        DataType::Utf8 | DataType::LargeUtf8 => Type::VARCHAR,
+        DataType::Binary | DataType::LargeBinary => Type::BYTEA,
+        DataType::Date32 => Type::DATE,
+        DataType::Time64(_) => Type::TIME,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
+        _ => return None,
+    })
+}
+
+fn field_infos(columns: &[(String, Type)], format: &Format) -> Vec<FieldInfo> {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(i, (name, pg_type))| {
+            FieldInfo::new(name.clone(), None, None, pg_type.clone(), format.format_for(i))
+        })
+        .collect()
+}
+
+fn encode_row(fields: &Arc<Vec<FieldInfo>>, row: Vec<Value>) -> PgWireResult<DataRow> {
+    let mut encoder = DataRowEncoder::new(fields.clone());
+    for (value, field) in row.into_iter().zip(fields.iter()) {
+        encode_value(&mut encoder, field.datatype(), value)?;
+    }
+    encoder.finish()
+}
+
+/// Encodes a DuckDB value as the column's Postgres type from [`pg_type`].
+fn encode_value(encoder: &mut DataRowEncoder, pg_type: &Type, value: Value) -> PgWireResult<()> {
+    match value {
+        Value::Null => encoder.encode_field(&None::<i16>),
+        Value::Boolean(v) => encoder.encode_field(&v),
+        Value::TinyInt(v) => encoder.encode_field(&(v as i16)),
+        Value::SmallInt(v) => encoder.encode_field(&v),
+        Value::UTinyInt(v) => encoder.encode_field(&(v as i16)),
+        Value::Int(v) => encoder.encode_field(&v),
+        Value::USmallInt(v) => encoder.encode_field(&(v as i32)),
+        Value::BigInt(v) => encoder.encode_field(&v),
+        Value::UInt(v) => encoder.encode_field(&(v as i64)),
+        Value::UBigInt(v) => encoder.encode_field(&Decimal::from(v)),
+        Value::HugeInt(v) => {
+            encoder.encode_field(&Decimal::try_from_i128_with_scale(v, 0).map_err(query_error)?)
+        }
+        Value::Decimal(v) => encoder.encode_field(&v),
+        Value::Float(v) => encoder.encode_field(&v),
+        Value::Double(v) => encoder.encode_field(&v),
+        Value::Text(v) | Value::Enum(v) => encoder.encode_field(&v),
+        Value::Blob(v) => encoder.encode_field(&v),
+        Value::Date32(days) => {
+            let date = DateTime::UNIX_EPOCH.date_naive() + chrono::Duration::days(days.into());
+            encoder.encode_field(&date)
+        }
+        Value::Time64(unit, v) => {
+            let micros = unit.to_micros(v);
+            let time = NaiveTime::from_num_seconds_from_midnight_opt(
+                (micros / 1_000_000) as u32,
+                (micros % 1_000_000 * 1000) as u32,
+            )
+            .ok_or_else(|| query_error(format!("Time out of range: {} microseconds", micros)))?;
+            encoder.encode_field(&time)
+        }
+        Value::Timestamp(unit, v) => {
+            let micros = unit.to_micros(v);
+            let timestamp = DateTime::from_timestamp_micros(micros).ok_or_else(|| {
+                query_error(format!("Timestamp out of range: {} microseconds", micros))
+            })?;
+            if *pg_type == Type::TIMESTAMPTZ {
+                encoder.encode_field(&timestamp)
+            } else {
+                encoder.encode_field(&timestamp.naive_utc())
+            }
+        }
+        // pg_columns casts every other type to text before the query runs.
+        other => encoder.encode_field(&format!("{:?}", other)),
+    }
+}
+
+/// The portal's parameters as DuckDB values.
+///
+/// Text-format parameters are bound as strings and cast by DuckDB; binary
+/// ones are decoded by their declared type.
+fn portal_params(portal: &Portal<String>) -> PgWireResult<Vec<Value>> {
+    (0..portal.parameter_len())
+        .map(|i| {
+            let Some(bytes) = &portal.parameters[i] else {
+                return Ok(Value::Null);
+            };
+            if portal.parameter_format.is_text(i) {
+                return String::from_utf8(bytes.to_vec())
+                    .map(Value::Text)
+                    .map_err(query_error);
+            }
+
+            let pg_type = portal.statement.parameter_types.get(i).unwrap_or(&Type::UNKNOWN);
+            Ok(match *pg_type {
+                Type::BOOL => Value::Boolean(binary_param(portal, i, pg_type)?),
+                Type::INT2 => Value::SmallInt(binary_param(portal, i, pg_type)?),
+                Type::INT4 => Value::Int(binary_param(portal, i, pg_type)?),
+                Type::INT8 => Value::BigInt(binary_param(portal, i, pg_type)?),
+                Type::FLOAT4 => Value::Float(binary_param(portal, i, pg_type)?),
+                Type::FLOAT8 => Value::Double(binary_param(portal, i, pg_type)?),
+                Type::NUMERIC => Value::Decimal(binary_param(portal, i, pg_type)?),
+                Type::BYTEA => Value::Blob(binary_param(portal, i, pg_type)?),
+                Type::DATE => {
+                    let date: NaiveDate = binary_param(portal, i, pg_type)?;
+                    Value::Date32((date - DateTime::UNIX_EPOCH.date_naive()).num_days() as i32)
+                }
+                Type::TIMESTAMP => {
+                    let timestamp: chrono::NaiveDateTime = binary_param(portal, i, pg_type)?;
+                    Value::Timestamp(TimeUnit::Microsecond, timestamp.and_utc().timestamp_micros())
+                }
+                Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN => {
+                    Value::Text(String::from_utf8(bytes.to_vec()).map_err(query_error)?)
+                }
+                _ => {
+                    return Err(query_error(format!(
+                        "Binary parameters of type {} are not supported",
+                        pg_type
+                    )))
+                }
+            })
+        })
+        .collect()
+}
+
+fn binary_param<T>(portal: &Portal<String>, i: usize, pg_type: &Type) -> PgWireResult<T>
+where
+    T: FromSqlOwned,
+{
+    portal
+        .parameter::<T>(i, pg_type)?
+        .ok_or_else(|| query_error("Unexpected NULL parameter"))
+}
+
+fn compat_to_response(compat: CompatResponse, format: &Format) -> PgWireResult<Response<'static>> {
+    Ok(match compat {
+        CompatResponse::Command(tag) => Response::Execution(Tag::new(tag)),
+        CompatResponse::TransactionStart => Response::TransactionStart(Tag::new("BEGIN")),
+        CompatResponse::TransactionEnd(tag) => Response::TransactionEnd(Tag::new(tag)),
+        CompatResponse::Value { column, value } => {
+            let fields = Arc::new(field_infos(&[(column, Type::VARCHAR)], format));
+            let mut encoder = DataRowEncoder::new(fields.clone());
+            encoder.encode_field(&value)?;
+            let row = encoder.finish();
+            Response::Query(QueryResponse::new(fields, stream::iter(vec![row])))
+        }
+    })
+}
+
+/// A failed query, reported to the client as an ERROR rather than dropping the connection.
+fn query_error(e: impl Display) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "42000".to_owned(),
+        e.to_string(),
+    )))
+}
+
+/// A query refused by access control, as SQLSTATE 42501 (insufficient_privilege).
+fn access_error(e: impl Display) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "42501".to_owned(),
+        e.to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::{Bytes, BytesMut};
+    use arrow::datatypes::{Field, IntervalUnit, TimeUnit as ArrowTimeUnit};
+    use futures::TryStreamExt;
+    use pgwire::api::DefaultClient;
+    use pgwire::messages::extendedquery::Bind;
+    use postgres_types::ToSql;
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    fn config() -> AppConfig {
+        serde_yaml::from_str(
+            r#"
+datasets:
+  - name: trades
+    format: csv
+  - name: quotes
+    format: csv
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+  roles:
+    - {name: desk, datasets: [trades]}
+postgres:
+  users:
+    - {username: alice, password: wonderland, roles: [desk]}
+    - {username: bob, password: builder}
+"#,
+        )
+        .unwrap()
+    }
+
+    fn handlers() -> HydroCubePgHandlers {
+        let pool = memory_pool(2);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE trades (desk VARCHAR, price DOUBLE);
+                 INSERT INTO trades VALUES ('fx', 1.5), ('rates', 2.5);
+                 CREATE TABLE quotes (bid DOUBLE)",
+            )
+            .unwrap();
+        HydroCubePgHandlers::new(pool, &config()).unwrap()
+    }
+
+    fn client(user: &str) -> DefaultClient<String> {
+        let mut client = DefaultClient::new("127.0.0.1:5432".parse().unwrap(), false);
+        client.metadata_mut().insert(METADATA_USER.to_string(), user.to_string());
+        client
+    }
+
+    /// The text-format fields of a data row.
+    fn text_fields(row: &DataRow) -> Vec<Option<String>> {
+        let mut data = &row.data[..];
+        (0..row.field_count)
+            .map(|_| {
+                let (length, rest) = data.split_at(4);
+                let length = i32::from_be_bytes(length.try_into().unwrap());
+                if length < 0 {
+                    data = rest;
+                    return None;
+                }
+                let (value, rest) = rest.split_at(length as usize);
+                data = rest;
+                Some(String::from_utf8(value.to_vec()).unwrap())
+            })
+            .collect()
+    }
+
+    /// Encodes `value` as a one-column text row of `pg_type`.
+    fn encode(pg_type: Type, value: Value) -> Option<String> {
+        let fields = Arc::new(field_infos(&[("c".to_string(), pg_type)], &Format::UnifiedText));
+        let row = encode_row(&fields, vec![value]).unwrap();
+        text_fields(&row).remove(0)
+    }
+
+    async fn query(backend: &HydroCubePgBackend, user: &str, sql: &str) -> PgWireResult<Vec<Vec<Option<String>>>> {
+        let permissions = backend.permissions(&client(user));
+        let response = backend.execute(sql.to_string(), Vec::new(), &Format::UnifiedText, &permissions).await?;
+        let Response::Query(response) = response else {
+            panic!("{} did not return rows", sql);
+        };
+        let rows: Vec<DataRow> = response.data_rows().try_collect().await?;
+        Ok(rows.iter().map(text_fields).collect())
+    }
+
+    #[actix_web::test]
+    async fn passwords_hash_for_known_users_only() {
+        let handlers = handlers();
+        let login = LoginInfo::new(Some("alice"), None, "127.0.0.1".to_string());
+        let password = handlers.users.get_password(&login).await.unwrap();
+        let salt = password.salt().unwrap();
+        assert_eq!(salt.len(), 4);
+        assert_eq!(password.password(), hash_md5_password("alice", "wonderland", salt).as_bytes());
+
+        for user in [Some("mallory"), None] {
+            let login = LoginInfo::new(user, None, "127.0.0.1".to_string());
+            let password = handlers.users.get_password(&login).await.unwrap();
+            assert!(password.salt().is_some());
+            assert!(password.password().is_empty(), "{:?}", user);
+        }
+    }
+
+    #[test]
+    fn maps_arrow_types_to_postgres() {
+        for (data_type, expected) in [
+            (DataType::Boolean, Type::BOOL),
+            (DataType::Int8, Type::INT2),
+            (DataType::Int16, Type::INT2),
+            (DataType::UInt8, Type::INT2),
+            (DataType::Int32, Type::INT4),
+            (DataType::UInt16, Type::INT4),
+            (DataType::Int64, Type::INT8),
+            (DataType::UInt32, Type::INT8),
+            (DataType::UInt64, Type::NUMERIC),
+            (DataType::Decimal128(18, 4), Type::NUMERIC),
+            (DataType::Float32, Type::FLOAT4),
+            (DataType::Float64, Type::FLOAT8),
+            (DataType::Utf8, Type::VARCHAR),
+            (DataType::LargeUtf8, Type::VARCHAR),
+            (DataType::Binary, Type::BYTEA),
+            (DataType::LargeBinary, Type::BYTEA),
+            (DataType::Date32, Type::DATE),
+            (DataType::Time64(ArrowTimeUnit::Microsecond), Type::TIME),
+            (DataType::Timestamp(ArrowTimeUnit::Microsecond, None), Type::TIMESTAMP),
+            (DataType::Timestamp(ArrowTimeUnit::Microsecond, Some("UTC".into())), Type::TIMESTAMPTZ),
+        ] {
+            assert_eq!(pg_type(&data_type), Some(expected), "{:?}", data_type);
+        }
+        for data_type in [
+            DataType::Interval(IntervalUnit::MonthDayNano),
+            DataType::List(Arc::new(Field::new("item", DataType::Int32, true))),
+            DataType::Decimal256(40, 2),
+        ] {
+            assert_eq!(pg_type(&data_type), None, "{:?}", data_type);
+        }
+    }
+
+    #[test]
+    fn encodes_each_value_as_its_postgres_type() {
+        let micros = 1_704_110_400_000_000; // 2024-01-01 12:00:00 UTC
+        for (pg_type, value, expected) in [
+            (Type::BOOL, Value::Boolean(true), "t"),
+            (Type::INT2, Value::TinyInt(-8), "-8"),
+            (Type::INT2, Value::SmallInt(300), "300"),
+            (Type::INT2, Value::UTinyInt(255), "255"),
+            (Type::INT4, Value::Int(-70000), "-70000"),
+            (Type::INT4, Value::USmallInt(65535), "65535"),
+            (Type::INT8, Value::BigInt(1 << 40), "1099511627776"),
+            (Type::INT8, Value::UInt(4_000_000_000), "4000000000"),
+            (Type::NUMERIC, Value::UBigInt(u64::MAX), "18446744073709551615"),
+            (Type::NUMERIC, Value::HugeInt(-12345678901234567890), "-12345678901234567890"),
+            (Type::NUMERIC, Value::Decimal(Decimal::new(12345, 2)), "123.45"),
+            (Type::FLOAT4, Value::Float(1.5), "1.5"),
+            (Type::FLOAT8, Value::Double(-0.25), "-0.25"),
+            (Type::VARCHAR, Value::Text("café".into()), "café"),
+            (Type::VARCHAR, Value::Enum("buy".into()), "buy"),
+            (Type::BYTEA, Value::Blob(vec![0xde, 0xad]), "\\xdead"),
+            (Type::DATE, Value::Date32(19723), "2024-01-01"),
+            (Type::TIME, Value::Time64(TimeUnit::Microsecond, 45_296_500_000), "12:34:56.500000"),
+            (Type::TIMESTAMP, Value::Timestamp(TimeUnit::Microsecond, micros), "2024-01-01 12:00:00.000000"),
+            (Type::TIMESTAMPTZ, Value::Timestamp(TimeUnit::Microsecond, micros), "2024-01-01 12:00:00.000000+00"),
+        ] {
+            assert_eq!(encode(pg_type.clone(), value.clone()).as_deref(), Some(expected), "{:?} as {}", value, pg_type);
+        }
+        assert_eq!(encode(Type::INT4, Value::Null), None);
+    }
+
+    /// A portal binding `parameters`, all in one format (0 text, 1 binary).
+    fn bound(parameters: Vec<(Type, Option<Bytes>)>, format_code: i16) -> Portal<String> {
+        let (parameter_types, parameters) = parameters.into_iter().unzip();
+        let statement = StoredStatement::new(String::new(), "SELECT 1".to_string(), parameter_types);
+        let bind = Bind::new(None, None, vec![format_code], parameters, Vec::new());
+        Portal::try_new(&bind, Arc::new(statement)).unwrap()
+    }
+
+    fn binary(pg_type: &Type, value: &(dyn ToSql + Sync)) -> (Type, Option<Bytes>) {
+        let mut bytes = BytesMut::new();
+        value.to_sql_checked(pg_type, &mut bytes).unwrap();
+        (pg_type.clone(), Some(bytes.freeze()))
+    }
+
+    #[test]
+    fn decodes_binary_parameters_by_their_declared_type() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let timestamp = date.and_hms_opt(3, 4, 5).unwrap();
+        let portal = bound(
+            vec![
+                binary(&Type::BOOL, &true),
+                binary(&Type::INT2, &7i16),
+                binary(&Type::INT4, &-8i32),
+                binary(&Type::INT8, &(1i64 << 40)),
+                binary(&Type::FLOAT4, &1.5f32),
+                binary(&Type::FLOAT8, &2.25f64),
+                binary(&Type::NUMERIC, &Decimal::new(-12345, 3)),
+                binary(&Type::BYTEA, &vec![1u8, 2]),
+                binary(&Type::DATE, &date),
+                binary(&Type::TIMESTAMP, &timestamp),
+                binary(&Type::VARCHAR, &"text"),
+                (Type::INT4, None),
+            ],
+            1,
+        );
+        assert_eq!(
+            portal_params(&portal).unwrap(),
+            vec![
+                Value::Boolean(true),
+                Value::SmallInt(7),
+                Value::Int(-8),
+                Value::BigInt(1 << 40),
+                Value::Float(1.5),
+                Value::Double(2.25),
+                Value::Decimal(Decimal::new(-12345, 3)),
+                Value::Blob(vec![1, 2]),
+                Value::Date32(19724),
+                Value::Timestamp(TimeUnit::Microsecond, timestamp.and_utc().timestamp_micros()),
+                Value::Text("text".to_string()),
+                Value::Null,
+            ]
+        );
+    }
+
+    #[test]
+    fn text_parameters_are_left_for_duckdb_to_cast() {
+        let portal = bound(vec![(Type::INT4, Some(Bytes::from_static(b"42")))], 0);
+        assert_eq!(portal_params(&portal).unwrap(), vec![Value::Text("42".to_string())]);
+
+        let unsupported = bound(vec![binary(&Type::TEXT_ARRAY, &Vec::<String>::new())], 1);
+        assert!(portal_params(&unsupported).is_err());
+    }
+
+    #[actix_web::test]
+    async fn logins_read_what_their_roles_grant() {
+        let handlers = handlers();
+        let backend = &handlers.backend;
+        let rows = query(backend, "alice", "SELECT desk, price FROM trades ORDER BY desk").await.unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![Some("fx".to_string()), Some("1.5".to_string())],
+                vec![Some("rates".to_string()), Some("2.5".to_string())],
+            ]
+        );
+        assert!(query(backend, "alice", "SELECT * FROM quotes").await.is_err());
+        // Logins without roles, and unknown ones, read nothing.
+        assert!(query(backend, "bob", "SELECT * FROM trades").await.is_err());
+        assert!(query(backend, "mallory", "SELECT * FROM trades").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn streams_results_larger_than_a_chunk() {
+        let handlers = handlers();
+        let sql = format!("SELECT range AS n FROM range({})", ROWS_PER_CHUNK * 3 + 5);
+        let rows = query(&handlers.backend, "alice", &sql).await.unwrap();
+        assert_eq!(rows.len(), ROWS_PER_CHUNK * 3 + 5);
+        assert_eq!(rows.last().unwrap()[0].as_deref(), Some("3076"));
+
+        // Queries that fail before their first row are reported up front.
+        assert!(query(&handlers.backend, "alice", "SELECT missing FROM trades").await.is_err());
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use arrow::record_batch::RecordBatch;
+use duckdb::types::Value;
+use duckdb::{params_from_iter, Connection};
+use r2d2::Pool;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+use tokio::task;
+use crate::aggregation::aggregator::LAST_UPDATE_COLUMN;
+use crate::auth::access::Permissions;
+use crate::config::config::AppConfig;
+use crate::db::arrow_encoding::{encode_ipc_stream, encode_json_rows};
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::db::interrupt::QueryInterrupt;
+use crate::ingestion::INSERT_TIMESTAMP_COLUMN;
+use crate::query::sql::quote_identifier;
+use crate::scheduler::schedule::Schedule;
+
+/// How a subscriber wants rows encoded.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Text frames: `{"type", "cube", "key_column", "rows": [...]}`.
+    #[default]
+    Json,
+    /// A JSON text header frame (as above, without `rows`) followed by a
+    /// binary frame holding an Arrow IPC stream.
+    Arrow,
+}
+
+/// A frame queued for a WebSocket connection.
+#[derive(Clone)]
+pub enum Outbound {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Frames a connection may have queued before it counts as fallen behind.
+const OUTBOX_CAPACITY: usize = 256;
+
+/// Where a WebSocket connection's frames are queued.
+///
+/// The queue is bounded: a connection that stops reading until it fills is
+/// told to disconnect rather than buffering updates without limit.
+#[derive(Clone)]
+pub struct Outbox {
+    sender: mpsc::Sender<Outbound>,
+    overflowed: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn new() -> (Self, mpsc::Receiver<Outbound>) {
+        let (sender, receiver) = mpsc::channel(OUTBOX_CAPACITY);
+        let outbox = Outbox {
+            sender,
+            overflowed: Arc::new(Notify::new()),
+        };
+        (outbox, receiver)
+    }
+
+    /// Queues a frame without waiting. Returns false if the connection is
+    /// gone or has fallen behind, in which case `overflowed` completes.
+    pub fn send(&self, frame: Outbound) -> bool {
+        match self.sender.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Completes once a frame was refused because the queue was full.
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// Something clients can subscribe to: a publisher, an aggregate or a dataset.
+struct Cube {
+    table: String,
+    change_column: String,
+    key_column: Option<String>,
+    on_change: bool,
+    /// Held while publishing so two triggers can't send the same delta twice.
+    publish_lock: Mutex<()>,
+}
+
+struct Subscriber {
+    connection: u64,
+    encoding: Encoding,
+    /// What to read the cube's table from: the table itself, or the query
+    /// through which this subscriber's policy lets them see it.
+    source: String,
+    /// Highest change-detection value this subscriber has been sent, as text.
+    /// `None` means it still needs its initial snapshot.
+    watermark: Option<String>,
+    outbox: Outbox,
+}
+
+/// Connection ids and outboxes due the same frames.
+type Targets = Vec<(u64, Outbox)>;
+
+/// A cube's snapshot or delta query in progress.
+struct RunningQuery {
+    /// Connections still subscribed that are due its rows.
+    waiting: HashSet<u64>,
+    interrupt: QueryInterrupt,
+}
+
+/// Tracks WebSocket subscriptions and pushes each cube's changed rows to them.
+///
+/// Every subscriber first gets a snapshot of the cube, then only the rows
+/// whose change-detection column advanced since its last push. Cubes with no
+/// subscribers are never queried, and a query is interrupted once every
+/// subscriber due its rows has gone.
+pub struct PublisherHub {
+    pool: Pool<DuckDBConnectionManager>,
+    cubes: HashMap<String, Cube>,
+    subscribers: Mutex<HashMap<String, Vec<Subscriber>>>,
+    /// By cube; `publish_lock` allows one at a time. Locked after `subscribers`.
+    running: Mutex<HashMap<String, RunningQuery>>,
+    next_connection: AtomicU64,
+}
+
+impl PublisherHub {
+    /// Registers every publisher, aggregate and dataset in the config as a cube.
+    /// Publisher names win over aggregates, which win over datasets.
+    pub fn new(pool: Pool<DuckDBConnectionManager>, config: &AppConfig) -> Self {
+        let mut cubes = HashMap::new();
+
+        for dataset in &config.datasets {
+            cubes.insert(
+                dataset.name.clone(),
+                Cube::new(dataset.table_name(), INSERT_TIMESTAMP_COLUMN, None, true),
+            );
+        }
+        for aggregate in &config.aggregates {
+            cubes.insert(
+                aggregate.name.clone(),
+                Cube::new(&aggregate.name, LAST_UPDATE_COLUMN, None, true),
+            );
+        }
+        for publisher in &config.publishers {
+            let on_change = matches!(publisher.schedule, None | Some(Schedule::OnChange));
+            cubes.insert(
+                publisher.name.clone(),
+                Cube::new(
+                    &publisher.aggregate,
+                    &publisher.change_detection_column,
+                    Some(publisher.key_column.clone()),
+                    on_change,
+                ),
+            );
+        }
+
+        Self {
+            pool,
+            cubes,
+            subscribers: Mutex::new(HashMap::new()),
+            running: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(1),
+        }
+    }
+
+    /// A fresh id for a WebSocket connection.
+    pub fn connection_id(&self) -> u64 {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Subscribes a connection to a cube, which it will see as `permissions`
+    /// allow. The caller should then `publish` the cube so the new subscriber
+    /// receives its snapshot.
+    pub fn subscribe(
+        &self,
+        cube: &str,
+        connection: u64,
+        encoding: Encoding,
+        permissions: &Permissions,
+        outbox: Outbox,
+    ) -> Result<()> {
+        let Some(table) = self.cubes.get(cube).map(|c| &c.table) else {
+            anyhow::bail!("Unknown cube {}", cube);
+        };
+        let source = permissions.source(table);
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let list = subscribers.entry(cube.to_string()).or_default();
+        list.retain(|s| s.connection != connection);
+        list.push(Subscriber {
+            connection,
+            encoding,
+            source,
+            watermark: None,
+            outbox,
+        });
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, cube: &str, connection: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(list) = subscribers.get_mut(cube) {
+            list.retain(|s| s.connection != connection);
+        }
+        self.stop_waiting(Some(cube), connection);
+    }
+
+    /// Drops every subscription held by a closed connection.
+    pub fn unsubscribe_all(&self, connection: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for list in subscribers.values_mut() {
+            list.retain(|s| s.connection != connection);
+        }
+        self.stop_waiting(None, connection);
+    }
+
+    /// Interrupts the running queries of `cube` (or of every cube) that
+    /// nobody but `connection` was waiting for.
+    fn stop_waiting(&self, cube: Option<&str>, connection: u64) {
+        let mut running = self.running.lock().unwrap();
+        for (name, query) in running.iter_mut() {
+            if cube.is_some_and(|cube| cube != name) {
+                continue;
+            }
+            if query.waiting.remove(&connection) && query.waiting.is_empty() {
+                query.interrupt.interrupt();
+            }
+        }
+    }
+
+    /// Runs `query` on `conn` for the connections in `waiting`, interrupting
+    /// it if they all unsubscribe first. `None` if it was interrupted, or
+    /// nobody is waiting any more.
+    fn query_for<T>(
+        &self,
+        cube_name: &str,
+        mut waiting: HashSet<u64>,
+        conn: &Connection,
+        query: impl FnOnce() -> Result<T>,
+    ) -> Result<Option<T>> {
+        let interrupt = QueryInterrupt::default();
+        let _armed = interrupt.arm(conn);
+        {
+            let subscribers = self.subscribers.lock().unwrap();
+            // Some may have left since the publish began.
+            let list = subscribers.get(cube_name).map(Vec::as_slice).unwrap_or_default();
+            waiting.retain(|connection| list.iter().any(|s| s.connection == *connection));
+            if waiting.is_empty() {
+                return Ok(None);
+            }
+            let running = RunningQuery {
+                waiting,
+                interrupt: interrupt.clone(),
+            };
+            self.running.lock().unwrap().insert(cube_name.to_string(), running);
+        }
+
+        let result = query();
+        self.running.lock().unwrap().remove(cube_name);
+        if interrupt.is_interrupted() {
+            return Ok(None);
+        }
+        result.map(Some)
+    }
+
+    /// On-change cubes backed by `table`.
+    fn on_change_cubes_for(&self, table: &str) -> Vec<String> {
+        self.cubes
+            .iter()
+            .filter(|(_, cube)| cube.on_change && cube.table == table)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Pushes a cube's new rows to its subscribers. Blocking: runs DuckDB queries.
+    ///
+    /// Subscribers are grouped by watermark and source so each distinct delta
+    /// is queried and encoded once. Deltas are bounded above by the current maximum, so
+    /// rows landing mid-publish are sent exactly once, on the next push.
+    pub fn publish(&self, cube_name: &str) -> Result<()> {
+        let cube = self
+            .cubes
+            .get(cube_name)
+            .with_context(|| format!("Unknown cube {}", cube_name))?;
+        let _guard = cube.publish_lock.lock().unwrap();
+
+        // Snapshot who needs what, without holding the lock across queries.
+        let mut groups: HashMap<(Option<String>, String, Encoding), Targets> = HashMap::new();
+        {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let Some(list) = subscribers.get_mut(cube_name) else {
+                return Ok(());
+            };
+            list.retain(|s| !s.outbox.is_closed());
+            if list.is_empty() {
+                return Ok(());
+            }
+            for s in list.iter() {
+                groups
+                    .entry((s.watermark.clone(), s.source.clone(), s.encoding))
+                    .or_default()
+                    .push((s.connection, s.outbox.clone()));
+            }
+        }
+
+        let conn = self.pool.get()?;
+        let high_mark = current_high_mark(&conn, cube)?;
+
+        // Who is due each distinct query's rows, whatever their encoding.
+        let mut waiting_by_query: HashMap<(Option<String>, String), HashSet<u64>> = HashMap::new();
+        for ((watermark, source, _), targets) in &groups {
+            waiting_by_query
+                .entry((watermark.clone(), source.clone()))
+                .or_default()
+                .extend(targets.iter().map(|(id, _)| *id));
+        }
+
+        let mut delivered: HashSet<u64> = HashSet::new();
+        let mut batches_by_query: HashMap<(Option<String>, String), Vec<RecordBatch>> = HashMap::new();
+        for ((watermark, source, encoding), targets) in groups {
+            let is_snapshot = watermark.is_none();
+            if !is_snapshot && watermark == high_mark {
+                continue;
+            }
+
+            let query = (watermark, source);
+            if !batches_by_query.contains_key(&query) {
+                let waiting = waiting_by_query.remove(&query).unwrap_or_default();
+                let batches = self.query_for(cube_name, waiting, &conn, || {
+                    query_changes(&conn, cube, &query.1, query.0.as_deref(), high_mark.as_deref())
+                })?;
+                let Some(batches) = batches else {
+                    // Everyone due these rows has gone; their groups are skipped too.
+                    batches_by_query.insert(query, Vec::new());
+                    continue;
+                };
+                batches_by_query.insert(query.clone(), batches);
+            }
+            let batches = &batches_by_query[&query];
+            let row_count: usize = batches.iter().map(|b| b.num_rows()).sum();
+            if !is_snapshot && row_count == 0 {
+                delivered.extend(targets.iter().map(|(id, _)| *id));
+                continue;
+            }
+
+            let kind = if is_snapshot { "snapshot" } else { "delta" };
+            let frames = encode_frames(cube_name, cube, kind, encoding, batches)?;
+            for (connection, outbox) in targets {
+                let sent = frames.iter().all(|frame| outbox.send(frame.clone()));
+                if sent {
+                    delivered.insert(connection);
+                }
+            }
+        }
+
+        // Advance watermarks for everyone who got their rows.
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(list) = subscribers.get_mut(cube_name) {
+            for s in list.iter_mut().filter(|s| delivered.contains(&s.connection)) {
+                s.watermark = high_mark.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cube {
+    fn new(table: &str, change_column: &str, key_column: Option<String>, on_change: bool) -> Self {
+        Self {
+            table: table.to_string(),
+            change_column: change_column.to_string(),
+            key_column,
+            on_change,
+            publish_lock: Mutex::new(()),
+        }
+    }
+}
+
+/// Publishes on-change cubes whenever a table they're backed by changes.
+/// `changed` carries table names from ingestion and aggregate refreshes.
+pub async fn publish_on_change(
+    hub: Arc<PublisherHub>,
+    mut changed: broadcast::Receiver<String>,
+) -> Result<()> {
+    loop {
+        let table = match changed.recv().await {
+            Ok(table) => table,
+            Err(RecvError::Lagged(skipped)) => {
+                // Missed notifications are fine: the next publish catches up
+                // from each subscriber's watermark anyway.
+                eprintln!("Publisher lagged behind {} change notifications", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        for cube in hub.on_change_cubes_for(&table) {
+            let hub_clone = hub.clone();
+            let cube_clone = cube.clone();
+            match task::spawn_blocking(move || hub_clone.publish(&cube_clone)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Error publishing {}: {:?}", cube, e),
+                Err(e) => eprintln!("Publish task for {} failed: {:?}", cube, e),
+            }
+        }
+    }
+}
+
+/// The cube's current maximum change-detection value, as text, or `None` if
+/// the table is empty or doesn't exist yet.
+fn current_high_mark(conn: &Connection, cube: &Cube) -> Result<Option<String>> {
+    let sql = format!(
+        "SELECT CAST(MAX({}) AS VARCHAR) FROM {}",
+        quote_identifier(&cube.change_column),
+        quote_identifier(&cube.table)
+    );
+    match conn.query_row(&sql, [], |row| row.get::<_, Option<String>>(0)) {
+        Ok(mark) => Ok(mark),
+        Err(e) if e.to_string().contains("does not exist") => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Rows of `source` with a change-detection value in `(from, to]`, or every
+/// row up to `to` when `from` is `None` (a snapshot).
+fn query_changes(
+    conn: &Connection,
+    cube: &Cube,
+    source: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<RecordBatch>> {
+    let column = quote_identifier(&cube.change_column);
+    let (filter, bounds): (String, Vec<Value>) = match (from, to) {
+        (None, None) => (String::new(), vec![]),
+        // Had rows before, table is empty now: nothing new to send.
+        (Some(_), None) => return Ok(Vec::new()),
+        (None, Some(to)) => (
+            format!(" WHERE {0} IS NULL OR {0} <= ?", column),
+            vec![Value::Text(to.to_string())],
+        ),
+        (Some(from), Some(to)) => (
+            format!(" WHERE {0} > ? AND {0} <= ?", column),
+            vec![Value::Text(from.to_string()), Value::Text(to.to_string())],
+        ),
+    };
+
+    let sql = format!("SELECT * FROM {}{}", source, filter);
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(stmt) => stmt,
+        // Snapshot of a table that doesn't exist yet: nothing to send.
+        Err(e) if e.to_string().contains("does not exist") => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let batches = stmt.query_arrow(params_from_iter(bounds))?.collect();
+    Ok(batches)
+}
+
+fn encode_frames(
+    cube_name: &str,
+    cube: &Cube,
+    kind: &str,
+    encoding: Encoding,
+    batches: &[RecordBatch],
+) -> Result<Vec<Outbound>> {
+    let mut header = json!({
+        "type": kind,
+        "cube": cube_name,
+        "key_column": cube.key_column,
+    });
+
+    match encoding {
+        Encoding::Json => {
+            let rows: serde_json::Value = serde_json::from_slice(&encode_json_rows(batches)?)?;
+            header["rows"] = rows;
+            Ok(vec![Outbound::Text(header.to_string())])
+        }
+        Encoding::Arrow => {
+            header["format"] = json!("arrow");
+            let Some(first) = batches.first() else {
+                // No batches means no schema to describe; send the header alone.
+                return Ok(vec![Outbound::Text(header.to_string())]);
+            };
+            let bytes = encode_ipc_stream(first.schema().as_ref(), batches)?;
+            Ok(vec![
+                Outbound::Text(header.to_string()),
+                Outbound::Binary(bytes),
+            ])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value as JsonValue};
+    use tokio::sync::mpsc::error::TryRecvError;
+    use super::*;
+    use crate::auth::access::AccessControl;
+    use crate::auth::identity::Identity;
+    use crate::db::db_pool::tests::memory_pool;
+
+    /// A `trades` dataset whose policy shows only `A` rows, except to traders.
+    fn config() -> AppConfig {
+        serde_yaml::from_str(
+            r#"
+datasets:
+  - name: trades
+    format: csv
+    policy:
+      row_filter: "sym = 'A'"
+      exempt_roles: [trader]
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+"#,
+        )
+        .unwrap()
+    }
+
+    fn hub() -> PublisherHub {
+        let pool = memory_pool(2);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE trades (sym VARCHAR, price DOUBLE, insert_timestamp TIMESTAMPTZ);
+                 INSERT INTO trades VALUES
+                    ('A', 1, '2024-01-01 00:00:01+00'),
+                    ('B', 2, '2024-01-01 00:00:02+00')",
+            )
+            .unwrap();
+        PublisherHub::new(pool, &config())
+    }
+
+    fn permissions(roles: &[&str]) -> Permissions {
+        let identity = Identity {
+            subject: "alice".into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            claims: Map::new(),
+        };
+        AccessControl::new(&config()).unwrap().permissions(Some(&identity))
+    }
+
+    fn insert(hub: &PublisherHub, values: &str) {
+        let sql = format!("INSERT INTO trades VALUES {}", values);
+        hub.pool.get().unwrap().execute_batch(&sql).unwrap();
+    }
+
+    /// The frames queued so far, parsed if they are text.
+    fn frames(rx: &mut mpsc::Receiver<Outbound>) -> Vec<Result<JsonValue, usize>> {
+        let mut frames = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(Outbound::Text(text)) => frames.push(Ok(serde_json::from_str(&text).unwrap())),
+                Ok(Outbound::Binary(bytes)) => frames.push(Err(bytes.len())),
+                Err(TryRecvError::Empty) => return frames,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    fn symbols(frame: &JsonValue) -> Vec<&str> {
+        frame["rows"].as_array().unwrap().iter().map(|row| row["sym"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn sends_a_snapshot_then_only_new_rows() {
+        let hub = hub();
+        let (outbox, mut rx) = Outbox::new();
+        hub.subscribe("trades", 1, Encoding::Json, &permissions(&["trader"]), outbox).unwrap();
+
+        hub.publish("trades").unwrap();
+        let snapshot = frames(&mut rx);
+        assert_eq!(snapshot.len(), 1);
+        let snapshot = snapshot[0].as_ref().unwrap();
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["cube"], "trades");
+        assert_eq!(symbols(snapshot), vec!["A", "B"]);
+
+        insert(&hub, "('C', 3, '2024-01-01 00:00:03+00')");
+        hub.publish("trades").unwrap();
+        let delta = frames(&mut rx);
+        assert_eq!(delta.len(), 1);
+        let delta = delta[0].as_ref().unwrap();
+        assert_eq!(delta["type"], "delta");
+        assert_eq!(symbols(delta), vec!["C"]);
+
+        // Nothing changed, so nothing is sent.
+        hub.publish("trades").unwrap();
+        assert!(frames(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn subscribers_share_a_query_but_not_a_policy() {
+        let hub = hub();
+        let (json, mut json_rx) = Outbox::new();
+        let (arrow, mut arrow_rx) = Outbox::new();
+        let (filtered, mut filtered_rx) = Outbox::new();
+        hub.subscribe("trades", 1, Encoding::Json, &permissions(&["trader"]), json).unwrap();
+        hub.subscribe("trades", 2, Encoding::Arrow, &permissions(&["trader"]), arrow).unwrap();
+        hub.subscribe("trades", 3, Encoding::Json, &permissions(&[]), filtered).unwrap();
+
+        hub.publish("trades").unwrap();
+        assert_eq!(symbols(frames(&mut json_rx)[0].as_ref().unwrap()), vec!["A", "B"]);
+        let arrow_frames = frames(&mut arrow_rx);
+        assert_eq!(arrow_frames.len(), 2);
+        assert_eq!(arrow_frames[0].as_ref().unwrap()["format"], "arrow");
+        assert!(matches!(arrow_frames[1], Err(len) if len > 0));
+        assert_eq!(symbols(frames(&mut filtered_rx)[0].as_ref().unwrap()), vec!["A"]);
+
+        // A new B row is a delta for traders only; the watermark still advances
+        // for the filtered subscriber, who isn't sent an empty delta.
+        insert(&hub, "('B', 4, '2024-01-01 00:00:04+00')");
+        hub.publish("trades").unwrap();
+        assert_eq!(symbols(frames(&mut json_rx)[0].as_ref().unwrap()), vec!["B"]);
+        assert_eq!(frames(&mut arrow_rx).len(), 2);
+        assert!(frames(&mut filtered_rx).is_empty());
+
+        insert(&hub, "('A', 5, '2024-01-01 00:00:05+00')");
+        hub.publish("trades").unwrap();
+        assert_eq!(symbols(frames(&mut filtered_rx)[0].as_ref().unwrap()), vec!["A"]);
+    }
+
+    #[test]
+    fn high_mark_and_changes_of_a_missing_or_empty_table() {
+        let hub = hub();
+        let conn = hub.pool.get().unwrap();
+        let cube = Cube::new("trades", INSERT_TIMESTAMP_COLUMN, None, true);
+        assert_eq!(
+            current_high_mark(&conn, &cube).unwrap().as_deref(),
+            Some("2024-01-01 00:00:02+00")
+        );
+
+        // Rows without a change-detection value are part of the snapshot.
+        conn.execute_batch("INSERT INTO trades VALUES ('N', 0, NULL)").unwrap();
+        let rows = |from: Option<&str>, to: Option<&str>| -> usize {
+            let batches = query_changes(&conn, &cube, "trades", from, to).unwrap();
+            batches.iter().map(|b| b.num_rows()).sum()
+        };
+        assert_eq!(rows(None, Some("2024-01-01 00:00:02+00")), 3);
+        assert_eq!(rows(Some("2024-01-01 00:00:01+00"), Some("2024-01-01 00:00:02+00")), 1);
+        assert_eq!(rows(Some("2024-01-01 00:00:02+00"), None), 0);
+
+        let missing = Cube::new("missing", INSERT_TIMESTAMP_COLUMN, None, true);
+        assert_eq!(current_high_mark(&conn, &missing).unwrap(), None);
+        assert!(query_changes(&conn, &missing, "missing", None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_full_outbox_asks_for_a_disconnect() {
+        let (outbox, _rx) = Outbox::new();
+        for _ in 0..OUTBOX_CAPACITY {
+            assert!(outbox.send(Outbound::Text(String::new())));
+        }
+        assert!(!outbox.send(Outbound::Text(String::new())));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            tokio::time::timeout(std::time::Duration::from_secs(1), outbox.overflowed())
+                .await
+                .expect("overflow wasn't signalled");
+        });
+    }
+}
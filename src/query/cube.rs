@@ -0,0 +1,385 @@
+use anyhow::{bail, Result};
+use duckdb::types::Value;
+use serde::Deserialize;
+use crate::aggregation::aggregator::{measure_alias, measure_sql};
+use crate::config::config::{AggregateFunction, MeasureConfig};
+use crate::query::filter::compile_filter;
+use crate::query::sql::{order_by_clause, quote_identifier, resolve_column};
+
+/// Name of the column added to rolled-up results: a bitmask of which
+/// dimensions were aggregated away in that row, as returned by DuckDB's
+/// `GROUPING()`. Zero marks a fully grouped row, all bits a grand total.
+pub const GROUPING_ID_COLUMN: &str = "grouping_id";
+
+/// Body of `POST /api/cube/{dataset}`.
+///
+/// ```json
+/// {
+///   "dimensions": ["region", "product_id"],
+///   "measures": [{"column": "quantity", "function": "sum"}],
+///   "filter": "price > 10",
+///   "grouping": "rollup",
+///   "order_by": "region, product_id",
+///   "limit": 1000
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct CubeRequest {
+    #[serde(default)]
+    pub dimensions: Vec<String>,
+    #[serde(default)]
+    pub measures: Vec<MeasureConfig>,
+    /// A predicate over the base table, in the `/api/data/arrow` filter grammar.
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// How to group; a plain `GROUP BY` of all dimensions if absent.
+    #[serde(default)]
+    pub grouping: Option<Grouping>,
+    /// Comma-separated output columns, each optionally followed by `asc` or `desc`.
+    #[serde(default)]
+    pub order_by: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Grouping {
+    /// `"rollup"`: subtotals from the last dimension up to a grand total.
+    Rollup,
+    /// `"cube"`: subtotals for every combination of dimensions.
+    Cube,
+    /// `{"grouping_sets": [["region"], ["product_id"], []]}`
+    #[serde(rename = "grouping_sets")]
+    Sets(Vec<Vec<String>>),
+}
+
+impl CubeRequest {
+    /// Compiles the request into a DuckDB query over `source` (a quoted
+    /// table, or a subquery with an alias), checking every column against
+    /// `column_types` (name and DuckDB type) and every measure's function
+    /// against its column's type. Filter values are returned as bound
+    /// parameters.
+    pub fn to_sql(
+        &self,
+        source: &str,
+        column_types: &[(String, String)],
+    ) -> Result<(String, Vec<Value>)> {
+        if self.dimensions.is_empty() && self.measures.is_empty() {
+            bail!("A cube request needs at least one dimension or measure");
+        }
+        let columns: Vec<String> = column_types.iter().map(|(name, _)| name.clone()).collect();
+        let columns = columns.as_slice();
+
+        let dimensions = self
+            .dimensions
+            .iter()
+            .map(|d| resolve_column(columns, d).map(quote_identifier))
+            .collect::<Result<Vec<_>>>()?;
+
+        let measures = self.resolved_measures(column_types)?;
+
+        let mut select_list = dimensions.clone();
+        select_list.extend(measures.iter().map(measure_sql));
+
+        let group_by = match &self.grouping {
+            _ if dimensions.is_empty() => None,
+            None => Some(dimensions.join(", ")),
+            Some(Grouping::Rollup) => Some(format!("ROLLUP ({})", dimensions.join(", "))),
+            Some(Grouping::Cube) => Some(format!("CUBE ({})", dimensions.join(", "))),
+            Some(Grouping::Sets(sets)) => {
+                let sets = sets
+                    .iter()
+                    .map(|set| {
+                        let set = set
+                            .iter()
+                            .map(|name| self.grouping_set_column(columns, name))
+                            .collect::<Result<Vec<_>>>()?;
+                        Ok(format!("({})", set.join(", ")))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if sets.is_empty() {
+                    bail!("grouping_sets must list at least one set");
+                }
+                Some(format!("GROUPING SETS ({})", sets.join(", ")))
+            }
+        };
+        if self.grouping.is_some() && !dimensions.is_empty() {
+            select_list.push(format!(
+                "GROUPING({}) AS {}",
+                dimensions.join(", "),
+                quote_identifier(GROUPING_ID_COLUMN)
+            ));
+        }
+
+        let mut sql = format!(
+            "SELECT {} FROM {}",
+            select_list.join(", "),
+            source
+        );
+        let mut params = Vec::new();
+
+        if let Some(filter) = self.filter.as_deref().filter(|f| !f.trim().is_empty()) {
+            let (predicate, filter_params) = compile_filter(filter, columns)?;
+            sql.push_str(&format!(" WHERE {}", predicate));
+            params = filter_params;
+        }
+        if let Some(group_by) = group_by {
+            sql.push_str(&format!(" GROUP BY {}", group_by));
+        }
+        if let Some(order_by) = self.order_by.as_deref().filter(|o| !o.trim().is_empty()) {
+            let outputs = self.output_columns(columns, &measures)?;
+            sql.push_str(&format!(" ORDER BY {}", order_by_clause(order_by, &outputs)?));
+        }
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        Ok((sql, params))
+    }
+
+    /// The measures with their columns checked and spelled as in the table.
+    fn resolved_measures(&self, column_types: &[(String, String)]) -> Result<Vec<MeasureConfig>> {
+        let columns: Vec<String> = column_types.iter().map(|(name, _)| name.clone()).collect();
+        self.measures
+            .iter()
+            .map(|measure| {
+                let column = if measure.column == "*" {
+                    if !matches!(measure.function, AggregateFunction::Count) {
+                        bail!("Only count can be applied to *");
+                    }
+                    "*".to_string()
+                } else {
+                    let column = resolve_column(&columns, &measure.column)?;
+                    let data_type = column_types
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .map_or("", |(_, data_type)| data_type.as_str());
+                    if !accepts(measure.function, data_type) {
+                        bail!(
+                            "{} cannot be applied to column {} of type {}",
+                            format!("{:?}", measure.function).to_lowercase(),
+                            column,
+                            data_type
+                        );
+                    }
+                    column.to_string()
+                };
+                Ok(MeasureConfig {
+                    column,
+                    ..measure.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// A grouping set may only use the request's dimensions, since those are
+    /// the only columns selected.
+    fn grouping_set_column(&self, columns: &[String], name: &str) -> Result<String> {
+        let column = resolve_column(columns, name)?;
+        if !self
+            .dimensions
+            .iter()
+            .any(|d| resolve_column(columns, d).ok() == Some(column))
+        {
+            bail!("Grouping set column {} is not one of the dimensions", name);
+        }
+        Ok(quote_identifier(column))
+    }
+
+    /// Names of the result's columns, which is what `order_by` may refer to.
+    fn output_columns(
+        &self,
+        columns: &[String],
+        measures: &[MeasureConfig],
+    ) -> Result<Vec<String>> {
+        let mut outputs = self
+            .dimensions
+            .iter()
+            .map(|d| resolve_column(columns, d).map(str::to_string))
+            .collect::<Result<Vec<_>>>()?;
+        outputs.extend(measures.iter().map(measure_alias));
+        if self.grouping.is_some() && !self.dimensions.is_empty() {
+            outputs.push(GROUPING_ID_COLUMN.to_string());
+        }
+        Ok(outputs)
+    }
+}
+
+/// Whether DuckDB can aggregate a column of `data_type` with `function`.
+/// `COUNT`, `MIN` and `MAX` take anything; `SUM` and `AVG` need numbers (or
+/// intervals, and for `AVG` times).
+fn accepts(function: AggregateFunction, data_type: &str) -> bool {
+    let base = data_type
+        .split('(')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_uppercase();
+    let numeric = matches!(
+        base.as_str(),
+        "TINYINT" | "SMALLINT" | "INTEGER" | "BIGINT" | "HUGEINT"
+            | "UTINYINT" | "USMALLINT" | "UINTEGER" | "UBIGINT" | "UHUGEINT"
+            | "FLOAT" | "DOUBLE" | "DECIMAL" | "INTERVAL"
+    );
+    match function {
+        AggregateFunction::Count | AggregateFunction::Min | AggregateFunction::Max => true,
+        AggregateFunction::Sum => numeric || base == "BOOLEAN",
+        AggregateFunction::Avg => numeric || base == "TIME" || base.starts_with("TIMESTAMP"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use duckdb::{params_from_iter, Connection};
+    use serde_json::json;
+    use super::*;
+
+    fn request(body: serde_json::Value) -> CubeRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn sales_columns() -> Vec<(String, String)> {
+        [("region", "VARCHAR"), ("product", "VARCHAR"), ("qty", "INTEGER"), ("price", "DECIMAL(18,4)")]
+            .map(|(name, data_type)| (name.to_string(), data_type.to_string()))
+            .to_vec()
+    }
+
+    fn to_sql(body: serde_json::Value) -> Result<String> {
+        request(body).to_sql("\"sales\"", &sales_columns()).map(|(sql, _)| sql)
+    }
+
+    #[test]
+    fn compiles_each_grouping() {
+        let base = json!({"dimensions": ["region", "product"], "measures": [{"column": "qty", "function": "sum"}]});
+        let with = |grouping: serde_json::Value| {
+            let mut body = base.clone();
+            body["grouping"] = grouping;
+            to_sql(body).unwrap()
+        };
+        let select = r#"SELECT "region", "product", SUM("qty") AS "sum_qty""#;
+        let grouping_id = r#"GROUPING("region", "product") AS "grouping_id""#;
+
+        assert_eq!(
+            to_sql(base.clone()).unwrap(),
+            format!(r#"{} FROM "sales" GROUP BY "region", "product""#, select)
+        );
+        assert_eq!(
+            with(json!("rollup")),
+            format!(r#"{}, {} FROM "sales" GROUP BY ROLLUP ("region", "product")"#, select, grouping_id)
+        );
+        assert_eq!(
+            with(json!("cube")),
+            format!(r#"{}, {} FROM "sales" GROUP BY CUBE ("region", "product")"#, select, grouping_id)
+        );
+        assert_eq!(
+            with(json!({"grouping_sets": [["region"], ["PRODUCT"], []]})),
+            format!(
+                r#"{}, {} FROM "sales" GROUP BY GROUPING SETS (("region"), ("product"), ())"#,
+                select, grouping_id
+            )
+        );
+    }
+
+    #[test]
+    fn binds_filter_values_and_orders_by_output_aliases() {
+        let (sql, params) = request(json!({
+            "dimensions": ["region"],
+            "measures": [{"column": "qty", "function": "sum"}, {"column": "*", "function": "count", "alias": "n"}],
+            "filter": "price > 10",
+            "grouping": "rollup",
+            "order_by": "n desc, grouping_id, sum_qty",
+            "limit": 5
+        }))
+        .to_sql("\"sales\"", &sales_columns())
+        .unwrap();
+        assert!(
+            sql.ends_with(r#"GROUP BY ROLLUP ("region") ORDER BY "n" DESC, "grouping_id" ASC, "sum_qty" ASC LIMIT 5"#),
+            "{}",
+            sql
+        );
+        assert!(!sql.contains("10"), "{}", sql);
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_and_mistyped_columns() {
+        let measure = json!([{"column": "qty", "function": "sum"}]);
+        for (body, error) in [
+            (json!({}), "at least one dimension or measure"),
+            (json!({"dimensions": ["country"], "measures": measure}), "Unknown column country"),
+            (json!({"measures": [{"column": "cost", "function": "sum"}]}), "Unknown column cost"),
+            (json!({"measures": [{"column": "region", "function": "sum"}]}), "sum cannot be applied to column region"),
+            (json!({"measures": [{"column": "*", "function": "max"}]}), "Only count can be applied to *"),
+            (
+                json!({"dimensions": ["region"], "measures": measure, "grouping": {"grouping_sets": [["product"]]}}),
+                "not one of the dimensions",
+            ),
+            (
+                json!({"dimensions": ["region"], "measures": measure, "grouping": {"grouping_sets": []}}),
+                "at least one set",
+            ),
+            // Ordering is by output columns, not base columns.
+            (json!({"dimensions": ["region"], "measures": measure, "order_by": "qty"}), "Unknown column qty"),
+            (json!({"dimensions": ["region"], "measures": measure, "order_by": "grouping_id"}), "Unknown column grouping_id"),
+            (json!({"dimensions": ["region"], "measures": measure, "order_by": "region sideways"}), "Invalid sort direction"),
+        ] {
+            let message = to_sql(body.clone()).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", body, message);
+        }
+    }
+
+    #[test]
+    fn accepts_functions_by_column_type() {
+        use AggregateFunction::*;
+        for data_type in ["INTEGER", "DECIMAL(18,4)", "double", "INTERVAL"] {
+            assert!(accepts(Sum, data_type) && accepts(Avg, data_type), "{}", data_type);
+        }
+        assert!(accepts(Sum, "BOOLEAN") && !accepts(Avg, "BOOLEAN"));
+        assert!(accepts(Avg, "TIMESTAMP WITH TIME ZONE") && !accepts(Sum, "TIMESTAMP"));
+        for data_type in ["VARCHAR", "DATE", "INTEGER[]", ""] {
+            assert!(!accepts(Sum, data_type) && !accepts(Avg, data_type), "{}", data_type);
+            assert!(accepts(Count, data_type) && accepts(Min, data_type) && accepts(Max, data_type));
+        }
+    }
+
+    #[test]
+    fn generated_sql_runs_in_duckdb() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sales (region VARCHAR, product VARCHAR, qty INTEGER, price DECIMAL(18,4));
+             INSERT INTO sales VALUES ('EU', 'x', 1, 20), ('EU', 'y', 2, 30), ('US', 'x', 4, 5), ('US', 'y', 8, 50);",
+        )
+        .unwrap();
+        let (sql, params) = request(json!({
+            "dimensions": ["region", "product"],
+            "measures": [{"column": "qty", "function": "sum"}],
+            "filter": "price > 10",
+            "grouping": "rollup",
+            "order_by": "grouping_id, region, product"
+        }))
+        .to_sql("\"sales\"", &sales_columns())
+        .unwrap();
+
+        let mut stmt = conn.prepare(&sql).unwrap();
+        let rows: Vec<(Option<String>, Option<String>, i64, i64)> = stmt
+            .query_map(params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let row = |region: Option<&str>, product: Option<&str>, qty, grouping_id| {
+            (region.map(String::from), product.map(String::from), qty, grouping_id)
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row(Some("EU"), Some("x"), 1, 0),
+                row(Some("EU"), Some("y"), 2, 0),
+                row(Some("US"), Some("y"), 8, 0),
+                row(Some("EU"), None, 3, 1),
+                row(Some("US"), None, 8, 1),
+                row(None, None, 11, 3),
+            ]
+        );
+    }
+}
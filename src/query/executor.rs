@@ -0,0 +1,32 @@
+use anyhow::Result;
+use arrow::datatypes::Schema;
+use arrow::record_batch::RecordBatch;
+use duckdb::{params_from_iter, Connection};
+use serde_json::Value as JsonValue;
+use crate::db::json_value::to_duckdb_value;
+use crate::query::read_only::ensure_read_only;
+
+/// Runs a user-supplied read-only query with positional (`?`) parameters.
+///
+/// The statement is checked by [`ensure_read_only`] first and then runs
+/// inside a transaction that is always rolled back, so even a statement that
+/// slips past the check cannot leave changes behind on the pooled connection.
+pub fn run_read_only_query(
+    conn: &mut Connection,
+    sql: &str,
+    params: &[JsonValue],
+) -> Result<(Schema, Vec<RecordBatch>)> {
+    ensure_read_only(sql)?;
+
+    let tx = conn.transaction()?;
+    let result = {
+        let mut stmt = tx.prepare(sql)?;
+        let values = params.iter().map(|p| to_duckdb_value(Some(p)));
+        let arrow = stmt.query_arrow(params_from_iter(values))?;
+        let schema = arrow.get_schema();
+        let batches: Vec<RecordBatch> = arrow.collect();
+        (schema.as_ref().clone(), batches)
+    };
+    tx.rollback()?;
+    Ok(result)
+}
@@ -0,0 +1,406 @@
+use anyhow::{bail, Result};
+use duckdb::types::Value;
+use crate::query::sql::{quote_identifier, resolve_column};
+
+/// Compiles a filter expression into a SQL predicate with `?` placeholders.
+///
+/// The grammar is deliberately small:
+///
+/// ```text
+/// expr       := and_expr ( OR and_expr )*
+/// and_expr   := not_expr ( AND not_expr )*
+/// not_expr   := NOT not_expr | '(' expr ')' | comparison
+/// comparison := column ( '=' | '!=' | '<>' | '<' | '<=' | '>' | '>=' ) literal
+///             | column [NOT] LIKE string
+///             | column [NOT] IN '(' literal ( ',' literal )* ')'
+///             | column BETWEEN literal AND literal
+///             | column IS [NOT] NULL
+/// literal    := number | 'string' | TRUE | FALSE | NULL
+/// ```
+///
+/// Columns must be in `columns` and are emitted quoted; literals are never
+/// written into the SQL but returned as bound parameters.
+pub fn compile_filter(filter: &str, columns: &[String]) -> Result<(String, Vec<Value>)> {
+    let tokens = tokenize(filter)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        columns,
+        params: Vec::new(),
+    };
+    let sql = parser.expression()?;
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected {} in filter", token.describe());
+    }
+    Ok((sql, parser.params))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Number(String),
+    Operator(&'static str),
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(w) => format!("'{}'", w),
+            Token::Quoted(s) => format!("string '{}'", s),
+            Token::Number(n) => format!("number {}", n),
+            Token::Operator(op) => format!("'{}'", op),
+            Token::OpenParen => "'('".to_string(),
+            Token::CloseParen => "')'".to_string(),
+            Token::Comma => "','".to_string(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+}
+
+const OPERATORS: &[&str] = &["<=", ">=", "!=", "<>", "=", "<", ">"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::OpenParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::CloseParen);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            // 'text' is a string, "name" a column; a doubled quote escapes itself.
+            let quote = c;
+            let mut content = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("Unterminated quote in filter"),
+                    Some(&q) if q == quote && chars.get(i + 1) == Some(&quote) => {
+                        content.push(q);
+                        i += 2;
+                    }
+                    Some(&q) if q == quote => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&other) => {
+                        content.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(if quote == '"' {
+                Token::Word(content)
+            } else {
+                Token::Quoted(content)
+            });
+        } else if c.is_ascii_digit()
+            || (c == '-' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit()))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push(Token::Operator(op));
+                    i += op.len();
+                }
+                None => bail!("Unexpected character '{}' in filter", c),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    columns: &'a [String],
+    params: Vec<Value>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token.ok_or_else(|| anyhow::anyhow!("Filter ends unexpectedly"))
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_keyword(keyword)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if !self.accept_keyword(keyword) {
+            bail!("Expected {} in filter", keyword);
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        let token = self.next()?;
+        if token != expected {
+            bail!("Expected {} but found {} in filter", expected.describe(), token.describe());
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<String> {
+        let mut sql = self.and_expression()?;
+        while self.accept_keyword("OR") {
+            sql = format!("{} OR {}", sql, self.and_expression()?);
+        }
+        Ok(sql)
+    }
+
+    fn and_expression(&mut self) -> Result<String> {
+        let mut sql = self.not_expression()?;
+        while self.accept_keyword("AND") {
+            sql = format!("{} AND {}", sql, self.not_expression()?);
+        }
+        Ok(sql)
+    }
+
+    fn not_expression(&mut self) -> Result<String> {
+        if self.accept_keyword("NOT") {
+            return Ok(format!("NOT {}", self.not_expression()?));
+        }
+        if self.peek() == Some(&Token::OpenParen) {
+            self.position += 1;
+            let inner = self.expression()?;
+            self.expect(Token::CloseParen)?;
+            return Ok(format!("({})", inner));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<String> {
+        let column = match self.next()? {
+            Token::Word(name) => quote_identifier(resolve_column(self.columns, &name)?),
+            other => bail!("Expected a column but found {} in filter", other.describe()),
+        };
+
+        if let Some(Token::Operator(op)) = self.peek().cloned() {
+            self.position += 1;
+            self.literal()?;
+            return Ok(format!("{} {} ?", column, op));
+        }
+        if self.accept_keyword("IS") {
+            let negated = if self.accept_keyword("NOT") { " NOT" } else { "" };
+            self.expect_keyword("NULL")?;
+            return Ok(format!("{} IS{} NULL", column, negated));
+        }
+        if self.accept_keyword("BETWEEN") {
+            self.literal()?;
+            self.expect_keyword("AND")?;
+            self.literal()?;
+            return Ok(format!("{} BETWEEN ? AND ?", column));
+        }
+
+        let negated = if self.accept_keyword("NOT") { "NOT " } else { "" };
+        if self.accept_keyword("LIKE") {
+            match self.next()? {
+                Token::Quoted(pattern) => self.params.push(Value::Text(pattern)),
+                other => bail!("LIKE needs a string but found {} in filter", other.describe()),
+            }
+            return Ok(format!("{} {}LIKE ?", column, negated));
+        }
+        if self.accept_keyword("IN") {
+            self.expect(Token::OpenParen)?;
+            let mut placeholders = vec!["?"];
+            self.literal()?;
+            while self.peek() == Some(&Token::Comma) {
+                self.position += 1;
+                self.literal()?;
+                placeholders.push("?");
+            }
+            self.expect(Token::CloseParen)?;
+            return Ok(format!("{} {}IN ({})", column, negated, placeholders.join(", ")));
+        }
+
+        match self.peek() {
+            Some(token) => bail!("Expected an operator but found {} in filter", token.describe()),
+            None => bail!("Filter ends unexpectedly"),
+        }
+    }
+
+    /// Consumes a literal and records it as the next bound parameter.
+    fn literal(&mut self) -> Result<()> {
+        let value = match self.next()? {
+            Token::Quoted(text) => Value::Text(text),
+            Token::Number(number) => match number.parse::<i64>() {
+                Ok(i) => Value::BigInt(i),
+                Err(_) => match number.parse::<f64>() {
+                    Ok(f) => Value::Double(f),
+                    Err(_) => bail!("Invalid number {} in filter", number),
+                },
+            },
+            token if token.is_keyword("TRUE") => Value::Boolean(true),
+            token if token.is_keyword("FALSE") => Value::Boolean(false),
+            token if token.is_keyword("NULL") => Value::Null,
+            other => bail!("Expected a value but found {} in filter", other.describe()),
+        };
+        self.params.push(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::params_from_iter;
+    use crate::db::db_pool::tests::memory_pool;
+
+    fn columns() -> Vec<String> {
+        ["id", "side", "price", "qty", "Odd \"name\""].map(String::from).to_vec()
+    }
+
+    fn compile(filter: &str) -> (String, Vec<Value>) {
+        compile_filter(filter, &columns()).unwrap()
+    }
+
+    #[test]
+    fn compiles_comparisons() {
+        assert_eq!(
+            compile("price >= 10.5 AND side <> 'buy' OR qty = -3"),
+            (
+                "\"price\" >= ? AND \"side\" <> ? OR \"qty\" = ?".to_string(),
+                vec![Value::Double(10.5), Value::Text("buy".into()), Value::BigInt(-3)]
+            )
+        );
+        assert_eq!(
+            compile("NOT (side = TRUE or qty != NULL)"),
+            (
+                "NOT (\"side\" = ? OR \"qty\" != ?)".to_string(),
+                vec![Value::Boolean(true), Value::Null]
+            )
+        );
+    }
+
+    #[test]
+    fn compiles_in_like_between_and_null_checks() {
+        assert_eq!(
+            compile("side NOT IN ('a', 'b') AND side like 'b%' AND qty BETWEEN 1 AND 5 AND price IS NOT NULL"),
+            (
+                "\"side\" NOT IN (?, ?) AND \"side\" LIKE ? AND \"qty\" BETWEEN ? AND ? AND \"price\" IS NOT NULL"
+                    .to_string(),
+                vec![
+                    Value::Text("a".into()),
+                    Value::Text("b".into()),
+                    Value::Text("b%".into()),
+                    Value::BigInt(1),
+                    Value::BigInt(5),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn binds_literals_instead_of_inlining_them() {
+        let (sql, params) = compile("side = 'x'' OR 1=1 --'");
+        assert_eq!(sql, "\"side\" = ?");
+        assert_eq!(params, vec![Value::Text("x' OR 1=1 --".into())]);
+    }
+
+    #[test]
+    fn resolves_and_quotes_columns() {
+        assert_eq!(compile("PRICE > 1").0, "\"price\" > ?");
+        assert_eq!(compile("\"Odd \"\"name\"\"\" IS NULL").0, "\"Odd \"\"name\"\"\" IS NULL");
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for filter in [
+            "",
+            "missing = 1",
+            "price",
+            "price >",
+            "1 = price",
+            "price = side",
+            "price = 'open",
+            "price = 1; DROP TABLE t",
+            "price = 1 price",
+            "(price = 1",
+            "price = 1)",
+            "side LIKE 1",
+            "qty IN ()",
+            "qty BETWEEN 1 5",
+            "price IS 1",
+            "price = 1.2.3",
+        ] {
+            assert!(compile_filter(filter, &columns()).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn filters_rows_in_duckdb() {
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER, side VARCHAR, price DOUBLE, qty INTEGER, \"Odd \"\"name\"\"\" VARCHAR);
+             INSERT INTO t VALUES
+                (1, 'buy', 1.5, 10, NULL),
+                (2, 'sell', 3.0, 2, 'x'),
+                (3, 'buy', NULL, 7, 'y'),
+                (4, 'sell''s', 1.0, 1, NULL);",
+        )
+        .unwrap();
+
+        let ids = |filter: &str| -> Vec<i32> {
+            let (predicate, params) = compile(filter);
+            let mut statement = conn
+                .prepare(&format!("SELECT id FROM t WHERE {} ORDER BY id", predicate))
+                .unwrap();
+            statement
+                .query_map(params_from_iter(params), |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+
+        assert_eq!(ids("side = 'buy' OR qty < 2 AND price = 1"), vec![1, 3, 4]);
+        assert_eq!(ids("(side = 'buy' OR qty < 2) AND price = 1"), vec![4]);
+        assert_eq!(ids("NOT side = 'buy' AND price BETWEEN 1 AND 2"), vec![4]);
+        assert_eq!(ids("side IN ('sell''s', 'buy') AND \"Odd \"\"name\"\"\" IS NULL"), vec![1, 4]);
+        assert_eq!(ids("side NOT LIKE 's%' AND price IS NULL"), vec![3]);
+        assert_eq!(ids("side = 'buy'' OR ''1''=''1'"), Vec::<i32>::new());
+    }
+}
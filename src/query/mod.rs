@@ -0,0 +1,7 @@
+pub mod cube;
+pub mod executor;
+pub mod filter;
+pub mod read_only;
+pub mod result_format;
+pub mod slice;
+pub mod sql;
\ No newline at end of file
@@ -0,0 +1,568 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use anyhow::{bail, Result};
+
+/// Statements that can only read data.
+const READ_ONLY_KEYWORDS: &[&str] = &[
+    "SELECT", "WITH", "VALUES", "FROM", "TABLE", "SHOW", "DESCRIBE", "SUMMARIZE", "EXPLAIN",
+    "PIVOT", "UNPIVOT",
+];
+
+/// Table functions a query may read from. They only generate rows; every
+/// other table function (`read_csv`, `glob`, `query_table`, ...) is refused,
+/// including ones added by extensions DuckDB loads on demand.
+const ALLOWED_TABLE_FUNCTIONS: &[&str] = &[
+    "range", "generate_series", "unnest", "repeat", "json_each", "json_tree",
+];
+
+/// Keywords that start a clause; used to tell whether a comma separates
+/// FROM-list entries or something else.
+const CLAUSE_KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "JOIN", "WHERE", "GROUP", "HAVING", "QUALIFY", "WINDOW", "ORDER",
+    "LIMIT", "OFFSET", "UNION", "EXCEPT", "INTERSECT", "ON", "USING", "VALUES",
+];
+
+/// Keywords that can be followed by a table name.
+const TABLE_KEYWORDS: &[&str] = &[
+    "FROM", "JOIN", "TABLE", "DESCRIBE", "SUMMARIZE", "SHOW", "PIVOT", "UNPIVOT",
+];
+
+/// Keywords that start a query, so a parenthesis opening with one holds a subquery.
+const QUERY_KEYWORDS: &[&str] = &["SELECT", "WITH", "FROM", "VALUES", "TABLE", "PIVOT", "UNPIVOT"];
+
+/// Rejects anything but a single read-only statement.
+///
+/// The statement's first keyword must be one of [`READ_ONLY_KEYWORDS`] (but
+/// not `EXPLAIN ANALYZE`, which runs the statement), it must not be followed
+/// by another statement, and it must not read files or other external
+/// resources: the only table functions it may call are those in
+/// [`ALLOWED_TABLE_FUNCTIONS`], and it may not name a path in a FROM clause
+/// (`FROM 'data.csv'`). String literals, quoted identifiers and comments are
+/// skipped while scanning, so keywords hidden inside them are neither trusted
+/// nor rejected.
+pub fn ensure_read_only(sql: &str) -> Result<()> {
+    let tokens = tokenize(sql)?;
+
+    let mut statements = tokens.split(|t| *t == Token::Semicolon).filter(|s| !s.is_empty());
+    let Some(statement) = statements.next() else {
+        bail!("Query is empty");
+    };
+    if statements.next().is_some() {
+        bail!("Only a single statement is allowed");
+    }
+
+    match statement.first() {
+        Some(Token::Word(word)) if READ_ONLY_KEYWORDS.contains(&word.to_uppercase().as_str()) => {}
+        Some(Token::Word(word)) => bail!("{} statements are not allowed; only reads are", word),
+        _ => bail!("Query must start with SELECT, WITH or another read-only keyword"),
+    }
+    if explains_analyze(statement) {
+        bail!("EXPLAIN ANALYZE is not allowed");
+    }
+    for function in referenced_tables(sql)?.functions {
+        if !ALLOWED_TABLE_FUNCTIONS.contains(&function.to_lowercase().as_str()) {
+            bail!("Table function {} is not allowed in queries", function);
+        }
+    }
+
+    // The clause keyword most recently seen at each parenthesis depth.
+    let mut clauses: Vec<Option<String>> = vec![None];
+    let mut previous: Option<&Token> = None;
+    for token in statement {
+        match token {
+            Token::Word(word) => {
+                let upper = word.to_uppercase();
+                if CLAUSE_KEYWORDS.contains(&upper.as_str()) {
+                    *clauses.last_mut().unwrap() = Some(upper);
+                }
+            }
+            Token::OpenParen => clauses.push(None),
+            Token::CloseParen if clauses.len() > 1 => {
+                clauses.pop();
+            }
+            Token::StringLit | Token::QuotedIdent(_) => {
+                let in_from_position = match previous {
+                    Some(Token::Word(word)) => {
+                        matches!(word.to_uppercase().as_str(), "FROM" | "JOIN")
+                    }
+                    Some(Token::Comma) => matches!(
+                        clauses.last().unwrap().as_deref(),
+                        Some("FROM") | Some("JOIN")
+                    ),
+                    _ => false,
+                };
+                let looks_like_path = match token {
+                    Token::QuotedIdent(name) => name.contains(['.', '/', '\\', ':']),
+                    _ => true,
+                };
+                if in_from_position && looks_like_path {
+                    bail!("Reading files or URLs by path is not allowed in queries");
+                }
+            }
+            _ => {}
+        }
+        previous = Some(token);
+    }
+
+    Ok(())
+}
+
+/// Whether an EXPLAIN statement asks for ANALYZE, in any of the spellings
+/// DuckDB accepts (`EXPLAIN ANALYZE`, `EXPLAIN (ANALYZE) ...`).
+fn explains_analyze(statement: &[Token]) -> bool {
+    let is_word = |token: &Token, keyword: &str| {
+        matches!(token, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    };
+    if !statement.first().is_some_and(|t| is_word(t, "EXPLAIN")) {
+        return false;
+    }
+    // Options come before the statement being explained.
+    statement[1..]
+        .iter()
+        .take_while(|t| !QUERY_KEYWORDS.iter().any(|keyword| is_word(t, keyword)))
+        .any(|t| is_word(t, "ANALYZE") || is_word(t, "ANALYSE"))
+}
+
+/// Splits a script into its statements, each without the separating `;`.
+///
+/// Semicolons inside literals, quoted identifiers and comments don't split.
+/// Statements that are blank, or only comments, are dropped.
+pub fn split_statements(sql: &str) -> Result<Vec<&str>> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut blank = true;
+    for (token, span) in tokenize_with_spans(sql)? {
+        if token == Token::Semicolon {
+            if !blank {
+                statements.push(&sql[start..span.start]);
+            }
+            start = span.end;
+            blank = true;
+        } else {
+            blank = false;
+        }
+    }
+    if !blank {
+        statements.push(&sql[start..]);
+    }
+    Ok(statements)
+}
+
+/// The tables a query reads, as found by [`referenced_tables`].
+#[derive(Debug, Default)]
+pub struct TableReferences {
+    /// Names used where a table goes (after FROM, JOIN, TABLE, ...), as
+    /// written. Qualified names keep their qualifier: `main.trades`.
+    pub tables: Vec<String>,
+    /// Where each of `tables` is in the query, as a byte range.
+    pub table_spans: Vec<Range<usize>>,
+    /// Lower-cased names the query defines for itself with WITH.
+    pub ctes: HashSet<String>,
+    /// Table functions called where a table goes (`FROM range(10)`), by
+    /// their unqualified name as written.
+    pub functions: Vec<String>,
+}
+
+/// Finds the names a query reads from, so they can be checked against what
+/// the caller may see.
+///
+/// Table functions (`range(10)`) are reported separately, and subqueries
+/// not at all, though the tables inside them are. FROM inside a call's
+/// arguments (`extract(year FROM ts)`) doesn't name a table.
+pub fn referenced_tables(sql: &str) -> Result<TableReferences> {
+    let (tokens, spans): (Vec<Token>, Vec<Range<usize>>) = tokenize_with_spans(sql)?.into_iter().unzip();
+    let mut tables = Vec::new();
+    let mut table_spans = Vec::new();
+    let mut functions = Vec::new();
+    // For each parenthesis depth, the clause keyword most recently seen and
+    // whether the parenthesis holds a call's arguments rather than a query.
+    let mut depths: Vec<(Option<String>, bool)> = vec![(None, false)];
+    let mut expect_table = false;
+
+    for (i, token) in tokens.iter().enumerate() {
+        // A table, or a table function if the name is followed by a call.
+        let mut reference = |name: &str| {
+            let (qualified, last, len) = qualified_name(name, &tokens[i + 1..]);
+            if tokens.get(i + 1 + len) == Some(&Token::OpenParen) {
+                functions.push(last);
+            } else {
+                tables.push(qualified);
+                table_spans.push(spans[i].start..spans[i + len].end);
+            }
+        };
+        match token {
+            Token::Word(word) => {
+                let upper = word.to_uppercase();
+                if expect_table && upper == "LATERAL" {
+                    continue;
+                }
+                let is_keyword = READ_ONLY_KEYWORDS.contains(&upper.as_str())
+                    || CLAUSE_KEYWORDS.contains(&upper.as_str());
+                if expect_table && !is_keyword {
+                    reference(word);
+                }
+                let (clause, in_call) = depths.last_mut().unwrap();
+                if CLAUSE_KEYWORDS.contains(&upper.as_str()) {
+                    *clause = Some(upper.clone());
+                }
+                expect_table = !*in_call && TABLE_KEYWORDS.contains(&upper.as_str());
+            }
+            Token::QuotedIdent(name) => {
+                if expect_table {
+                    reference(name);
+                }
+                expect_table = false;
+            }
+            Token::OpenParen => {
+                // `FROM (a JOIN b)` still expects tables inside.
+                let opens_query = match tokens.get(i + 1) {
+                    Some(Token::Word(word)) => QUERY_KEYWORDS.contains(&word.to_uppercase().as_str()),
+                    Some(Token::OpenParen) => true,
+                    _ => false,
+                };
+                let clause = expect_table.then(|| "FROM".to_string());
+                depths.push((clause, !expect_table && !opens_query));
+            }
+            Token::CloseParen => {
+                if depths.len() > 1 {
+                    depths.pop();
+                }
+                expect_table = false;
+            }
+            Token::Comma => {
+                let (clause, in_call) = depths.last().unwrap();
+                expect_table = !*in_call
+                    && matches!(clause.as_deref(), Some("FROM") | Some("JOIN") | Some("ON") | Some("USING"));
+            }
+            Token::Semicolon => {
+                depths = vec![(None, false)];
+                expect_table = false;
+            }
+            _ => expect_table = false,
+        }
+    }
+
+    Ok(TableReferences {
+        tables,
+        table_spans,
+        ctes: cte_names(&tokens),
+        functions,
+    })
+}
+
+/// A name followed by any `.part`s: the parts joined with dots, the last
+/// part, and how many tokens of `rest` the `.part`s took.
+fn qualified_name(first: &str, rest: &[Token]) -> (String, String, usize) {
+    let mut name = first.to_string();
+    let mut last = first.to_string();
+    let mut len = 0;
+    for pair in rest.chunks(2) {
+        match pair {
+            [Token::Dot, Token::Word(part) | Token::QuotedIdent(part)] => {
+                name.push('.');
+                name.push_str(part);
+                last = part.clone();
+                len += 2;
+            }
+            _ => break,
+        }
+    }
+    (name, last, len)
+}
+
+/// Puts `ctes` (each `name AS (query)`) in front of a query, merging them
+/// into its own WITH clause if it has one, so they can stand in for tables.
+///
+/// Only queries that may start with WITH (SELECT, FROM, VALUES, TABLE or
+/// WITH itself) can take them.
+pub fn prepend_ctes(sql: &str, ctes: &[String]) -> Result<String> {
+    if ctes.is_empty() {
+        return Ok(sql.to_string());
+    }
+    let tokens = tokenize_with_spans(sql)?;
+    let first = match tokens.first() {
+        Some((Token::Word(word), _)) => word.to_uppercase(),
+        _ => bail!("Query must start with SELECT, WITH or another read-only keyword"),
+    };
+    match first.as_str() {
+        "WITH" => {
+            // Ours go first, so the query's own CTEs can read them.
+            let mut end = tokens[0].1.end;
+            if let Some((Token::Word(word), span)) = tokens.get(1) {
+                if word.eq_ignore_ascii_case("RECURSIVE") {
+                    end = span.end;
+                }
+            }
+            Ok(format!("{} {},{}", &sql[..end], ctes.join(", "), &sql[end..]))
+        }
+        "SELECT" | "FROM" | "VALUES" | "TABLE" => Ok(format!("WITH {} {}", ctes.join(", "), sql)),
+        _ => bail!("{} statements cannot read tables with row or column policies", first),
+    }
+}
+
+/// Names defined by WITH clauses anywhere in the statement.
+fn cte_names(tokens: &[Token]) -> HashSet<String> {
+    let is_word = |token: Option<&Token>, keyword: &str| {
+        matches!(token, Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    };
+    let mut names = HashSet::new();
+    for i in 0..tokens.len() {
+        if !is_word(tokens.get(i), "WITH") {
+            continue;
+        }
+        let mut j = i + 1;
+        if is_word(tokens.get(j), "RECURSIVE") {
+            j += 1;
+        }
+        // name [(columns)] AS [NOT] [MATERIALIZED] (query) [, ...]
+        while let Some(Token::Word(name) | Token::QuotedIdent(name)) = tokens.get(j) {
+            j += 1;
+            if tokens.get(j) == Some(&Token::OpenParen) {
+                j = past_closing_paren(tokens, j);
+            }
+            if !is_word(tokens.get(j), "AS") {
+                break;
+            }
+            j += 1;
+            while is_word(tokens.get(j), "NOT") || is_word(tokens.get(j), "MATERIALIZED") {
+                j += 1;
+            }
+            if tokens.get(j) != Some(&Token::OpenParen) {
+                break;
+            }
+            names.insert(name.to_lowercase());
+            j = past_closing_paren(tokens, j);
+            if tokens.get(j) != Some(&Token::Comma) {
+                break;
+            }
+            j += 1;
+        }
+    }
+    names
+}
+
+/// The index just past the parenthesis that closes the one at `open`.
+fn past_closing_paren(tokens: &[Token], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token {
+            Token::OpenParen => depth += 1,
+            Token::CloseParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    QuotedIdent(String),
+    StringLit,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Semicolon,
+    Dot,
+    Other,
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    Ok(tokenize_with_spans(sql)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/// A minimal SQL lexer: just enough to find words, parentheses, commas and
+/// statement separators outside of literals and comments. Each token comes
+/// with the bytes it spans.
+fn tokenize_with_spans(sql: &str) -> Result<Vec<(Token, Range<usize>)>> {
+    let chars: Vec<(usize, char)> = sql.char_indices().collect();
+    let char_at = |i: usize| chars.get(i).map(|&(_, c)| c);
+    let byte_at = |i: usize| chars.get(i).map_or(sql.len(), |&(offset, _)| offset);
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while let Some(c) = char_at(i) {
+        let offset = chars[i].0;
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '-' if char_at(i + 1) == Some('-') => {
+                while char_at(i).is_some_and(|c| c != '\n') {
+                    i += 1;
+                }
+            }
+            '/' if char_at(i + 1) == Some('*') => {
+                i += 2;
+                while char_at(i).is_some() && !(char_at(i) == Some('*') && char_at(i + 1) == Some('/')) {
+                    i += 1;
+                }
+                if char_at(i).is_none() {
+                    bail!("Unterminated comment");
+                }
+                i += 2;
+            }
+            '\'' | '"' => {
+                // Literals and quoted identifiers; a doubled quote is an escape.
+                let quote = c;
+                let mut content = String::new();
+                i += 1;
+                loop {
+                    match char_at(i) {
+                        None => bail!("Unterminated quoted string"),
+                        Some(q) if q == quote && char_at(i + 1) == Some(quote) => {
+                            content.push(q);
+                            i += 2;
+                        }
+                        Some(q) if q == quote => {
+                            i += 1;
+                            break;
+                        }
+                        Some(other) => {
+                            content.push(other);
+                            i += 1;
+                        }
+                    }
+                }
+                let token = if quote == '"' {
+                    Token::QuotedIdent(content)
+                } else {
+                    Token::StringLit
+                };
+                tokens.push((token, offset..byte_at(i)));
+            }
+            '(' | ')' | ',' | ';' | '.' => {
+                let token = match c {
+                    '(' => Token::OpenParen,
+                    ')' => Token::CloseParen,
+                    ',' => Token::Comma,
+                    '.' => Token::Dot,
+                    _ => Token::Semicolon,
+                };
+                i += 1;
+                tokens.push((token, offset..byte_at(i)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some(c) = char_at(i).filter(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                    i += 1;
+                }
+                tokens.push((Token::Word(word), offset..byte_at(i)));
+            }
+            _ => {
+                i += 1;
+                tokens.push((Token::Other, offset..byte_at(i)));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_reads() {
+        for sql in [
+            "SELECT * FROM trades",
+            "with t AS (SELECT 1) SELECT * FROM t;",
+            "FROM trades WHERE price > 1",
+            "SELECT * FROM range(10), generate_series(1, 3)",
+            "SELECT * FROM trades t, LATERAL unnest(t.legs)",
+            "EXPLAIN SELECT 1",
+            "SELECT 'read_csv(''x'')' AS not_a_call",
+            "SELECT extract(year FROM ts) FROM trades",
+        ] {
+            assert!(ensure_read_only(sql).is_ok(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_writes_and_scripts() {
+        for sql in [
+            "",
+            "  ;  ",
+            "DELETE FROM trades",
+            "SELECT 1; DROP TABLE trades",
+            "ATTACH 'other.db'",
+            "COPY trades TO 'out.csv'",
+            "/* SELECT */ INSTALL httpfs",
+        ] {
+            assert!(ensure_read_only(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_explain_analyze() {
+        assert!(ensure_read_only("EXPLAIN ANALYZE SELECT 1").is_err());
+        assert!(ensure_read_only("explain (analyze) SELECT 1").is_err());
+        assert!(ensure_read_only("EXPLAIN SELECT analyze FROM t").is_ok());
+    }
+
+    #[test]
+    fn only_allowed_table_functions() {
+        for sql in [
+            "SELECT * FROM read_csv('/etc/passwd')",
+            "FROM read_text('secret')",
+            "SELECT * FROM trades JOIN glob('*') ON true",
+            "SELECT * FROM trades, query_table('t')",
+            "SELECT * FROM \"read_parquet\"('x.parquet')",
+            "SELECT * FROM system.main.read_csv('x')",
+            "SELECT (SELECT count(*) FROM read_json('x'))",
+            "SELECT coalesce((FROM sqlite_scan('x.db', 't')), 1)",
+            "SUMMARIZE read_csv('x')",
+            "SELECT * FROM trades t JOIN LATERAL duckdb_secrets() s ON true",
+        ] {
+            assert!(ensure_read_only(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_paths() {
+        assert!(ensure_read_only("SELECT * FROM 'data.csv'").is_err());
+        assert!(ensure_read_only("SELECT * FROM trades, \"s3://bucket/x.parquet\"").is_err());
+        assert!(ensure_read_only("SELECT * FROM \"trades\"").is_ok());
+    }
+
+    #[test]
+    fn splits_statements_outside_literals_and_comments() {
+        let statements = split_statements("SELECT ';'; -- a;b\nSELECT \"x;y\" ; /* ; */ ;").unwrap();
+        assert_eq!(statements, vec!["SELECT ';'", " -- a;b\nSELECT \"x;y\" "]);
+        assert!(split_statements("SELECT 'open").is_err());
+        assert!(split_statements("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_referenced_tables() {
+        let references = referenced_tables(
+            "WITH recent AS (SELECT * FROM trades) \
+             SELECT * FROM recent JOIN main.\"Quotes\" q ON true, range(3) \
+             WHERE x IN (SELECT id FROM ids) AND extract(year FROM ts) = 2024",
+        )
+        .unwrap();
+        assert_eq!(references.tables, vec!["trades", "recent", "main.Quotes", "ids"]);
+        assert_eq!(references.ctes, HashSet::from(["recent".to_string()]));
+        assert_eq!(references.functions, vec!["range"]);
+    }
+
+    #[test]
+    fn table_spans_cover_qualified_names() {
+        let sql = "SELECT * FROM pg_catalog . \"pg_class\" c JOIN t ON true";
+        let references = referenced_tables(sql).unwrap();
+        let spans: Vec<&str> = references.table_spans.iter().map(|span| &sql[span.clone()]).collect();
+        assert_eq!(spans, vec!["pg_catalog . \"pg_class\"", "t"]);
+    }
+
+    #[test]
+    fn finds_tables_in_nested_joins_and_statements() {
+        let references =
+            referenced_tables("SELECT * FROM (a JOIN b USING (id)); TABLE c; DESCRIBE d").unwrap();
+        assert_eq!(references.tables, vec!["a", "b", "c", "d"]);
+    }
+}
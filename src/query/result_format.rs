@@ -0,0 +1,56 @@
+use anyhow::Result;
+use arrow::datatypes::Schema;
+use arrow::record_batch::RecordBatch;
+use crate::db::arrow_encoding::{encode_csv, encode_ipc_stream, encode_json_rows, encode_parquet};
+
+/// Encodings a query result can be returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultFormat {
+    ArrowStream,
+    Json,
+    Csv,
+    Parquet,
+}
+
+impl ResultFormat {
+    /// Picks a format from an `Accept` header.
+    ///
+    /// Media types are tried in the order the client listed them and the
+    /// first supported one wins; a missing header or a wildcard means JSON.
+    /// Returns `None` if nothing acceptable is supported.
+    pub fn from_accept(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept else {
+            return Some(ResultFormat::Json);
+        };
+        accept
+            .split(',')
+            .map(|media| media.split(';').next().unwrap_or("").trim().to_lowercase())
+            .find_map(|media| match media.as_str() {
+                "application/vnd.apache.arrow.stream" => Some(ResultFormat::ArrowStream),
+                "application/json" | "application/*" | "*/*" | "" => Some(ResultFormat::Json),
+                "text/csv" | "text/*" => Some(ResultFormat::Csv),
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    Some(ResultFormat::Parquet)
+                }
+                _ => None,
+            })
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ResultFormat::ArrowStream => "application/vnd.apache.arrow.stream",
+            ResultFormat::Json => "application/json",
+            ResultFormat::Csv => "text/csv",
+            ResultFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn encode(self, schema: &Schema, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+        match self {
+            ResultFormat::ArrowStream => encode_ipc_stream(schema, batches),
+            ResultFormat::Json => encode_json_rows(batches),
+            ResultFormat::Csv => encode_csv(schema, batches),
+            ResultFormat::Parquet => encode_parquet(schema, batches),
+        }
+    }
+}
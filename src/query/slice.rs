@@ -0,0 +1,58 @@
+use anyhow::Result;
+use duckdb::types::Value;
+use serde::Deserialize;
+use crate::query::filter::compile_filter;
+use crate::query::sql::{order_by_clause, quote_identifier, resolve_column};
+
+/// Query-string parameters selecting part of a dataset, e.g.
+/// `?columns=region,amount&filter=amount > 100&order_by=amount desc&limit=50`.
+#[derive(Debug, Default, Deserialize)]
+pub struct SliceParams {
+    /// Comma-separated columns to return; all columns if absent.
+    pub columns: Option<String>,
+    /// A predicate in the grammar accepted by [`compile_filter`].
+    pub filter: Option<String>,
+    /// Comma-separated columns, each optionally followed by `asc` or `desc`.
+    pub order_by: Option<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+impl SliceParams {
+    /// Builds the SELECT for this slice of `source` (a quoted table, or a
+    /// subquery with an alias), checking every column it mentions against
+    /// `columns`. Filter values are returned as bound parameters.
+    pub fn to_sql(&self, source: &str, columns: &[String]) -> Result<(String, Vec<Value>)> {
+        let projection = match self.columns.as_deref().map(str::trim) {
+            None | Some("") => "*".to_string(),
+            Some(list) => list
+                .split(',')
+                .map(|name| resolve_column(columns, name.trim()).map(quote_identifier))
+                .collect::<Result<Vec<_>>>()?
+                .join(", "),
+        };
+
+        let mut sql = format!("SELECT {} FROM {}", projection, source);
+        let mut params = Vec::new();
+
+        if let Some(filter) = self.filter.as_deref().filter(|f| !f.trim().is_empty()) {
+            let (predicate, filter_params) = compile_filter(filter, columns)?;
+            sql.push_str(&format!(" WHERE {}", predicate));
+            params = filter_params;
+        }
+
+        if let Some(order_by) = self.order_by.as_deref().filter(|o| !o.trim().is_empty()) {
+            sql.push_str(&format!(" ORDER BY {}", order_by_clause(order_by, columns)?));
+        }
+
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        if let Some(offset) = self.offset {
+            sql.push_str(&format!(" OFFSET {}", offset));
+        }
+
+        Ok((sql, params))
+    }
+}
+
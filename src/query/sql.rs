@@ -0,0 +1,366 @@
+use anyhow::{bail, Result};
+
+/// Quotes an identifier for DuckDB, doubling any embedded quotes.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a string as a DuckDB literal, doubling any embedded quotes.
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Finds `name` among a table's columns, preferring an exact match and
+/// falling back to a case-insensitive one as DuckDB itself does.
+pub fn resolve_column<'a>(columns: &'a [String], name: &str) -> Result<&'a str> {
+    columns
+        .iter()
+        .find(|c| c.as_str() == name)
+        .or_else(|| columns.iter().find(|c| c.eq_ignore_ascii_case(name)))
+        .map(|c| c.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Unknown column {}", name))
+}
+
+
+/// Compiles `column [asc|desc], ...` into an ORDER BY list, checking each
+/// column against `columns`.
+pub fn order_by_clause(order_by: &str, columns: &[String]) -> Result<String> {
+    let terms = order_by
+        .split(',')
+        .map(|term| {
+            let mut parts = term.split_whitespace();
+            let Some(name) = parts.next() else {
+                bail!("Empty order_by term");
+            };
+            let column = quote_identifier(resolve_column(columns, name)?);
+            let direction = match parts.next() {
+                None => "ASC",
+                Some(d) if d.eq_ignore_ascii_case("asc") => "ASC",
+                Some(d) if d.eq_ignore_ascii_case("desc") => "DESC",
+                Some(d) => bail!("Invalid sort direction {} for {}", d, name),
+            };
+            if parts.next().is_some() {
+                bail!("Invalid order_by term {}", term.trim());
+            }
+            Ok(format!("{} {}", column, direction))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(terms.join(", "))
+}
+
+/// DuckDB's built-in type names, including aliases. `DOUBLE PRECISION`,
+/// `CHARACTER VARYING` and `TIME[STAMP] WITH[OUT] TIME ZONE` are handled
+/// by [`check_column_type`] itself.
+const TYPE_NAMES: &[&str] = &[
+    "BIGINT", "INT8", "LONG", "BIT", "BITSTRING", "BLOB", "BYTEA", "BINARY", "VARBINARY",
+    "BOOLEAN", "BOOL", "LOGICAL", "DATE", "DECIMAL", "NUMERIC", "DOUBLE", "FLOAT8", "FLOAT",
+    "REAL", "FLOAT4", "HUGEINT", "UHUGEINT", "INTEGER", "INT4", "INT", "SIGNED", "INTERVAL",
+    "JSON", "SMALLINT", "INT2", "SHORT", "TINYINT", "INT1", "UBIGINT", "UINTEGER", "USMALLINT",
+    "UTINYINT", "UUID", "VARINT", "TIME", "TIMETZ", "TIMESTAMP", "DATETIME", "TIMESTAMPTZ",
+    "TIMESTAMP_S", "TIMESTAMP_MS", "TIMESTAMP_NS", "TIMESTAMP_US", "VARCHAR", "CHAR", "BPCHAR",
+    "TEXT", "STRING", "CHARACTER",
+];
+
+/// Checks a column type from the config against DuckDB's type grammar, so
+/// it can go into a CREATE TABLE as written.
+///
+/// Accepts the built-in types with optional size parameters
+/// (`DECIMAL(18, 4)`, `VARCHAR(20)`), `STRUCT(...)`, `UNION(...)`,
+/// `MAP(key, value)`, `ENUM('a', 'b')` and any of those followed by `[]` or
+/// `[n]` for lists and arrays.
+pub fn check_column_type(type_name: &str) -> Result<()> {
+    let tokens = type_tokens(type_name)
+        .ok_or_else(|| anyhow::anyhow!("Invalid column type {}", type_name))?;
+    match parse_type(&tokens, 0) {
+        Some(end) if end == tokens.len() => Ok(()),
+        _ => bail!("Invalid column type {}", type_name),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum TypeToken {
+    Word(String),
+    Number,
+    Str,
+    QuotedIdent,
+    Punct(char),
+}
+
+/// Splits a type into tokens, or `None` if it contains anything a type can't.
+fn type_tokens(type_name: &str) -> Option<Vec<TypeToken>> {
+    let mut tokens = Vec::new();
+    let mut chars = type_name.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '[' | ']' | ',' => tokens.push(TypeToken::Punct(c)),
+            '\'' | '"' => {
+                // A doubled quote is an escape.
+                loop {
+                    match chars.next()? {
+                        q if q == c && chars.peek() == Some(&c) => {
+                            chars.next();
+                        }
+                        q if q == c => break,
+                        _ => {}
+                    }
+                }
+                tokens.push(if c == '"' { TypeToken::QuotedIdent } else { TypeToken::Str });
+            }
+            c if c.is_ascii_digit() => {
+                while chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    chars.next();
+                }
+                tokens.push(TypeToken::Number);
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(TypeToken::Word(word.to_uppercase()));
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+/// Parses one type starting at `i`, returning the index just past it.
+fn parse_type(tokens: &[TypeToken], i: usize) -> Option<usize> {
+    let word = |i: usize| match tokens.get(i) {
+        Some(TypeToken::Word(word)) => Some(word.as_str()),
+        _ => None,
+    };
+    let punct = |i: usize, c: char| tokens.get(i) == Some(&TypeToken::Punct(c));
+
+    let mut i = match word(i)? {
+        "STRUCT" | "UNION" if punct(i + 1, '(') => {
+            // (name type, ...)
+            let mut j = i + 2;
+            loop {
+                match tokens.get(j)? {
+                    TypeToken::Word(_) | TypeToken::QuotedIdent => {}
+                    _ => return None,
+                }
+                j = parse_type(tokens, j + 1)?;
+                if punct(j, ',') {
+                    j += 1;
+                } else if punct(j, ')') {
+                    break j + 1;
+                } else {
+                    return None;
+                }
+            }
+        }
+        "MAP" if punct(i + 1, '(') => {
+            let j = parse_type(tokens, i + 2)?;
+            if !punct(j, ',') {
+                return None;
+            }
+            let j = parse_type(tokens, j + 1)?;
+            if !punct(j, ')') {
+                return None;
+            }
+            j + 1
+        }
+        "ENUM" if punct(i + 1, '(') => {
+            let mut j = i + 2;
+            loop {
+                if tokens.get(j)? != &TypeToken::Str {
+                    return None;
+                }
+                if punct(j + 1, ',') {
+                    j += 2;
+                } else if punct(j + 1, ')') {
+                    break j + 2;
+                } else {
+                    return None;
+                }
+            }
+        }
+        name if TYPE_NAMES.contains(&name) => {
+            let mut j = i + 1;
+            match (name, word(j)) {
+                ("DOUBLE", Some("PRECISION")) | ("CHARACTER", Some("VARYING")) => j += 1,
+                ("TIME" | "TIMESTAMP", Some("WITH" | "WITHOUT"))
+                    if word(j + 1) == Some("TIME") && word(j + 2) == Some("ZONE") =>
+                {
+                    j += 3
+                }
+                _ => {}
+            }
+            // Size parameters: (n) or (n, m).
+            if punct(j, '(') {
+                j += 1;
+                loop {
+                    if tokens.get(j)? != &TypeToken::Number {
+                        return None;
+                    }
+                    if punct(j + 1, ',') {
+                        j += 2;
+                    } else if punct(j + 1, ')') {
+                        break j + 2;
+                    } else {
+                        return None;
+                    }
+                }
+            } else {
+                j
+            }
+        }
+        _ => return None,
+    };
+
+    // List ([]) and fixed-size array ([n]) suffixes.
+    while punct(i, '[') {
+        if tokens.get(i + 1) == Some(&TypeToken::Number) {
+            i += 1;
+        }
+        if !punct(i + 1, ']') {
+            return None;
+        }
+        i += 2;
+    }
+    Some(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    const AWKWARD_NAMES: &[&str] = &[
+        "plain",
+        "select",
+        "with space",
+        "a\"b",
+        "\"\"",
+        "'",
+        "x\" INTEGER); DROP TABLE victim; --",
+        "x'); DROP TABLE victim; --",
+        "/* comment */",
+        "new\nline",
+        "ünïcødé",
+    ];
+
+    #[test]
+    fn quoted_names_and_values_round_trip() {
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch("CREATE TABLE victim (x INTEGER)").unwrap();
+
+        for name in AWKWARD_NAMES {
+            let table = quote_identifier(name);
+            let column = quote_identifier(name);
+            conn.execute_batch(&format!(
+                "CREATE TABLE {table} ({column} VARCHAR); INSERT INTO {table} VALUES ({});",
+                quote_literal(name)
+            ))
+            .unwrap();
+            let (stored_column, value): (String, String) = conn
+                .query_row(
+                    &format!("SELECT column_name, (SELECT {column} FROM {table}) FROM duckdb_columns() WHERE table_name = ?"),
+                    [name],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!((stored_column.as_str(), value.as_str()), (*name, *name));
+        }
+
+        let tables: i64 = conn
+            .query_row("SELECT count(*) FROM duckdb_tables()", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables as usize, AWKWARD_NAMES.len() + 1);
+    }
+
+    #[test]
+    fn resolves_exact_names_first() {
+        let columns = ["Price", "price", "qty"].map(String::from);
+        assert_eq!(resolve_column(&columns, "price").unwrap(), "price");
+        assert_eq!(resolve_column(&columns, "Price").unwrap(), "Price");
+        assert_eq!(resolve_column(&columns, "QTY").unwrap(), "qty");
+        assert!(resolve_column(&columns, "qty; DROP TABLE t").is_err());
+    }
+
+    #[test]
+    fn order_by_takes_only_columns_and_directions() {
+        let columns = ["price", "a\"b"].map(String::from);
+        assert_eq!(
+            order_by_clause("price desc, A\"B", &columns).unwrap(),
+            "\"price\" DESC, \"a\"\"b\" ASC"
+        );
+        for order_by in ["", "price,", "price sideways", "price desc nulls first", "price; DROP TABLE t", "1"] {
+            assert!(order_by_clause(order_by, &columns).is_err(), "{}", order_by);
+        }
+    }
+
+    #[test]
+    fn accepts_types_duckdb_can_create() {
+        let pool = memory_pool(1);
+        let conn = pool.get().unwrap();
+        for (i, type_name) in [
+            "INTEGER",
+            "varchar",
+            "VARCHAR(20)",
+            "DECIMAL(18, 4)",
+            "DOUBLE PRECISION",
+            "CHARACTER VARYING",
+            "TIMESTAMP WITH TIME ZONE",
+            "time without time zone",
+            "TIMESTAMP_MS",
+            "INTEGER[]",
+            "DOUBLE[3]",
+            "VARCHAR[][]",
+            "STRUCT(a INTEGER, \"b c\" VARCHAR[])",
+            "MAP(VARCHAR, STRUCT(x DOUBLE))",
+            "UNION(num INTEGER, str VARCHAR)",
+            "ENUM('a', 'it''s', 'c')",
+            "UUID",
+        ]
+        .iter()
+        .enumerate()
+        {
+            check_column_type(type_name).unwrap();
+            conn.execute_batch(&format!("CREATE TABLE t{} (c {})", i, type_name))
+                .unwrap_or_else(|e| panic!("{}: {}", type_name, e));
+        }
+    }
+
+    #[test]
+    fn rejects_anything_but_a_type() {
+        for type_name in [
+            "",
+            "INTEGER); DROP TABLE victim; --",
+            "INTEGER, evil VARCHAR",
+            "INTEGER DEFAULT 1",
+            "INTEGER NOT NULL",
+            "INTEGER PRIMARY KEY",
+            "INTEGER CHECK (1 = 1)",
+            "VARCHAR COLLATE nocase",
+            "INTEGER GENERATED ALWAYS AS (1)",
+            "INTEGER -- comment",
+            "INTEGER /* comment */",
+            "INTEGER;",
+            "VARCHAR(20",
+            "VARCHAR(x)",
+            "ENUM('open",
+            "ENUM(a, b)",
+            "ENUM()",
+            "STRUCT(a)",
+            "STRUCT(a INTEGER",
+            "STRUCT(a INTEGER) DEFAULT NULL",
+            "MAP(VARCHAR)",
+            "INTEGER[",
+            "INTEGER[x]",
+            "INTEGER INTEGER",
+            "TIMESTAMP WITH TIME",
+            "\"INTEGER\"",
+            "my_custom_type",
+            "read_csv('x')",
+        ] {
+            assert!(check_column_type(type_name).is_err(), "{}", type_name);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod schedule;
+#[allow(clippy::module_inception)]
+pub mod scheduler;
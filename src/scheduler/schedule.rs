@@ -0,0 +1,181 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// When a job runs, parsed from a config string.
+///
+/// Accepted forms:
+/// - `on_change`: run whenever the job's input changes rather than on a clock.
+/// - `every_<n>_<unit>` or `every_<unit>`, where unit is `second(s)`,
+///   `minute(s)`, `hour(s)` or `day(s)`, e.g. `every_30_seconds`.
+/// - A cron expression with 5 fields (minute precision) or 6–7 fields
+///   (seconds first, optional year), e.g. `*/5 * * * *`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    OnChange,
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// The next time the job is due strictly after `now`, or `None` for
+    /// `on_change` schedules and cron expressions with no future firings.
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::OnChange => None,
+            Schedule::Every(interval) => chrono::Duration::from_std(*interval)
+                .ok()
+                .and_then(|interval| now.checked_add_signed(interval)),
+            Schedule::Cron(cron) => cron.after(&now).next(),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "on_change" {
+            return Ok(Schedule::OnChange);
+        }
+
+        if let Some(rest) = s.strip_prefix("every_") {
+            return parse_every(rest)
+                .map(Schedule::Every)
+                .with_context(|| format!("Invalid schedule {:?}", s));
+        }
+
+        let fields = s.split_whitespace().count();
+        let expression = match fields {
+            // Standard cron has no seconds field; fire on the minute.
+            5 => format!("0 {}", s),
+            6 | 7 => s.to_string(),
+            _ => return Err(anyhow!(
+                "Invalid schedule {:?}: expected on_change, every_<n>_<unit> or a cron expression",
+                s
+            )),
+        };
+        let cron = cron::Schedule::from_str(&expression)
+            .with_context(|| format!("Invalid cron expression {:?}", s))?;
+        Ok(Schedule::Cron(Box::new(cron)))
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::OnChange => write!(f, "on_change"),
+            Schedule::Every(interval) => write!(f, "every {:?}", interval),
+            Schedule::Cron(cron) => write!(f, "cron {}", cron),
+        }
+    }
+}
+
+fn parse_every(rest: &str) -> anyhow::Result<Duration> {
+    let (count, unit) = match rest.split_once('_') {
+        Some((count, unit)) => (count.parse::<u64>()?, unit),
+        None => (1, rest),
+    };
+    if count == 0 {
+        return Err(anyhow!("interval must be greater than zero"));
+    }
+
+    let unit_secs = match unit {
+        "second" | "seconds" => 1,
+        "minute" | "minutes" => 60,
+        "hour" | "hours" => 60 * 60,
+        "day" | "days" => 24 * 60 * 60,
+        other => return Err(anyhow!("unknown unit {:?}", other)),
+    };
+    let secs = count
+        .checked_mul(unit_secs)
+        .ok_or_else(|| anyhow!("interval is too long"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn every(s: &str) -> Duration {
+        match s.parse::<Schedule>().unwrap() {
+            Schedule::Every(interval) => interval,
+            other => panic!("{} parsed as {}", s, other),
+        }
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(every("every_30_seconds"), Duration::from_secs(30));
+        assert_eq!(every("every_1_second"), Duration::from_secs(1));
+        assert_eq!(every("every_minute"), Duration::from_secs(60));
+        assert_eq!(every("every_2_hours"), Duration::from_secs(2 * 60 * 60));
+        assert_eq!(every(" every_7_days "), Duration::from_secs(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_bad_intervals() {
+        for s in [
+            "every_0_seconds",
+            "every_-1_seconds",
+            "every_x_minutes",
+            "every_5_weeks",
+            "every_5",
+            "every_",
+            "every_99999999999999999_days",
+            "every_18446744073709551616_seconds",
+        ] {
+            assert!(s.parse::<Schedule>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parses_on_change() {
+        let schedule: Schedule = "on_change".parse().unwrap();
+        assert!(matches!(schedule, Schedule::OnChange));
+        assert_eq!(schedule.next_after(Utc::now()), None);
+    }
+
+    #[test]
+    fn five_field_cron_fires_on_the_minute() {
+        let schedule: Schedule = "*/5 * * * *".parse().unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 3, 30).unwrap();
+        assert_eq!(schedule.next_after(now), Some(Utc.with_ymd_and_hms(2024, 1, 1, 12, 5, 0).unwrap()));
+    }
+
+    #[test]
+    fn six_field_cron_has_seconds() {
+        let schedule: Schedule = "*/10 * * * * *".parse().unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(schedule.next_after(now), Some(Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 10).unwrap()));
+    }
+
+    #[test]
+    fn rejects_other_expressions() {
+        for s in ["", "hourly", "* * * *", "* * * * * * * *", "61 * * * *"] {
+            assert!(s.parse::<Schedule>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn interval_is_added_to_now() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let schedule = Schedule::Every(Duration::from_secs(90));
+        assert_eq!(schedule.next_after(now), Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 1, 30).unwrap()));
+        assert_eq!(Schedule::Every(Duration::from_secs(1_000_000_000_000_000)).next_after(now), None);
+    }
+}
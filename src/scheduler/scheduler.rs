@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use chrono::Utc;
+use duckdb::params;
+use r2d2::Pool;
+use tokio::task;
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::scheduler::schedule::Schedule;
+
+/// A blocking unit of work, run on tokio's blocking pool.
+pub type JobFn = Arc<dyn Fn() -> Result<()> + Send + Sync>;
+
+struct Job {
+    name: String,
+    schedule: Schedule,
+    run: JobFn,
+    running: AtomicBool,
+}
+
+/// Runs jobs on interval or cron schedules.
+///
+/// A job that is still running when it next falls due is skipped rather than
+/// started twice. Every run, including skipped ones, is recorded in the
+/// `hydrocube_job_runs` table with its start time, duration and outcome.
+pub struct Scheduler {
+    pool: Pool<DuckDBConnectionManager>,
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    pub fn new(pool: Pool<DuckDBConnectionManager>) -> Self {
+        Self { pool, jobs: Vec::new() }
+    }
+
+    /// Registers a job. `on_change` schedules are driven elsewhere and are ignored here.
+    pub fn add_job(&mut self, name: impl Into<String>, schedule: Schedule, run: JobFn) {
+        let name = name.into();
+        if matches!(schedule, Schedule::OnChange) {
+            return;
+        }
+        self.jobs.push(Job {
+            name,
+            schedule,
+            run,
+            running: AtomicBool::new(false),
+        });
+    }
+
+    /// Spawns one timer task per job and returns immediately.
+    pub fn start(self) -> Result<()> {
+        create_job_runs_table_if_not_exists(&self.pool)?;
+
+        for job in self.jobs {
+            println!("Scheduled job {} ({})", job.name, job.schedule);
+            let pool = self.pool.clone();
+            tokio::spawn(async move { run_job_timer(pool, job).await });
+        }
+        Ok(())
+    }
+}
+
+async fn run_job_timer(pool: Pool<DuckDBConnectionManager>, job: Job) {
+    let job = Arc::new(job);
+    loop {
+        let now = Utc::now();
+        let Some(next) = job.schedule.next_after(now) else {
+            println!("Job {} has no further runs scheduled", job.name);
+            return;
+        };
+        let wait = (next - now).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let started_at = SystemTime::now();
+        if job.running.swap(true, Ordering::SeqCst) {
+            eprintln!("Job {} is still running; skipping this run", job.name);
+            record_run(&pool, &job.name, started_at, 0, "skipped", None).await;
+            continue;
+        }
+
+        // Run in the background so a slow job doesn't delay its own timer;
+        // the running flag stops the next tick from overlapping it.
+        let pool_clone = pool.clone();
+        let job_clone = job.clone();
+        tokio::spawn(async move {
+            let timer = Instant::now();
+            let run = job_clone.run.clone();
+            let result = task::spawn_blocking(move || run()).await;
+            let duration_ms = timer.elapsed().as_millis() as i64;
+            job_clone.running.store(false, Ordering::SeqCst);
+
+            let (outcome, error) = match result {
+                Ok(Ok(())) => ("success", None),
+                Ok(Err(e)) => {
+                    eprintln!("Job {} failed: {:?}", job_clone.name, e);
+                    ("failure", Some(format!("{:#}", e)))
+                }
+                Err(e) => {
+                    eprintln!("Job {} panicked: {:?}", job_clone.name, e);
+                    ("failure", Some(e.to_string()))
+                }
+            };
+            record_run(&pool_clone, &job_clone.name, started_at, duration_ms, outcome, error)
+                .await;
+        });
+    }
+}
+
+fn create_job_runs_table_if_not_exists(pool: &Pool<DuckDBConnectionManager>) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS hydrocube_job_runs (
+            job VARCHAR NOT NULL,
+            started_at TIMESTAMP NOT NULL,
+            duration_ms BIGINT NOT NULL,
+            outcome VARCHAR NOT NULL,
+            error VARCHAR
+        );",
+        [],
+    )?;
+    Ok(())
+}
+
+async fn record_run(
+    pool: &Pool<DuckDBConnectionManager>,
+    job: &str,
+    started_at: SystemTime,
+    duration_ms: i64,
+    outcome: &'static str,
+    error: Option<String>,
+) {
+    let pool = pool.clone();
+    let job = job.to_string();
+    let started_ms = started_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+
+    let result = task::spawn_blocking(move || -> Result<()> {
+        let conn = pool.get()?;
+        conn.execute(
+            "INSERT INTO hydrocube_job_runs (job, started_at, duration_ms, outcome, error)
+             VALUES (?, epoch_ms(?), ?, ?, ?)",
+            params![job, started_ms, duration_ms, outcome, error],
+        )?;
+        Ok(())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("Error recording job run: {:?}", e),
+        Err(e) => eprintln!("Job run recording task failed: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use crate::db::db_pool::tests::memory_pool;
+    use super::*;
+
+    fn job(run: JobFn) -> Job {
+        Job {
+            name: "test_job".to_string(),
+            schedule: Schedule::Every(Duration::from_millis(50)),
+            run,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// `(outcome, error)` of every recorded run, oldest first.
+    fn runs(pool: &Pool<DuckDBConnectionManager>) -> Vec<(String, Option<String>)> {
+        let conn = pool.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT outcome, error FROM hydrocube_job_runs WHERE job = 'test_job' ORDER BY started_at")
+            .unwrap();
+        let runs = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        runs.collect::<Result<_, _>>().unwrap()
+    }
+
+    /// Polls the recorded runs until `done` holds, for up to five seconds.
+    async fn wait_for_runs(
+        pool: &Pool<DuckDBConnectionManager>,
+        done: impl Fn(&[(String, Option<String>)]) -> bool,
+    ) -> Vec<(String, Option<String>)> {
+        for _ in 0..100 {
+            let runs = runs(pool);
+            if done(&runs) {
+                return runs;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Runs never matched: {:?}", runs(pool));
+    }
+
+    #[tokio::test]
+    async fn skips_runs_that_would_overlap_a_slow_one() {
+        let pool = memory_pool(2);
+        create_job_runs_table_if_not_exists(&pool).unwrap();
+        let started = Arc::new(AtomicUsize::new(0));
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let started_clone = started.clone();
+        let timer = tokio::spawn(run_job_timer(
+            pool.clone(),
+            job(Arc::new(move || {
+                started_clone.fetch_add(1, Ordering::SeqCst);
+                let _ = released.lock().unwrap().recv_timeout(Duration::from_secs(5));
+                Ok(())
+            })),
+        ));
+
+        // While the first run is held, every tick is skipped.
+        let runs = wait_for_runs(&pool, |runs| runs.len() >= 2).await;
+        assert!(runs.iter().all(|(outcome, _)| outcome == "skipped"), "{:?}", runs);
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+
+        release.send(()).unwrap();
+        wait_for_runs(&pool, |runs| runs.iter().any(|(outcome, _)| outcome == "success")).await;
+        timer.abort();
+    }
+
+    #[tokio::test]
+    async fn records_failures_with_their_error() {
+        let pool = memory_pool(2);
+        create_job_runs_table_if_not_exists(&pool).unwrap();
+        let timer = tokio::spawn(run_job_timer(
+            pool.clone(),
+            job(Arc::new(|| Err(anyhow::anyhow!("disk full")))),
+        ));
+
+        let runs = wait_for_runs(&pool, |runs| !runs.is_empty()).await;
+        timer.abort();
+        assert_eq!(runs[0], ("failure".to_string(), Some("disk full".to_string())));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod web_server;
 pub mod web_handlers;
-pub mod web_embed;
\ No newline at end of file
+pub mod web_embed;
+pub mod web_socket;
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,297 @@
+use anyhow::{bail, Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::config::config::HttpsConfig;
+
+/// How long the files must be quiet before a reload, since a rotation
+/// usually writes the certificate and key separately.
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+
+/// The certificate and key from `security.https`, reloaded when either file
+/// changes or the process receives SIGHUP.
+///
+/// Shared by every listener that speaks TLS, so they all present the same
+/// certificate. A reload only affects new handshakes; open connections carry
+/// on with the certificate they started with. If the new files can't be
+/// loaded, e.g. halfway through a rotation, the current certificate stays.
+#[derive(Debug)]
+pub struct Certificates {
+    https: HttpsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    /// Loads the certificate and key and starts watching them for changes.
+    pub fn watch(https: &HttpsConfig) -> Result<Arc<Self>> {
+        let certificates = Arc::new(Certificates {
+            https: https.clone(),
+            current: RwLock::new(Arc::new(load_certified_key(https)?)),
+        });
+        let watched = certificates.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watched.reload_on_change().await {
+                eprintln!("TLS certificate watcher error: {:?}", e);
+            }
+        });
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup())?;
+            let signalled = certificates.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    println!("Received SIGHUP, reloading TLS certificate");
+                    signalled.reload();
+                }
+            });
+        }
+        Ok(certificates)
+    }
+
+    /// A rustls server config that presents the current certificate.
+    pub fn server_config(self: &Arc<Self>) -> rustls::ServerConfig {
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    /// Swaps in the certificate on disk if it differs from the current one.
+    fn reload(&self) {
+        let key = match load_certified_key(&self.https) {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("Keeping the current TLS certificate: {:?}", e);
+                return;
+            }
+        };
+        let mut current = self.current.write().unwrap();
+        if current.cert != key.cert {
+            *current = Arc::new(key);
+            println!("Reloaded TLS certificate from {}", self.https.cert_path);
+        }
+    }
+
+    /// Reloads after the files' directories see changes.
+    ///
+    /// Directories rather than the files are watched, so certificates
+    /// replaced by a rename or a symlink swap (as Kubernetes secrets are)
+    /// are noticed too.
+    async fn reload_on_change(&self) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel::<()>(1);
+        let mut watcher = RecommendedWatcher::new(
+            move |res: Result<notify::Event, notify::Error>| {
+                if res.is_ok() {
+                    // A reload is already due if the channel is full.
+                    let _ = tx.try_send(());
+                }
+            },
+            notify::Config::default(),
+        )?;
+        let mut directories = vec![parent_directory(&self.https.cert_path)];
+        let key_directory = parent_directory(&self.https.key_path);
+        if !directories.contains(&key_directory) {
+            directories.push(key_directory);
+        }
+        for directory in &directories {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Cannot watch {}", directory.display()))?;
+        }
+
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = time::timeout(RELOAD_DELAY, rx.recv()).await {}
+            self.reload();
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reads the certificate chain and private key from `security.https`,
+/// checking that they belong together.
+///
+/// The key may be PKCS#8 (`BEGIN PRIVATE KEY`), PKCS#1 (`BEGIN RSA PRIVATE
+/// KEY`) or SEC1 (`BEGIN EC PRIVATE KEY`).
+fn load_certified_key(https: &HttpsConfig) -> Result<CertifiedKey> {
+    let mut certs_file = BufReader::new(
+        File::open(&https.cert_path)
+            .with_context(|| format!("Cannot open cert file {}", &https.cert_path))?
+    );
+    let mut key_file = BufReader::new(
+        File::open(&https.key_path)
+            .with_context(|| format!("Cannot open key file {}", &https.key_path))?
+    );
+    let tls_certs = rustls_pemfile::certs(&mut certs_file)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Cannot read cert file {}", &https.cert_path))?;
+    if tls_certs.is_empty() {
+        bail!("No certificates found in {}", &https.cert_path);
+    }
+    let tls_key = rustls_pemfile::private_key(&mut key_file)
+        .with_context(|| format!("Cannot read key file {}", &https.key_path))?
+        .with_context(|| {
+            format!(
+                "No private key found in {}; expected a PKCS#8, PKCS#1 (RSA) or SEC1 (EC) PEM key",
+                &https.key_path
+            )
+        })?;
+    CertifiedKey::from_der(tls_certs, tls_key, &ring::default_provider()).with_context(|| {
+        format!(
+            "Cannot use the key in {} with the certificate in {}",
+            &https.key_path, &https.cert_path
+        )
+    })
+}
+
+fn parent_directory(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use rcgen::{CertificateParams, KeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_RSA_SHA256};
+    use tempfile::TempDir;
+    use super::*;
+
+    /// A self-signed certificate for `localhost` and its key pair.
+    fn self_signed(algorithm: &'static SignatureAlgorithm) -> (String, KeyPair) {
+        let key = KeyPair::generate_for(algorithm).unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        (cert.pem(), key)
+    }
+
+    /// The private key inside a PKCS#8 document: PKCS#1 for RSA, SEC1 for EC.
+    fn unwrap_pkcs8(der: &[u8]) -> Vec<u8> {
+        // One DER element: its tag, contents and whatever follows it.
+        fn element(der: &[u8]) -> (u8, &[u8], &[u8]) {
+            let (tag, first) = (der[0], der[1] as usize);
+            let (length, header) = if first < 0x80 {
+                (first, 2)
+            } else {
+                let octets = first & 0x7f;
+                let length = der[2..2 + octets].iter().fold(0, |n, b| n << 8 | *b as usize);
+                (length, 2 + octets)
+            };
+            (tag, &der[header..header + length], &der[header + length..])
+        }
+        let (_, info, _) = element(der);
+        let (_, _, rest) = element(info); // version
+        let (_, _, rest) = element(rest); // algorithm
+        let (tag, key, _) = element(rest);
+        assert_eq!(tag, 0x04, "expected the key's OCTET STRING");
+        key.to_vec()
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let base64 = STANDARD.encode(der);
+        let lines: Vec<&str> = base64
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        format!("-----BEGIN {0}-----\n{1}\n-----END {0}-----\n", label, lines.join("\n"))
+    }
+
+    struct Files {
+        _dir: TempDir,
+        https: HttpsConfig,
+    }
+
+    impl Files {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let https = HttpsConfig {
+                enabled: true,
+                cert_path: dir.path().join("cert.pem").to_str().unwrap().to_string(),
+                key_path: dir.path().join("key.pem").to_str().unwrap().to_string(),
+            };
+            Files { _dir: dir, https }
+        }
+
+        fn write(&self, cert: &str, key: &str) {
+            std::fs::write(&self.https.cert_path, cert).unwrap();
+            std::fs::write(&self.https.key_path, key).unwrap();
+        }
+    }
+
+    #[test]
+    fn loads_pkcs8_pkcs1_and_sec1_keys() {
+        let files = Files::new();
+        let (rsa_cert, rsa_key) = self_signed(&PKCS_RSA_SHA256);
+        let (ec_cert, ec_key) = self_signed(&PKCS_ECDSA_P256_SHA256);
+        for (cert, key) in [
+            (&rsa_cert, rsa_key.serialize_pem()),
+            (&rsa_cert, pem("RSA PRIVATE KEY", &unwrap_pkcs8(&rsa_key.serialize_der()))),
+            (&ec_cert, ec_key.serialize_pem()),
+            (&ec_cert, pem("EC PRIVATE KEY", &unwrap_pkcs8(&ec_key.serialize_der()))),
+        ] {
+            files.write(cert, &key);
+            let label = key.lines().next().unwrap();
+            let loaded = load_certified_key(&files.https).unwrap_or_else(|e| panic!("{}: {:?}", label, e));
+            assert_eq!(loaded.cert.len(), 1);
+        }
+    }
+
+    #[test]
+    fn rejects_a_key_that_does_not_match_the_certificate() {
+        let files = Files::new();
+        let (cert, _) = self_signed(&PKCS_ECDSA_P256_SHA256);
+        let other = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        files.write(&cert, &other.serialize_pem());
+        let error = load_certified_key(&files.https).unwrap_err();
+        assert!(error.to_string().starts_with("Cannot use the key in"), "{:?}", error);
+
+        files.write(&cert, "not a key");
+        let error = load_certified_key(&files.https).unwrap_err();
+        assert!(error.to_string().starts_with("No private key found"), "{:?}", error);
+        files.write("", &other.serialize_pem());
+        let error = load_certified_key(&files.https).unwrap_err();
+        assert!(error.to_string().starts_with("No certificates found"), "{:?}", error);
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_current_certificate() {
+        let files = Files::new();
+        let (cert, key) = self_signed(&PKCS_ECDSA_P256_SHA256);
+        files.write(&cert, &key.serialize_pem());
+        let certificates = Certificates {
+            https: files.https.clone(),
+            current: RwLock::new(Arc::new(load_certified_key(&files.https).unwrap())),
+        };
+        let current = || certificates.current.read().unwrap().cert.clone();
+        let original = current();
+
+        // Halfway through a rotation: the new certificate with the old key.
+        let (new_cert, new_key) = self_signed(&PKCS_ECDSA_P256_SHA256);
+        std::fs::write(&files.https.cert_path, &new_cert).unwrap();
+        certificates.reload();
+        assert_eq!(current(), original);
+
+        std::fs::write(&files.https.key_path, new_key.serialize_pem()).unwrap();
+        certificates.reload();
+        assert_ne!(current(), original);
+    }
+}
@@ -1,96 +1,225 @@
 use std::borrow::Cow;
-use std::ops::Deref;
 use crate::config::config::AppConfig;
+use actix_web::http::header;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use arrow::ipc::writer::StreamWriter;
+use duckdb::types::Value;
 use r2d2::Pool;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use crate::auth::access::AccessControl;
+use crate::auth::identity::Identity;
+use crate::db::arrow_streaming::stream_ipc;
+use crate::db::catalog::table_column_types;
+use crate::db::db_pool::{DuckDBConnectionManager, PoolMetrics};
+use crate::query::cube::CubeRequest;
+use crate::query::executor::run_read_only_query;
+use crate::query::result_format::ResultFormat;
+use crate::query::slice::SliceParams;
 use crate::server::web_embed::Frontend;
 
+/// Streams a dataset's table as an Arrow IPC stream.
+///
+/// `columns`, `filter`, `order_by`, `limit` and `offset` query parameters
+/// narrow the result; see [`SliceParams`]. The dataset's policy, if any,
+/// applies first.
 pub async fn api_get_arrow(
     path: web::Path<String>,
-    data: web::Data<Pool<crate::db::db_pool::DuckDBConnectionManager>>,
+    slice: web::Query<SliceParams>,
+    identity: Option<Identity>,
+    access: web::Data<AccessControl>,
+    config: web::Data<AppConfig>,
+    data: web::Data<Pool<DuckDBConnectionManager>>,
 ) -> impl Responder {
-    let table_name = path.into_inner();
-
-    // Get a connection from the pool.
-    let conn = match data.get() {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error getting connection from pool: {:?}", e);
-            return HttpResponse::InternalServerError().body("Error getting connection");
-        }
-    };
-
-    // Prepare and execute the SELECT query.
-    let query = format!("SELECT * FROM \"{}\"", table_name);
-    let mut stmt = match conn.prepare(&query) {
-        Ok(stmt) => stmt,
-        Err(e) => {
-            eprintln!("Error preparing query for {}: {:?}", table_name, e);
-            return HttpResponse::InternalServerError().body("Error preparing query");
-        }
+    let name = path.into_inner();
+    let permissions = access.permissions(identity.as_ref());
+    if !access.can_read(&permissions, &name) {
+        return forbidden(&name);
+    }
+    let Some(table_name) = config.table_for(&name) else {
+        return HttpResponse::NotFound().body(format!("Unknown dataset {}", name));
     };
-
-    // Get result as a single Arrow batch.
-    let arrow_batch = match stmt.query_arrow([]) {
-        Ok(batch) => batch,
-        Err(e) => {
-            eprintln!("Error executing query_arrow for {}: {:?}", table_name, e);
-            return HttpResponse::InternalServerError().body("Error executing query_arrow");
-        }
+    let columns = match load_columns(&data, table_name).await {
+        Ok(columns) => columns,
+        Err(response) => return response,
     };
 
-    let schema = arrow_batch.get_schema();
-
-    // Collect the Arrow batch into a Vec of RecordBatch.
-    let record_batch = arrow_batch.collect::<Vec<_>>().to_vec();
+    match slice.to_sql(&permissions.source(table_name), &columns) {
+        Ok((query, params)) => arrow_response(&data, table_name, query, params).await,
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
 
-    // Serialize the RecordBatch to an Arrow IPC stream.
-    let mut buffer = Vec::new();
+/// Runs a pivot/cube query over a dataset or aggregate and streams the
+/// result as Arrow; see [`CubeRequest`] for the request body.
+pub async fn api_post_cube(
+    path: web::Path<String>,
+    body: web::Json<CubeRequest>,
+    identity: Option<Identity>,
+    access: web::Data<AccessControl>,
+    config: web::Data<AppConfig>,
+    data: web::Data<Pool<DuckDBConnectionManager>>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let permissions = access.permissions(identity.as_ref());
+    if !access.can_read(&permissions, &name) {
+        return forbidden(&name);
+    }
+    let Some(table_name) = config.table_for(&name) else {
+        return HttpResponse::NotFound().body(format!("Unknown dataset {}", name));
+    };
+    let columns = match load_column_types(&data, table_name).await {
+        Ok(columns) => columns,
+        Err(response) => return response,
+    };
 
-    // If there are no record batches (e.g., empty table), handle that case appropriately.
-    if record_batch.is_empty() {
-        return HttpResponse::Ok()
-            .content_type("application/vnd.apache.arrow.stream")
-            .body(buffer);
+    match body.to_sql(&permissions.source(table_name), &columns) {
+        Ok((query, params)) => arrow_response(&data, table_name, query, params).await,
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
     }
+}
 
-    let mut stream_writer = match StreamWriter::try_new(&mut buffer, schema.deref()) {
-        Ok(writer) => writer,
-        Err(e) => {
-            eprintln!("Error creating StreamWriter for {}: {:?}", table_name, e);
-            return HttpResponse::InternalServerError().body("Error creating Arrow stream");
-        }
-    };
+/// A table's columns, or the error response to send if it can't be read or
+/// doesn't exist.
+async fn load_columns(
+    data: &web::Data<Pool<DuckDBConnectionManager>>,
+    table_name: &str,
+) -> Result<Vec<String>, HttpResponse> {
+    let columns = load_column_types(data, table_name).await?;
+    Ok(columns.into_iter().map(|(name, _)| name).collect())
+}
 
-    for batch in record_batch {
-        if let Err(e) = stream_writer.write(&batch) {
-            eprintln!("Error writing batch for {}: {:?}", table_name, e);
-            return HttpResponse::InternalServerError().body("Error writing Arrow batch");
+/// A table's columns with their types, or the error response to send if it
+/// can't be read or doesn't exist.
+async fn load_column_types(
+    data: &web::Data<Pool<DuckDBConnectionManager>>,
+    table_name: &str,
+) -> Result<Vec<(String, String)>, HttpResponse> {
+    let pool = data.get_ref().clone();
+    let lookup_table = table_name.to_string();
+    match web::block(move || table_column_types(&*pool.get()?, &lookup_table)).await {
+        Ok(Ok(columns)) if columns.is_empty() => {
+            Err(HttpResponse::NotFound().body(format!("Unknown dataset {}", table_name)))
+        }
+        Ok(Ok(columns)) => Ok(columns),
+        Ok(Err(e)) => {
+            eprintln!("Error reading columns of {}: {:?}", table_name, e);
+            Err(HttpResponse::InternalServerError().body("Error reading table schema"))
+        }
+        Err(e) => {
+            eprintln!("Schema task for {} failed: {:?}", table_name, e);
+            Err(HttpResponse::InternalServerError().body("Error reading table schema"))
         }
     }
+}
 
-    if let Err(e) = stream_writer.finish() {
-        eprintln!("Error finishing Arrow stream for {}: {:?}", table_name, e);
-        return HttpResponse::InternalServerError().body("Error finishing Arrow stream");
+/// Streams a query's result as the body of an Arrow IPC response.
+async fn arrow_response(
+    data: &web::Data<Pool<DuckDBConnectionManager>>,
+    table_name: &str,
+    query: String,
+    params: Vec<Value>,
+) -> HttpResponse {
+    match stream_ipc(data.get_ref().clone(), query, params).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/vnd.apache.arrow.stream")
+            .streaming(body),
+        Err(e) => {
+            eprintln!("Error executing query for {}: {:?}", table_name, e);
+            HttpResponse::InternalServerError().body("Error executing query")
+        }
     }
-
-    let buff = buffer;
-
-    // Return the serialized Arrow IPC stream as the HTTP response body.
-    HttpResponse::Ok()
-        .content_type("application/vnd.apache.arrow.stream")
-        .body(buff)
 }
 
+fn forbidden(name: &str) -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("Access to {} is not permitted", name))
+}
 
-pub async fn api_get_datasets(config: web::Data<AppConfig>) -> impl Responder {
+/// Lists the datasets the caller may read.
+pub async fn api_get_datasets(
+    identity: Option<Identity>,
+    access: web::Data<AccessControl>,
+    config: web::Data<AppConfig>,
+) -> impl Responder {
+    let permissions = access.permissions(identity.as_ref());
     let dataset_names: Vec<String> = config.datasets.iter()
+        .filter(|d| access.can_read(&permissions, &d.name))
         .map(|d| d.name.clone())
         .collect();
     HttpResponse::Ok().json(dataset_names)
 }
 
+/// The connection pool's size and counters; see
+/// [`PoolMetricsSnapshot`](crate::db::db_pool::PoolMetricsSnapshot).
+pub async fn api_get_pool_metrics(
+    metrics: web::Data<PoolMetrics>,
+    data: web::Data<Pool<DuckDBConnectionManager>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(metrics.snapshot(&data))
+}
+
+/// Body of `POST /api/query`.
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    pub sql: String,
+    /// Values bound to the statement's `?` placeholders, in order.
+    #[serde(default)]
+    pub params: Vec<JsonValue>,
+}
+
+/// Runs a read-only SQL statement and returns the result in the format
+/// picked by the `Accept` header: Arrow IPC stream, JSON, CSV or Parquet.
+///
+/// Callers restricted by `security.roles` may only read the tables they
+/// were granted, and tables with policies are read through them; see
+/// [`AccessControl::secure_query`].
+pub async fn api_post_query(
+    req: HttpRequest,
+    body: web::Json<QueryRequest>,
+    identity: Option<Identity>,
+    access: web::Data<AccessControl>,
+    data: web::Data<Pool<DuckDBConnectionManager>>,
+) -> impl Responder {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let Some(format) = ResultFormat::from_accept(accept) else {
+        return HttpResponse::NotAcceptable().body(
+            "Supported formats: application/vnd.apache.arrow.stream, application/json, \
+             text/csv, application/vnd.apache.parquet",
+        );
+    };
+
+    let mut query = body.into_inner();
+    query.sql = match access.secure_query(&access.permissions(identity.as_ref()), &query.sql) {
+        Ok(sql) => sql,
+        Err(e) => return HttpResponse::Forbidden().body(e.to_string()),
+    };
+
+    // Checking out a connection can block until one is free, so it happens
+    // on the blocking pool along with the query.
+    let pool = data.get_ref().clone();
+    let result = web::block(move || -> Result<_, r2d2::Error> {
+        let mut conn = pool.get()?;
+        Ok(run_read_only_query(&mut conn, &query.sql, &query.params)
+            .and_then(|(schema, batches)| format.encode(&schema, &batches)))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Ok(body))) => HttpResponse::Ok().content_type(format.content_type()).body(body),
+        Ok(Ok(Err(e))) => HttpResponse::BadRequest().body(format!("Query failed: {}", e)),
+        Ok(Err(e)) => {
+            eprintln!("Error getting connection from pool: {:?}", e);
+            HttpResponse::InternalServerError().body("Error getting connection")
+        }
+        Err(e) => {
+            eprintln!("Query task failed: {:?}", e);
+            HttpResponse::InternalServerError().body("Error executing query")
+        }
+    }
+}
+
 /// Example API endpoint: returns a simple JSON response.
 pub async fn api_get_json() -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
@@ -106,7 +235,7 @@ pub async fn serve_embedded(req: HttpRequest) -> impl Responder {
 
     match Frontend::get(path) {
         Some(content) => {
-            let body: Cow<[u8]> = content.data.into();
+            let body: Cow<[u8]> = content.data;
             let mime_type = mime_guess::from_path(path).first_or_octet_stream();
             HttpResponse::Ok()
                 .content_type(mime_type.as_ref())
@@ -115,3 +244,119 @@ pub async fn serve_embedded(req: HttpRequest) -> impl Responder {
         None => HttpResponse::NotFound().body("404 Not Found"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpMessage};
+    use arrow::array::{Array, StringArray};
+    use arrow::ipc::reader::StreamReader;
+    use serde_json::Map;
+    use super::*;
+    use crate::db::db_pool::tests::memory_pool;
+
+    /// `trades`, whose policy shows each desk only its own rows.
+    fn config() -> AppConfig {
+        serde_yaml::from_str(
+            r#"
+datasets:
+  - name: trades
+    format: csv
+    policy:
+      row_filter: "desk = {{claims.desk}}"
+      masked_columns: [{column: trader, mask: redact}]
+      exempt_roles: [auditor]
+security:
+  oauth:
+    enabled: false
+    provider: test
+    client_id: id
+    client_secret: secret
+    auth_url: http://idp/auth
+    token_url: http://idp/token
+    redirect_url: http://app/callback
+  https:
+    enabled: false
+    cert_path: cert.pem
+    key_path: key.pem
+"#,
+        )
+        .unwrap()
+    }
+
+    fn identity(roles: &[&str], desk: &str) -> Identity {
+        let mut claims = Map::new();
+        claims.insert("desk".into(), desk.into());
+        Identity {
+            subject: "alice".into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            claims,
+        }
+    }
+
+    /// The `(desk, trader)` rows `GET /api/data/arrow/trades{query}` returns
+    /// to `identity`.
+    async fn fetch(identity: Option<Identity>, query: &str) -> Vec<(String, Option<String>)> {
+        let config = config();
+        let pool = memory_pool(2);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE trades (desk VARCHAR, trader VARCHAR, qty INTEGER);
+                 INSERT INTO trades VALUES ('fx', 'bob', 1), ('fx', 'carol', 2), ('rates', 'dave', 3)",
+            )
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AccessControl::new(&config).unwrap()))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(pool))
+                .wrap_fn(move |req, srv| {
+                    if let Some(identity) = identity.clone() {
+                        req.extensions_mut().insert(identity);
+                    }
+                    srv.call(req)
+                })
+                .route("/api/data/arrow/{dataset}", web::get().to(api_get_arrow)),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri(&format!("/api/data/arrow/trades{}", query));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test::read_body(response).await;
+
+        let mut rows = Vec::new();
+        for batch in StreamReader::try_new(body.as_ref(), None).unwrap() {
+            let batch = batch.unwrap();
+            let column = |name: &str| {
+                let array = batch.column_by_name(name).unwrap();
+                array.as_any().downcast_ref::<StringArray>().unwrap().clone()
+            };
+            let (desks, traders) = (column("desk"), column("trader"));
+            for i in 0..batch.num_rows() {
+                let trader = traders.is_valid(i).then(|| traders.value(i).to_string());
+                rows.push((desks.value(i).to_string(), trader));
+            }
+        }
+        rows
+    }
+
+    #[actix_web::test]
+    async fn arrow_endpoint_applies_the_dataset_policy() {
+        let masked = Some("****".to_string());
+        assert_eq!(
+            fetch(Some(identity(&[], "fx")), "?order_by=qty").await,
+            vec![("fx".to_string(), masked.clone()), ("fx".to_string(), masked.clone())]
+        );
+
+        // Filters and columns act on what the policy lets through.
+        assert_eq!(
+            fetch(Some(identity(&[], "fx")), "?columns=desk,trader&filter=qty%20%3E%3D%202").await,
+            vec![("fx".to_string(), masked)]
+        );
+        assert!(fetch(None, "").await.is_empty());
+        assert_eq!(fetch(Some(identity(&["auditor"], "fx")), "?order_by=qty").await.len(), 3);
+    }
+}
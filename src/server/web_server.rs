@@ -1,35 +1,96 @@
 use actix_files::Files;
-use actix_web::middleware::Logger;
-use actix_web::{web, App, HttpServer};
-use anyhow::{Context, Result};
-use mime_guess;
-use rustls_pemfile;
-use std::fs::File;
-use std::io::BufReader;
-
-use crate::config::config::AppConfig;
-use crate::db::db_pool::DuckDBConnectionManager;
-use crate::server::web_handlers::{api_get_arrow, api_get_datasets, api_get_json, serve_embedded};
-
-/// Sets up the Actix app with shared routes and middleware, then starts the HTTP server.
+use actix_web::http::{header, KeepAlive};
+use actix_web::middleware::{from_fn, Condition, Logger};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::access::AccessControl;
+use crate::auth::authenticate::authenticate;
+use crate::auth::jwt::JwtValidator;
+use crate::auth::oauth::{auth_callback, auth_login, auth_logout, OAuthClient};
+use crate::config::config::{AppConfig, ServerConfig};
+use crate::db::db_pool::{DuckDBConnectionManager, PoolMetrics};
+use crate::publisher::hub::PublisherHub;
+use crate::server::web_handlers::{
+    api_get_arrow, api_get_datasets, api_get_json, api_get_pool_metrics, api_post_cube,
+    api_post_query,
+    serve_embedded,
+};
+use crate::server::tls::Certificates;
+use crate::server::web_socket::ws_subscribe;
+
+/// Sets up the Actix app with shared routes and middleware, then starts the HTTP server
+/// on the address, ports and limits in the `server` section.
 /// This function conditionally serves static files from disk in debug builds and
 /// uses embedded assets in release builds.
 pub async fn run_server(
     pool: r2d2::Pool<DuckDBConnectionManager>,
     config_data: web::Data<AppConfig>,
+    hub: web::Data<PublisherHub>,
+    pool_metrics: web::Data<PoolMetrics>,
+    certificates: Option<Arc<Certificates>>,
 ) -> Result<()> {
+    let access = web::Data::new(AccessControl::new(&config_data)?);
+
+    // With OAuth or JWT enabled, requests must be authenticated; see `authenticate`.
+    let oauth = if config_data.security.oauth.enabled {
+        println!("OAuth login is enabled for provider: {}", config_data.security.oauth.provider);
+        Some(web::Data::new(
+            OAuthClient::new(&config_data.security, access.claims_used()).await?,
+        ))
+    } else {
+        None
+    };
+    let jwt = if config_data.security.jwt.enabled {
+        println!("JWT bearer tokens are accepted from issuer: {}", config_data.security.jwt.issuer);
+        let validator = Arc::new(JwtValidator::new(&config_data.security.jwt).await?);
+        tokio::spawn(validator.clone().refresh_periodically());
+        Some(web::Data::from(validator))
+    } else {
+        None
+    };
+    let auth_enabled = oauth.is_some() || jwt.is_some();
+
+    if access.is_enabled() && !auth_enabled {
+        println!("security.roles has no effect without OAuth or JWT: callers can't be identified");
+    }
+
     // Common app factory closure.
+    let max_payload = config_data.server.max_payload_bytes;
     let app_factory = {
         let pool = pool.clone();
         let config_data = config_data.clone();
         move || {
             let app = App::new()
+                .app_data(web::JsonConfig::default().limit(max_payload))
+                .app_data(web::PayloadConfig::new(max_payload))
                 .app_data(web::Data::new(pool.clone()))
                 .app_data(config_data.clone())
+                .app_data(hub.clone())
+                .app_data(pool_metrics.clone())
+                .app_data(access.clone())
+                .wrap(Condition::new(auth_enabled, from_fn(authenticate)))
                 .wrap(Logger::default())
                 .route("/api/data/json", web::get().to(api_get_json))
                 .route("/api/data/arrow/{dataset}", web::get().to(api_get_arrow))
-                .route("/api/datasets", web::get().to(api_get_datasets));
+                .route("/api/datasets", web::get().to(api_get_datasets))
+                .route("/api/query", web::post().to(api_post_query))
+                .route("/api/cube/{dataset}", web::post().to(api_post_cube))
+                .route("/api/metrics/pool", web::get().to(api_get_pool_metrics))
+                .route("/ws", web::get().to(ws_subscribe))
+                .configure(|cfg| {
+                    if let Some(jwt) = &jwt {
+                        cfg.app_data(jwt.clone());
+                    }
+                    if let Some(oauth) = &oauth {
+                        cfg.app_data(oauth.clone())
+                            .route("/auth/login", web::get().to(auth_login))
+                            .route("/auth/callback", web::get().to(auth_callback))
+                            .route("/auth/logout", web::get().to(auth_logout));
+                    }
+                });
 
             // Conditionally add the frontend routes:
             // In debug builds, serve files from disk.
@@ -38,46 +99,130 @@ pub async fn run_server(
 
             // In release builds, serve embedded assets.
             //#[cfg(not(debug_assertions))]
-            let app = app.route("/{filename:.*}", web::get().to(serve_embedded));
-
-            app
+            app.route("/{filename:.*}", web::get().to(serve_embedded))
         }
     };
 
+    let settings = &config_data.server;
+    let mut server = HttpServer::new(app_factory);
+    match settings.keep_alive_secs {
+        None => {}
+        Some(0) => server = server.keep_alive(KeepAlive::Disabled),
+        Some(secs) => server = server.keep_alive(Duration::from_secs(secs)),
+    }
+    if let Some(workers) = settings.workers {
+        server = server.workers(workers);
+    }
+
     // Set up the server: use TLS if enabled, otherwise plain HTTP.
-    let server = if config_data.security.https.enabled {
+    if let Some(certificates) = certificates {
         // ----- HTTPS Setup -----
-        let mut certs_file = BufReader::new(
-            File::open(&config_data.security.https.cert_path)
-                .with_context(|| format!("Cannot open cert file {}", &config_data.security.https.cert_path))?
-        );
-        let mut key_file = BufReader::new(
-            File::open(&config_data.security.https.key_path)
-                .with_context(|| format!("Cannot open key file {}", &config_data.security.https.key_path))?
-        );
-        let tls_certs = rustls_pemfile::certs(&mut certs_file)
-            .collect::<Result<Vec<_>, _>>()?;
-        let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_file)
-            .next().unwrap()?;
-        let tls_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(tls_certs, rustls::pki_types::PrivateKeyDer::Pkcs8(tls_key))?;
-
-        println!("Starting HTTPS server on port 8443");
-        HttpServer::new(app_factory)
-            .bind_rustls_0_23(("0.0.0.0", 8443), tls_config)?
-            .run()
+        let tls_config = certificates.server_config();
+
+        println!("Starting HTTPS server on {}:{}", settings.host, settings.https_port);
+        let server = server
+            .bind_rustls_0_23((settings.host.as_str(), settings.https_port), tls_config)?
+            .run();
+        if settings.redirect_http {
+            println!(
+                "Redirecting HTTP on {}:{} to HTTPS",
+                settings.host, settings.http_port
+            );
+            tokio::try_join!(server, redirect_server(settings)?)?;
+        } else {
+            server.await?;
+        }
     } else {
         // ----- Plain HTTP Setup -----
-        println!("Starting HTTP server on port 8080");
-        HttpServer::new(app_factory)
-            .bind(("0.0.0.0", 8080))?
-            .keep_alive(actix_web::http::KeepAlive::Os)
+        println!("Starting HTTP server on {}:{}", settings.host, settings.http_port);
+        server
+            .bind((settings.host.as_str(), settings.http_port))?
             .run()
-    };
-
-    server.await?;
+            .await?;
+    }
     Ok(())
 }
 
+/// A plain HTTP listener on `http_port` that sends every request to the
+/// same URL on the HTTPS listener.
+fn redirect_server(settings: &ServerConfig) -> Result<actix_web::dev::Server> {
+    let https_port = settings.https_port;
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(https_port))
+            .default_service(web::to(redirect_to_https))
+    })
+    .workers(1)
+    .bind((settings.host.as_str(), settings.http_port))?
+    .run();
+    Ok(server)
+}
+
+/// `308 Permanent Redirect` to HTTPS, keeping the host, path and query. 308
+/// rather than 301 so clients repeat a POST as a POST.
+async fn redirect_to_https(req: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    // Without a Host the connection info falls back to our own address,
+    // which may not be one the client can reach.
+    if !req.headers().contains_key(header::HOST) && req.uri().host().is_none() {
+        return HttpResponse::BadRequest().body("Host header required");
+    }
+    let connection_info = req.connection_info();
+    let host = connection_info.host();
+    // Drop the port, minding IPv6 literals such as `[::1]:8080`.
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let location = match **https_port {
+        443 => format!("https://{}{}", hostname, path),
+        port => format!("https://{}:{}{}", hostname, port, path),
+    };
+    HttpResponse::PermanentRedirect()
+        .append_header((header::LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    async fn redirect(request: TestRequest, https_port: u16) -> HttpResponse {
+        redirect_to_https(request.to_http_request(), web::Data::new(https_port)).await
+    }
 
+    fn location(response: &HttpResponse) -> &str {
+        response.headers().get(header::LOCATION).unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_the_same_host_on_the_https_port() {
+        for (host, expected) in [
+            ("example.com:8080", "https://example.com:8443/api/data?x=1"),
+            ("example.com", "https://example.com:8443/api/data?x=1"),
+            ("[::1]:8080", "https://[::1]:8443/api/data?x=1"),
+            ("[::1]", "https://[::1]:8443/api/data?x=1"),
+            ("10.0.0.1:80", "https://10.0.0.1:8443/api/data?x=1"),
+        ] {
+            let request = TestRequest::post().uri("/api/data?x=1").insert_header((header::HOST, host));
+            let response = redirect(request, 8443).await;
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT, "{}", host);
+            assert_eq!(location(&response), expected, "{}", host);
+        }
+    }
+
+    #[actix_web::test]
+    async fn leaves_out_the_default_https_port() {
+        let request = TestRequest::get().insert_header((header::HOST, "example.com:80"));
+        assert_eq!(location(&redirect(request, 443).await), "https://example.com/");
+    }
+
+    #[actix_web::test]
+    async fn requests_without_a_host_are_refused() {
+        let response = redirect(TestRequest::get().uri("/dashboard"), 8443).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(header::LOCATION).is_none());
+    }
+}
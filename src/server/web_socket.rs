@@ -0,0 +1,115 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::task;
+use crate::auth::access::AccessControl;
+use crate::auth::identity::Identity;
+use crate::publisher::hub::{Encoding, Outbound, Outbox, PublisherHub};
+
+/// Messages a client sends over `/ws`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum ClientMessage {
+    /// `{"action": "subscribe", "cube": "sales_agg", "format": "arrow"}`
+    Subscribe {
+        cube: String,
+        #[serde(default)]
+        format: Encoding,
+    },
+    /// `{"action": "unsubscribe", "cube": "sales_agg"}`
+    Unsubscribe { cube: String },
+}
+
+/// WebSocket endpoint for real-time updates.
+///
+/// Clients subscribe to a publisher, aggregate or dataset by name and receive
+/// a snapshot followed by deltas as the cube changes. Subscribing to a cube
+/// the caller may not read is refused with an error frame. A client that
+/// stops reading until its queue of frames fills up is disconnected.
+pub async fn ws_subscribe(
+    req: HttpRequest,
+    body: web::Payload,
+    identity: Option<Identity>,
+    access: web::Data<AccessControl>,
+    hub: web::Data<PublisherHub>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let permissions = access.permissions(identity.as_ref());
+    let hub = hub.into_inner();
+    let connection = hub.connection_id();
+    let (outbox, mut rx) = Outbox::new();
+
+    actix_web::rt::spawn(async move {
+        let mut close_reason = None;
+        loop {
+            tokio::select! {
+                message = messages.recv() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if session.pong(&bytes).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+
+                    match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Subscribe { cube, format }) => {
+                            if !access.can_read(&permissions, &cube) {
+                                outbox.send(error_frame(&format!("Access to {} is not permitted", cube)));
+                                continue;
+                            }
+                            if let Err(e) = hub.subscribe(&cube, connection, format, &permissions, outbox.clone()) {
+                                outbox.send(error_frame(&e.to_string()));
+                                continue;
+                            }
+
+                            // Send the new subscriber its snapshot straight away.
+                            let hub_clone = hub.clone();
+                            task::spawn_blocking(move || {
+                                if let Err(e) = hub_clone.publish(&cube) {
+                                    eprintln!("Error publishing snapshot of {}: {:?}", cube, e);
+                                }
+                            });
+                        }
+                        Ok(ClientMessage::Unsubscribe { cube }) => hub.unsubscribe(&cube, connection),
+                        Err(e) => {
+                            outbox.send(error_frame(&format!("Invalid message: {}", e)));
+                        }
+                    }
+                }
+                outbound = rx.recv() => {
+                    let sent = match outbound {
+                        Some(Outbound::Text(text)) => session.text(text).await,
+                        Some(Outbound::Binary(bytes)) => session.binary(bytes).await,
+                        None => break,
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+                _ = outbox.overflowed() => {
+                    eprintln!("Disconnecting WebSocket connection {}: it fell behind", connection);
+                    close_reason = Some(CloseReason {
+                        code: CloseCode::Again,
+                        description: Some("Fell behind on updates".to_string()),
+                    });
+                    break;
+                }
+            }
+        }
+
+        hub.unsubscribe_all(connection);
+        let _ = session.close(close_reason).await;
+    });
+
+    Ok(response)
+}
+
+fn error_frame(message: &str) -> Outbound {
+    Outbound::Text(json!({ "type": "error", "message": message }).to_string())
+}